@@ -0,0 +1,89 @@
+//! Saved coverage numbers to compare later runs against, so coverage can be kept from getting
+//! worse without picking fixed minimums.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CovError, Result,
+    export::{ExportData, Metric, Summary},
+    formats::relative_path,
+    thresholds::below,
+};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Baseline {
+    pub totals: Summary,
+    /// By path relative to the project root, so a baseline from CI works on any checkout
+    pub files: BTreeMap<String, Summary>,
+}
+
+impl Baseline {
+    pub fn new(data: &ExportData, root: &Path) -> Self {
+        Baseline {
+            totals: data.totals.clone(),
+            files: data
+                .files
+                .iter()
+                .map(|f| (relative_path(&f.filename, root), f.summary.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).map_err(|e| CovError::io(path, e))?;
+        serde_json::from_str(&contents).map_err(|e| CovError::Config {
+            path: path.to_path_buf(),
+            message: format!("not a saved baseline: {}", e),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("unable to serialize baseline");
+        fs::write(path, json + "\n").map_err(|e| CovError::io(path, e))
+    }
+
+    /// Every metric that dropped more than `max_drop` below this baseline in `current`, as a
+    /// message for the user. Files are only compared by line coverage, and only if they're in
+    /// both, since new and deleted files have nothing to compare.
+    pub fn regressions(&self, current: &Baseline, max_drop: f32) -> Vec<String> {
+        let mut regressions = vec![];
+        let mut check = |what: String, before: &Metric, after: &Metric| {
+            // Going from nothing to measure to something isn't a drop
+            if before.count == 0 || after.count == 0 {
+                return;
+            }
+            if after.fraction() < before.fraction() - max_drop {
+                regressions.push(format!(
+                    "{} dropped below the baseline ({})",
+                    what,
+                    below(after.fraction(), before.fraction())
+                ));
+            }
+        };
+
+        for (name, before, after) in [
+            ("Line", &self.totals.lines, &current.totals.lines),
+            ("Branch", &self.totals.branches, &current.totals.branches),
+            (
+                "Function",
+                &self.totals.functions,
+                &current.totals.functions,
+            ),
+            ("Region", &self.totals.regions, &current.totals.regions),
+        ] {
+            check(format!("{} coverage", name), before, after);
+        }
+        for (path, before) in &self.files {
+            if let Some(after) = current.files.get(path) {
+                check(
+                    format!("Line coverage of {}", path),
+                    &before.lines,
+                    &after.lines,
+                );
+            }
+        }
+        regressions
+    }
+}
@@ -0,0 +1,3 @@
+// The same CLI under the name cargo looks for, so that once installed it also runs as
+// `cargo simple-cov`. See `main` for how it tells the two apart.
+include!("../main.rs");
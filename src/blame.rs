@@ -0,0 +1,116 @@
+//! Who wrote the lines the tests never run, from `git blame`, for finding the changes that
+//! brought in untested code.
+
+use std::{cmp::Reverse, collections::BTreeMap, fmt::Write, path::Path};
+
+use clap::ValueEnum;
+
+use crate::{
+    Result,
+    export::ExportData,
+    git::{self, BlameCommit},
+    history::format_timestamp,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BlameBy {
+    Author,
+    Commit,
+}
+
+/// The uncovered lines one commit last changed
+#[derive(Debug)]
+pub struct Blamed {
+    pub commit: BlameCommit,
+    pub uncovered: u64,
+}
+
+/// Each commit that last changed an uncovered line, with how many, most first. A file git can't
+/// blame, like one that isn't committed yet, counts as uncommitted changes.
+pub fn blame(data: &ExportData, project_dir: &Path) -> Result<Vec<Blamed>> {
+    // Outside of a git checkout nothing can be blamed, which should be an error
+    git::toplevel(project_dir)?;
+
+    let mut commits: BTreeMap<String, Blamed> = BTreeMap::new();
+    for file in &data.files {
+        let uncovered: Vec<u64> = file.uncovered_lines().collect();
+        if uncovered.is_empty() {
+            continue;
+        }
+        let lines = git::blame(project_dir, Path::new(&file.filename)).unwrap_or_default();
+        for line in uncovered {
+            let commit = lines
+                .get(&line)
+                .filter(|c| c.id.bytes().any(|b| b != b'0'))
+                .cloned()
+                .unwrap_or_else(uncommitted);
+            commits
+                .entry(commit.id.clone())
+                .or_insert(Blamed {
+                    commit,
+                    uncovered: 0,
+                })
+                .uncovered += 1;
+        }
+    }
+    let mut blamed: Vec<Blamed> = commits.into_values().collect();
+    blamed.sort_by_key(|b| Reverse(b.uncovered));
+    Ok(blamed)
+}
+
+/// What `git blame` says for lines that aren't committed yet, minus the summary it makes up
+fn uncommitted() -> BlameCommit {
+    BlameCommit {
+        id: "0".repeat(40),
+        author: "Not Committed Yet".to_string(),
+        time: 0,
+        summary: String::new(),
+    }
+}
+
+/// A table of the uncovered lines by author, or by commit with the newest first
+pub fn render(blamed: &[Blamed], by: BlameBy) -> String {
+    let mut out = String::new();
+    match by {
+        BlameBy::Author => {
+            let mut authors: BTreeMap<&str, u64> = BTreeMap::new();
+            for b in blamed {
+                *authors.entry(&b.commit.author).or_default() += b.uncovered;
+            }
+            let mut authors: Vec<_> = authors.into_iter().collect();
+            authors.sort_by_key(|(_, uncovered)| Reverse(*uncovered));
+            writeln!(out, "{:>9}  Author", "Uncovered").unwrap();
+            for (author, uncovered) in authors {
+                writeln!(out, "{:>9}  {}", uncovered, author).unwrap();
+            }
+        }
+        BlameBy::Commit => {
+            let mut commits: Vec<&Blamed> = blamed.iter().collect();
+            commits.sort_by_key(|b| Reverse(b.commit.time));
+            writeln!(
+                out,
+                "{:>9}  {:<7}  {:<16}  {:<20}  Summary",
+                "Uncovered", "Commit", "Date (UTC)", "Author"
+            )
+            .unwrap();
+            for b in commits {
+                let date = if b.commit.time == 0 {
+                    "-".to_string()
+                } else {
+                    format_timestamp(b.commit.time)
+                };
+                writeln!(
+                    out,
+                    "{:>9}  {:<7}  {:<16}  {:<20}  {}",
+                    b.uncovered,
+                    &b.commit.id[..7],
+                    date,
+                    b.commit.author,
+                    b.commit.summary
+                )
+                .unwrap();
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,404 @@
+//! The rustflags cargo would build with from its config files, so the coverage flags can be
+//! added to them. Cargo only uses `build.rustflags` when no `[target]` table's rustflags apply,
+//! so flags added with `--config build.rustflags=[...]` are dropped in a project that has
+//! `[target.x86_64-unknown-linux-gnu] rustflags`. Working out the flags the way cargo does and
+//! passing them in `CARGO_ENCODED_RUSTFLAGS` keeps both.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use toml::{Table, Value};
+
+use crate::{Config, CovError, Result, tools};
+
+/// The `.cargo/config.toml` files cargo reads when run in `dir`, least important first: the one
+/// in the cargo home, then the ones from the root of the filesystem down to `dir`
+pub fn config_files(dir: &Path) -> Vec<PathBuf> {
+    let dir = std::path::absolute(dir).unwrap_or(dir.to_path_buf());
+    let mut files: Vec<PathBuf> = vec![];
+    // Canonical paths, so a symlinked dir doesn't have its file read twice
+    for file in dir
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home())
+        .filter_map(|dir| config_file(&dir))
+    {
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    files.reverse();
+    files
+}
+
+/// The config file in a `.cargo` dir. Cargo prefers the old name without an extension when
+/// both are there.
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    ["config", "config.toml"]
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+        .and_then(|path| path.canonicalize().ok())
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+        env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| Path::new(&home).join(".cargo"))
+    })
+}
+
+fn load(path: &Path) -> Result<Table> {
+    let contents = fs::read_to_string(path).map_err(|e| CovError::io(path, e))?;
+    contents
+        .parse()
+        .map_err(|e: toml::de::Error| CovError::Config {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+}
+
+/// The flags under `key`, like `rustflags` or `rustdocflags`, that cargo would build the
+/// project with from its config files and the `CARGO_BUILD_*` and `CARGO_TARGET_*` variables.
+/// With `targets`, `[target]` tables for the target being built apply too, instead of `[build]`.
+pub fn configured_flags(config: &Config, key: &str, targets: bool) -> Result<Vec<String>> {
+    let tables = config_files(&config.project_dir)
+        .iter()
+        .map(|path| load(path))
+        .collect::<Result<Vec<_>>>()?;
+    let env_flags = |var: String| env::var(var).ok().map(|flags| split(&flags));
+    let build_flags = env_flags(format!("CARGO_BUILD_{}", key.to_uppercase()));
+    if !targets {
+        return Ok(flags(&tables, key, None, build_flags));
+    }
+
+    let build_target = tables
+        .iter()
+        .rev()
+        .find_map(|t| t.get("build")?.get("target")?.as_str().map(str::to_string));
+    let triple = match config.target.clone().or(build_target) {
+        Some(target) => target,
+        None => tools::rustc_output(&config.project_dir, &["-vV"])
+            .as_deref()
+            .and_then(|info| tools::rustc_field(info, "host: "))
+            .map(str::to_string)
+            .ok_or_else(|| {
+                CovError::Toolchain("couldn't get the host from rustc -vV".to_string())
+            })?,
+    };
+    let cfgs: Vec<String> = tools::rustc_output(
+        &config.project_dir,
+        &["--print", "cfg", "--target", &triple],
+    )
+    .unwrap_or_default()
+    .lines()
+    .map(str::to_string)
+    .collect();
+    let target_flags = env_flags(format!(
+        "CARGO_TARGET_{}_{}",
+        triple.to_uppercase().replace(['-', '.'], "_"),
+        key.to_uppercase()
+    ));
+    Ok(flags(
+        &tables,
+        key,
+        Some(Target {
+            triple: &triple,
+            cfgs: &cfgs,
+            env: target_flags,
+        }),
+        build_flags,
+    ))
+}
+
+/// The target being built, as far as picking its `[target]` tables goes
+struct Target<'a> {
+    triple: &'a str,
+    /// Its `rustc --print cfg`
+    cfgs: &'a [String],
+    /// From its `CARGO_TARGET_<TRIPLE>_*` variable
+    env: Option<Vec<String>>,
+}
+
+/// What [`configured_flags`] finds in the config files, least important first
+fn flags(
+    tables: &[Table],
+    key: &str,
+    target: Option<Target>,
+    build_env: Option<Vec<String>>,
+) -> Vec<String> {
+    if let Some(target) = target {
+        // Every matching [target] table's flags are joined, and then [build] doesn't count
+        let mut flags: Vec<String> = tables
+            .iter()
+            .filter_map(|t| t.get("target")?.as_table())
+            .flat_map(|targets| {
+                targets
+                    .iter()
+                    .filter(|(name, _)| match name.strip_prefix("cfg(") {
+                        Some(expr) => expr
+                            .strip_suffix(')')
+                            .is_some_and(|expr| cfg_matches(expr, target.cfgs)),
+                        None => *name == target.triple,
+                    })
+                    .filter_map(|(_, t)| value_flags(t.get(key)?))
+            })
+            .flatten()
+            .collect();
+        flags.extend(target.env.into_iter().flatten());
+        if !flags.is_empty() {
+            return flags;
+        }
+    }
+    let mut flags: Vec<String> = tables
+        .iter()
+        .filter_map(|t| value_flags(t.get("build")?.get(key)?))
+        .flatten()
+        .collect();
+    flags.extend(build_env.into_iter().flatten());
+    flags
+}
+
+/// Flags written as an array, or as a space-separated string
+fn value_flags(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(flags) => Some(split(flags)),
+        Value::Array(flags) => Some(
+            flags
+                .iter()
+                .filter_map(|f| f.as_str().map(str::to_string))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+fn split(flags: &str) -> Vec<String> {
+    flags.split_whitespace().map(str::to_string).collect()
+}
+
+/// Whether what's inside a `cfg(...)` target name holds, given the `rustc --print cfg` lines
+/// like `unix` and `target_os="linux"`
+fn cfg_matches(expr: &str, cfgs: &[String]) -> bool {
+    let tokens = tokenize(expr);
+    let mut pos = 0;
+    predicate(&tokens, &mut pos, cfgs).is_some_and(|matches| matches && pos == tokens.len())
+}
+
+fn tokenize(expr: &str) -> Vec<String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if matches!(c, '(' | ')' | ',' | '=') {
+            tokens.push(c.to_string());
+            i += 1;
+        } else if c == '"' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|c| *c == '"')
+                .map_or(chars.len(), |p| i + 1 + p);
+            tokens.push(chars[i..(end + 1).min(chars.len())].iter().collect());
+            i = end + 1;
+        } else {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            if i == start {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        }
+    }
+    tokens
+}
+
+/// One predicate, like `unix`, `target_os = "linux"`, or `all(...)`. `None` when it doesn't
+/// parse, which cargo would complain about.
+fn predicate(tokens: &[String], pos: &mut usize, cfgs: &[String]) -> Option<bool> {
+    let name = tokens.get(*pos)?.clone();
+    *pos += 1;
+    match tokens.get(*pos).map(String::as_str) {
+        Some("(") if matches!(name.as_str(), "all" | "any" | "not") => {
+            *pos += 1;
+            let mut results = vec![];
+            while tokens.get(*pos).map(String::as_str) != Some(")") {
+                results.push(predicate(tokens, pos, cfgs)?);
+                if tokens.get(*pos).map(String::as_str) == Some(",") {
+                    *pos += 1;
+                }
+            }
+            *pos += 1;
+            match name.as_str() {
+                "all" => Some(results.iter().all(|r| *r)),
+                "any" => Some(results.iter().any(|r| *r)),
+                _ => match results[..] {
+                    [result] => Some(!result),
+                    _ => None,
+                },
+            }
+        }
+        Some("=") => {
+            let value = tokens.get(*pos + 1)?;
+            *pos += 2;
+            Some(cfgs.contains(&format!("{}={}", name, value)))
+        }
+        _ => Some(cfgs.contains(&name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> Table {
+        toml.parse().unwrap()
+    }
+
+    fn strings(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|f| f.to_string()).collect()
+    }
+
+    const LINUX: &str = "x86_64-unknown-linux-gnu";
+
+    fn linux_cfgs() -> Vec<String> {
+        strings(&[
+            "debug_assertions",
+            "panic=\"unwind\"",
+            "target_arch=\"x86_64\"",
+            "target_os=\"linux\"",
+            "unix",
+        ])
+    }
+
+    #[test]
+    fn target_flags_replace_build_flags() {
+        let tables = [table(
+            "[build]\nrustflags = [\"-C\", \"target-cpu=native\"]\n\
+             [target.x86_64-unknown-linux-gnu]\nrustflags = [\"-C\", \"debuginfo=1\"]\n",
+        )];
+        let cfgs = linux_cfgs();
+        assert_eq!(
+            flags(
+                &tables,
+                "rustflags",
+                Some(Target {
+                    triple: LINUX,
+                    cfgs: &cfgs,
+                    env: None
+                }),
+                None
+            ),
+            strings(&["-C", "debuginfo=1"])
+        );
+        // Building for some other target, only [build] applies
+        assert_eq!(
+            flags(
+                &tables,
+                "rustflags",
+                Some(Target {
+                    triple: "wasm32-unknown-unknown",
+                    cfgs: &[],
+                    env: None
+                }),
+                None
+            ),
+            strings(&["-C", "target-cpu=native"])
+        );
+    }
+
+    #[test]
+    fn matching_target_tables_are_joined() {
+        let tables = [
+            table("[target.'cfg(unix)']\nrustflags = \"-C link-arg=-fuse-ld=lld\"\n"),
+            table(
+                "[target.x86_64-unknown-linux-gnu]\nrustflags = [\"-C\", \"debuginfo=1\"]\n\
+                 [target.'cfg(windows)']\nrustflags = [\"-C\", \"target-feature=+crt-static\"]\n",
+            ),
+        ];
+        let cfgs = linux_cfgs();
+        assert_eq!(
+            flags(
+                &tables,
+                "rustflags",
+                Some(Target {
+                    triple: LINUX,
+                    cfgs: &cfgs,
+                    env: Some(strings(&["--cfg", "ci"]))
+                }),
+                None
+            ),
+            strings(&[
+                "-C",
+                "link-arg=-fuse-ld=lld",
+                "-C",
+                "debuginfo=1",
+                "--cfg",
+                "ci"
+            ])
+        );
+    }
+
+    #[test]
+    fn build_flags_from_every_file_and_the_environment() {
+        let tables = [
+            table("[build]\nrustflags = \"-C opt-level=1\"\n"),
+            table("[build]\nrustflags = [\"-C\", \"target-cpu=native\"]\n"),
+        ];
+        assert_eq!(
+            flags(&tables, "rustflags", None, Some(strings(&["-W", "unused"]))),
+            strings(&[
+                "-C",
+                "opt-level=1",
+                "-C",
+                "target-cpu=native",
+                "-W",
+                "unused"
+            ])
+        );
+    }
+
+    #[test]
+    fn cfg_expressions() {
+        let cfgs = linux_cfgs();
+        for (expr, expected) in [
+            ("unix", true),
+            ("windows", false),
+            ("target_os = \"linux\"", true),
+            ("target_os = \"macos\"", false),
+            ("all(unix, target_arch = \"x86_64\")", true),
+            ("all(unix, not(debug_assertions))", false),
+            ("any(windows, target_os = \"linux\")", true),
+            ("not(any(windows, target_os = \"macos\"))", true),
+            ("all()", true),
+            ("any()", false),
+        ] {
+            assert_eq!(cfg_matches(expr, &cfgs), expected, "cfg({})", expr);
+        }
+    }
+
+    #[test]
+    fn config_files_nearest_last() {
+        let root = env::temp_dir().join(format!("simple-cov-cargo-config-{}", std::process::id()));
+        let project = root.join("project");
+        fs::create_dir_all(project.join(".cargo")).unwrap();
+        fs::create_dir_all(root.join(".cargo")).unwrap();
+        fs::write(project.join(".cargo/config.toml"), "").unwrap();
+        fs::write(root.join(".cargo/config"), "").unwrap();
+
+        let files = config_files(&project);
+        let root_file = root.join(".cargo/config").canonicalize().unwrap();
+        let project_file = project.join(".cargo/config.toml").canonicalize().unwrap();
+        let root_pos = files.iter().position(|f| *f == root_file).unwrap();
+        assert_eq!(files.last(), Some(&project_file));
+        assert!(root_pos < files.len() - 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
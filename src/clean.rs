@@ -0,0 +1,65 @@
+//! What the `clean` command deletes: everything a run leaves behind, but not the history,
+//! baselines, or anything else meant to be kept between runs.
+
+use std::{fs, path::PathBuf};
+
+use crate::{Config, CovError, Result, canonicalize, default_html_dir, profdata};
+
+/// Where versions before `output_dir` kept the profiles, in the project dir
+const OLD_PROFDATA_DIR: &str = ".profdata";
+
+/// The files and directories from earlier runs that exist, with nothing listed that's inside
+/// something else on the list. Nothing containing the project dir is ever listed, in case the
+/// report was configured to be written somewhere like `.`.
+pub fn artifacts(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut candidates = vec![
+        config.output_dir.clone(),
+        config.project_dir.join(OLD_PROFDATA_DIR),
+    ];
+    candidates.extend(config.target_dir.clone());
+    candidates.extend(
+        [&config.html, &config.lcov, &config.badge]
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    candidates.push(default_html_dir(config));
+
+    let root = crate::project_root(config)?;
+    let mut paths = vec![];
+    for candidate in candidates {
+        if let Ok(path) = canonicalize(&candidate)
+            && !root.starts_with(&path)
+        {
+            paths.push(path);
+        }
+    }
+    // Profiles from tests that set their own LLVM_PROFILE_FILE, like the ones a run merges
+    for dir in &config.profraw_dirs {
+        if dir.is_dir() {
+            profdata::find_profraw(dir, &mut paths)?;
+        }
+    }
+
+    // Sorting by component puts everything inside a directory right after it
+    paths.sort();
+    let mut artifacts: Vec<PathBuf> = vec![];
+    for path in paths {
+        if !artifacts.last().is_some_and(|last| path.starts_with(last)) {
+            artifacts.push(path);
+        }
+    }
+    Ok(artifacts)
+}
+
+pub fn remove(artifacts: &[PathBuf]) -> Result<()> {
+    for path in artifacts {
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        removed.map_err(|e| CovError::io(path, e))?;
+    }
+    Ok(())
+}
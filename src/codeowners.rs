@@ -0,0 +1,148 @@
+//! Coverage by owner, from the `CODEOWNERS` file GitHub and GitLab use to assign reviewers, for
+//! teams that are each responsible for their own part of a repository.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::{
+    Config, CovError, Result,
+    export::{ExportData, Metric},
+    formats::relative_path,
+    git,
+};
+
+/// Where `CODEOWNERS` can be, relative to the root of the repository, in the order GitHub looks
+const LOCATIONS: [&str; 3] = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+/// What `CODEOWNERS` paths are relative to: the root of the git checkout, or the project dir
+/// outside of one
+pub fn repository_root(config: &Config) -> Result<PathBuf> {
+    git::toplevel(&config.project_dir).or_else(|_| crate::project_root(config))
+}
+
+#[derive(Debug)]
+pub struct CodeOwners {
+    /// Where it was read from
+    pub path: PathBuf,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    matches: GlobSet,
+    owners: Vec<String>,
+}
+
+impl CodeOwners {
+    /// The `CODEOWNERS` of the repository at `root`, if it has one
+    pub fn find(root: &Path) -> Result<Option<CodeOwners>> {
+        let Some(path) = LOCATIONS.iter().map(|l| root.join(l)).find(|p| p.is_file()) else {
+            return Ok(None);
+        };
+        let contents = fs::read_to_string(&path).map_err(|e| CovError::io(&path, e))?;
+        CodeOwners::parse(&contents, path).map(Some)
+    }
+
+    fn parse(contents: &str, path: PathBuf) -> Result<CodeOwners> {
+        let mut rules = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            // GitLab's [Section] headers and ^[Optional] ones don't change who owns what
+            if line.is_empty() || line.starts_with(['#', '[', '^']) {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let Some(pattern) = words.next() else {
+                continue;
+            };
+            let owners = words
+                .take_while(|w| !w.starts_with('#'))
+                .map(str::to_string)
+                .collect();
+            let matches = globs(pattern).map_err(|message| CovError::Config {
+                path: path.clone(),
+                message: format!("invalid pattern {}: {}", pattern, message),
+            })?;
+            rules.push(Rule { matches, owners });
+        }
+        Ok(CodeOwners { path, rules })
+    }
+
+    /// Who owns `path`, relative to the root of the repository with `/` separators. The last
+    /// pattern that matches wins, and a pattern without owners means nobody does.
+    pub fn owners(&self, path: &str) -> &[String] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|r| r.matches.is_match(path))
+            .map_or(&[], |r| &r.owners)
+    }
+}
+
+/// A `CODEOWNERS` pattern, which works like a `.gitignore` one: it's anchored to the root if
+/// there's a `/` anywhere but the end, and otherwise matches at any depth. One that matches a
+/// directory matches everything in it, except that `docs/*` is only what's directly in docs.
+fn globs(pattern: &str) -> std::result::Result<GlobSet, String> {
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(dir) => (dir, true),
+        None => (pattern, false),
+    };
+    let pattern = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{}", pattern),
+    };
+    let contents = (!pattern.ends_with("/*")).then(|| format!("{}/**", pattern));
+    let mut set = GlobSetBuilder::new();
+    for glob in [contents.as_deref(), (!dir_only).then_some(pattern.as_str())]
+        .into_iter()
+        .flatten()
+    {
+        let glob = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| e.kind().to_string())?;
+        set.add(glob);
+    }
+    set.build().map_err(|e| e.to_string())
+}
+
+/// The line and branch coverage of the files one owner owns
+#[derive(Debug, Default)]
+pub struct OwnerCoverage {
+    pub files: usize,
+    pub lines: Metric,
+    pub branches: Metric,
+}
+
+/// Totals up each owner's files, with paths relative to `root`, the repository's. A file with
+/// several owners counts for each of them, and files nobody owns are under `None`, which sorts
+/// first.
+pub fn by_owner(
+    data: &ExportData,
+    owners: &CodeOwners,
+    root: &Path,
+) -> BTreeMap<Option<String>, OwnerCoverage> {
+    let mut coverage: BTreeMap<Option<String>, OwnerCoverage> = BTreeMap::new();
+    for file in &data.files {
+        let path = relative_path(&file.filename, root);
+        let file_owners = owners.owners(&path);
+        let keys = if file_owners.is_empty() {
+            vec![None]
+        } else {
+            file_owners.iter().cloned().map(Some).collect()
+        };
+        for key in keys {
+            let owner = coverage.entry(key).or_default();
+            owner.files += 1;
+            owner.lines.add(&file.summary.lines);
+            owner.branches.add(&file.summary.branches);
+        }
+    }
+    coverage
+}
@@ -0,0 +1,1123 @@
+//! Resolves the settings for a run. Each setting is taken from the first of these that sets it:
+//!
+//! 1. the command line, or its `SIMPLE_COV_*` environment variables
+//! 2. the config file, `.simple-cov.toml` in the project dir unless `--config` points elsewhere
+//! 3. `[package.metadata.simple-cov]` in the project's Cargo.toml
+//! 4. `[workspace.metadata.simple-cov]` in the workspace's Cargo.toml
+//! 5. the built-in default
+//!
+//! Other workspace members can set `min-line-coverage` and `min-branch-coverage` in their own
+//! `[package.metadata.simple-cov]`, which become package thresholds for that member.
+//!
+//! List settings like `ignore` and `features` are the exception: entries from the config file and
+//! the command line are combined. Package thresholds are merged by package name, with the command
+//! line winning for a package set in both, and path thresholds by pattern.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::{CovError, Result, tools, workspace};
+
+pub const CONFIG_FILE: &str = ".simple-cov.toml";
+
+/// Key under `[package.metadata]` and `[workspace.metadata]`
+const METADATA_KEY: &str = "simple-cov";
+
+/// Under the workspace's target dir. Building with instrument-coverage invalidates everything
+/// built without it, so sharing a target dir would mean a full rebuild every time you switched
+/// between a coverage run and a normal `cargo test`.
+const COVERAGE_TARGET_DIR: &str = "coverage";
+
+const DEFAULT_MIN_LINE_COVERAGE: f32 = 1.0;
+const DEFAULT_MIN_BRANCH_COVERAGE: f32 = 1.0;
+/// Under the workspace's target dir, which is already kept out of git
+const DEFAULT_OUTPUT_DIR: &str = "simple-cov";
+/// Relative to the output dir, in the profiles dir so each run starts without them. `%p`
+/// (process id) and `%m` (binary signature) keep parallel test binaries and forked processes
+/// from overwriting each other's profiles.
+const DEFAULT_PROFILE_FILE: &str = "profiles/raw/%p-%m.profraw";
+/// Relative to the output dir, for an empty `lcov`
+pub const DEFAULT_LCOV_FILE: &str = "lcov.info";
+/// Relative to the output dir, for an empty `html`
+pub const DEFAULT_HTML_DIR: &str = "html";
+/// Relative to the output dir, written along with the `json` format's output
+pub const JSON_FILE: &str = "coverage.json";
+/// Relative to the project dir
+pub const DEFAULT_HISTORY_FILE: &str = ".simple-cov-history.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+    Cobertura,
+    Sonarqube,
+}
+
+impl Format {
+    /// Whether rendering this format needs per-line data rather than just the summaries
+    pub fn needs_lines(&self) -> bool {
+        matches!(self, Format::Cobertura | Format::Sonarqube)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Annotate {
+    Github,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Upload {
+    Codecov,
+    Coveralls,
+}
+
+/// How llvm-cov exports the coverage of several test binaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportStrategy {
+    /// One llvm-cov run with every binary
+    Single,
+    /// One llvm-cov run per binary, in parallel, with the results combined afterwards
+    Parallel,
+}
+
+/// What runs the instrumented tests once they're built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Runner {
+    /// Each test binary, the way `cargo test` runs them
+    Cargo,
+    /// `cargo nextest run`, which runs every test in its own process
+    Nextest,
+}
+
+/// Where the sources were for the coverage, and where they are for the report, written as
+/// `FROM,TO` like llvm-cov's `-path-equivalence`. For coverage collected in a container.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PathEquivalence {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl PathEquivalence {
+    /// `path` with `from` swapped for `to`, if it's under `from`
+    pub fn remap(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.from)
+            .ok()
+            .map(|rest| self.to.join(rest))
+    }
+}
+
+impl FromStr for PathEquivalence {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(',') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(PathEquivalence {
+                from: from.into(),
+                to: to.into(),
+            }),
+            _ => Err(format!("expected FROM,TO, not {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for PathEquivalence {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The first of `equivalences` that applies to `path`, applied
+pub fn remap_path(equivalences: &[PathEquivalence], path: &Path) -> Option<PathBuf> {
+    equivalences.iter().find_map(|e| e.remap(path))
+}
+
+/// A coverage minimum as the fraction it's checked against. It can be given as a fraction from 0
+/// to 1 or a percentage from 0 to 100: anything over 1 is a percentage, and with `percent`,
+/// everything is, so that 1 means 1% rather than 100%.
+pub fn threshold(name: &str, value: f32, percent: bool) -> Result<f32> {
+    let invalid = |reason| {
+        Err(CovError::Threshold {
+            name: name.to_string(),
+            value,
+            reason,
+        })
+    };
+    if value.is_nan() {
+        invalid("it isn't a number")
+    } else if value < 0.0 {
+        invalid("coverage can't be negative")
+    } else if value > 100.0 {
+        invalid("expected a fraction from 0 to 1, or a percentage from 0 to 100")
+    } else if percent || value > 1.0 {
+        Ok(value / 100.0)
+    } else {
+        Ok(value)
+    }
+}
+
+/// An allowance in percentage points, like `tolerance`, as the fraction it's added to coverage
+/// as. Unlike [`threshold`], it's always in points, so 1 means one point whatever `percent` says.
+fn points(name: &str, value: f32) -> Result<f32> {
+    if (0.0..=100.0).contains(&value) {
+        Ok(value / 100.0)
+    } else {
+        Err(CovError::Threshold {
+            name: name.to_string(),
+            value,
+            reason: "expected percentage points from 0 to 100",
+        })
+    }
+}
+
+/// Minimums for one workspace member
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageMinimums {
+    pub line: f32,
+    pub branch: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackageThreshold {
+    pub package: String,
+    pub min_line_coverage: f32,
+    pub min_branch_coverage: Option<f32>,
+}
+
+/// Minimums for a group of files, like the ones matching a pattern or the ones a team owns:
+/// just the line coverage, or `{ line, branch }`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum GroupMinimums {
+    Line(f32),
+    Both(PackageMinimums),
+}
+
+impl GroupMinimums {
+    fn line_and_branch(self) -> (f32, Option<f32>) {
+        match self {
+            GroupMinimums::Line(line) => (line, None),
+            GroupMinimums::Both(mins) => (mins.line, mins.branch),
+        }
+    }
+}
+
+/// Minimums for the files an owner in `CODEOWNERS` owns
+#[derive(Debug, Clone)]
+pub struct OwnerThreshold {
+    pub owner: String,
+    pub min_line_coverage: f32,
+    pub min_branch_coverage: Option<f32>,
+}
+
+/// Minimums for the files whose paths, relative to the project dir, match `glob`. A file only
+/// counts towards the most specific pattern it matches, the longest one.
+#[derive(Debug, Clone)]
+pub struct PathThreshold {
+    pub pattern: String,
+    pub glob: GlobMatcher,
+    pub min_line_coverage: f32,
+    pub min_branch_coverage: Option<f32>,
+}
+
+/// Paths to the external tools, for setups where they aren't on the PATH under their usual
+/// names
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ToolSettings {
+    pub cargo: Option<String>,
+    pub llvm_profdata: Option<String>,
+    pub llvm_cov: Option<String>,
+}
+
+/// Settings as they come from one source, with anything that source doesn't set left empty
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub min_line_coverage: Option<f32>,
+    pub min_branch_coverage: Option<f32>,
+    pub min_function_coverage: Option<f32>,
+    pub min_region_coverage: Option<f32>,
+    /// Minimum coverage of the lines changed since `diff_base`
+    pub min_patch_coverage: Option<f32>,
+    pub min_file_line_coverage: Option<f32>,
+    pub min_file_branch_coverage: Option<f32>,
+    pub package_thresholds: BTreeMap<String, PackageMinimums>,
+    /// Minimums for the files matching each glob, like `"src/core/**" = 0.95`
+    pub path_thresholds: BTreeMap<String, GroupMinimums>,
+    /// Minimums for the files each owner in `CODEOWNERS` owns, like `"@org/core" = 0.9`
+    pub owner_thresholds: BTreeMap<String, GroupMinimums>,
+    /// Minimums that only warn when they aren't met, for requirements that aren't enforced yet
+    pub warn_line_coverage: Option<f32>,
+    pub warn_branch_coverage: Option<f32>,
+    pub warn_function_coverage: Option<f32>,
+    pub warn_region_coverage: Option<f32>,
+    /// Read every minimum as a percentage, even ones of 1 or less
+    pub percent: Option<bool>,
+    /// Percentage points coverage can be under a minimum and still meet it
+    pub tolerance: Option<f32>,
+    /// Most lines of code allowed behind `#[coverage(off)]`
+    pub max_coverage_off_lines: Option<u64>,
+    /// Regexes for source files to leave out of the report
+    pub ignore: Vec<String>,
+    /// Regexes for the only source files to report on
+    pub include: Vec<String>,
+    /// Source paths to report under other paths, for coverage collected elsewhere
+    pub path_equivalence: Vec<PathEquivalence>,
+    /// Leave `#[cfg(test)]` code and `tests` directories out of the totals
+    pub exclude_tests: Option<bool>,
+    /// Keep sources generated into a build script's `OUT_DIR` in the report
+    pub include_generated: Option<bool>,
+    /// Directories searched recursively for `.profraw` files
+    pub profraw_dirs: Vec<PathBuf>,
+    /// `LLVM_PROFILE_FILE` pattern for the test run
+    pub profile_file: Option<PathBuf>,
+    /// Move the raw profiles into the profiles dir once they're merged, instead of deleting them
+    pub keep_profraw: Option<bool>,
+    /// Indexed profiles from other runs to merge into this one's
+    pub merge_with: Vec<PathBuf>,
+    /// Instrumented binaries to read coverage mappings from on top of the ones the tests built
+    pub objects: Vec<PathBuf>,
+    /// Where the profiles go, and the reports that aren't given a path
+    pub output_dir: Option<PathBuf>,
+    /// Build with only the coverage flags, instead of adding them to the user's RUSTFLAGS
+    pub replace_rustflags: Option<bool>,
+    /// Build into `target/coverage` rather than sharing `target` with normal builds
+    pub separate_target_dir: Option<bool>,
+    /// Test every workspace member, not just the one in the project dir
+    pub workspace: Option<bool>,
+    /// Only test these workspace members
+    pub packages: Vec<String>,
+    /// Workspace members to leave out with `workspace`
+    pub exclude: Vec<String>,
+    /// Only test the library, like `cargo test --lib`. Combines with the other target options.
+    pub lib: Option<bool>,
+    /// Only test the binaries
+    pub bins: Option<bool>,
+    /// Only run the test targets, the integration tests in `tests` and any marked `test = true`
+    pub tests: Option<bool>,
+    /// Only run these integration tests
+    pub test: Vec<String>,
+    /// Only rerun the tests of packages that changed since the last such run, and reuse the
+    /// coverage of the rest
+    pub changed_only: Option<bool>,
+    pub features: Vec<String>,
+    /// Feature sets to run the tests with one after the other, for the combined coverage. Each
+    /// is a comma-separated list, added to `features`, where `default` adds nothing.
+    pub feature_matrix: Vec<String>,
+    pub all_features: Option<bool>,
+    pub no_default_features: Option<bool>,
+    pub release: Option<bool>,
+    /// Target triple to build the tests for, like `aarch64-unknown-linux-gnu`
+    pub target: Option<String>,
+    /// Command that runs each test binary, like `qemu-aarch64 -L /usr/aarch64-linux-gnu`, with
+    /// the binary and its arguments after it
+    pub target_runner: Option<String>,
+    pub runner: Option<Runner>,
+    /// Shell command that runs the tests instead of the runner, like `just test-all`
+    pub test_command: Option<String>,
+    /// Carry on past failing tests and report the coverage they got anyway, instead of stopping
+    pub keep_going: Option<bool>,
+    /// Only run tests whose names contain this
+    pub filter: Option<String>,
+    /// Passed to every test binary, like the arguments after `--` in `cargo test`
+    pub test_args: Vec<String>,
+    /// Also measure what the doctests cover. Needs a nightly toolchain.
+    pub doctests: Option<bool>,
+    /// Instrument branches, so there's branch coverage to measure. Needs a nightly toolchain.
+    pub branch: Option<bool>,
+    /// Fail instead of warning when there's a branch threshold but no branch data
+    pub strict_branch: Option<bool>,
+    /// Git ref to measure patch coverage against, like `origin/main`
+    pub diff_base: Option<String>,
+    /// List the functions that never ran
+    pub show_uncovered_functions: Option<bool>,
+    pub format: Option<Format>,
+    pub export_strategy: Option<ExportStrategy>,
+    /// Show which step the run is on, and how long each took. Only ever shown when stdout is a
+    /// terminal.
+    pub progress: Option<bool>,
+    /// Leave out the tests' output and the progress, for when you only want the totals
+    pub quiet: Option<bool>,
+    pub annotate: Option<Annotate>,
+    pub upload: Option<Upload>,
+    /// Empty for [`DEFAULT_LCOV_FILE`] in the output dir
+    pub lcov: Option<PathBuf>,
+    /// Empty for [`DEFAULT_HTML_DIR`] in the output dir
+    pub html: Option<PathBuf>,
+    pub badge: Option<PathBuf>,
+    /// Where to write the totals and per-file numbers, for a later run to compare against
+    pub save_baseline: Option<PathBuf>,
+    /// A saved baseline to fail against if coverage dropped
+    pub baseline: Option<PathBuf>,
+    /// How far below the baseline coverage can drop, in percentage points
+    pub max_coverage_drop: Option<f32>,
+    /// Keep every file at or above the best coverage it has reached, recorded in a lock file
+    pub ratchet: Option<bool>,
+    /// Add this run's totals to the history file
+    pub record_history: Option<bool>,
+    /// JSON-lines file of past runs' totals
+    pub history_file: Option<PathBuf>,
+    /// Cargo.toml to point cargo at with `--manifest-path`. Only from the command line, where it
+    /// also sets the project dir.
+    #[serde(skip)]
+    pub manifest_path: Option<PathBuf>,
+    pub tools: ToolSettings,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tools {
+    pub cargo: String,
+    pub llvm_profdata: String,
+    pub llvm_cov: String,
+}
+
+impl From<ToolSettings> for Tools {
+    fn from(settings: ToolSettings) -> Self {
+        Tools {
+            cargo: settings.cargo.unwrap_or("cargo".to_string()),
+            llvm_profdata: settings
+                .llvm_profdata
+                .unwrap_or(tools::DEFAULT_LLVM_PROFDATA.to_string()),
+            llvm_cov: settings
+                .llvm_cov
+                .unwrap_or(tools::DEFAULT_LLVM_COV.to_string()),
+        }
+    }
+}
+
+/// Fully resolved settings for a run
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub project_dir: PathBuf,
+    /// Passed to every cargo command as `--manifest-path`, always absolute
+    pub manifest_path: Option<PathBuf>,
+    pub min_line_coverage: f32,
+    pub min_branch_coverage: f32,
+    pub min_function_coverage: Option<f32>,
+    pub min_region_coverage: Option<f32>,
+    pub min_patch_coverage: Option<f32>,
+    pub min_file_line_coverage: Option<f32>,
+    pub min_file_branch_coverage: Option<f32>,
+    pub package_thresholds: Vec<PackageThreshold>,
+    pub path_thresholds: Vec<PathThreshold>,
+    pub owner_thresholds: Vec<OwnerThreshold>,
+    /// Minimums that only warn, see [`Report::soft_failures`](crate::Report::soft_failures)
+    pub warn_line_coverage: Option<f32>,
+    pub warn_branch_coverage: Option<f32>,
+    pub warn_function_coverage: Option<f32>,
+    pub warn_region_coverage: Option<f32>,
+    /// How far under a minimum coverage can be and still meet it, as a fraction, for the noise
+    /// in coverage that sits right at one
+    pub tolerance: f32,
+    pub max_coverage_off_lines: Option<u64>,
+    pub ignore: Vec<String>,
+    pub include: Vec<String>,
+    pub path_equivalence: Vec<PathEquivalence>,
+    pub exclude_tests: bool,
+    pub include_generated: bool,
+    /// Never empty, defaults to just the project dir
+    pub profraw_dirs: Vec<PathBuf>,
+    /// Always absolute, since each test binary runs in its own package's dir
+    pub profile_file: PathBuf,
+    pub keep_profraw: bool,
+    pub merge_with: Vec<PathBuf>,
+    pub objects: Vec<PathBuf>,
+    /// Always absolute. Defaults to [`DEFAULT_OUTPUT_DIR`] in the workspace's target dir.
+    pub output_dir: PathBuf,
+    pub replace_rustflags: bool,
+    /// `CARGO_TARGET_DIR` for the coverage build, or `None` to use the usual one
+    pub target_dir: Option<PathBuf>,
+    pub workspace: bool,
+    pub packages: Vec<String>,
+    pub exclude: Vec<String>,
+    pub lib: bool,
+    pub bins: bool,
+    pub tests: bool,
+    pub test: Vec<String>,
+    pub changed_only: bool,
+    pub features: Vec<String>,
+    /// Each entry's features, already split up, with `default` taken out since cargo only
+    /// accepts it for packages that declare it
+    pub feature_matrix: Vec<Vec<String>>,
+    pub all_features: bool,
+    pub no_default_features: bool,
+    pub release: bool,
+    pub target: Option<String>,
+    pub target_runner: Option<String>,
+    pub runner: Runner,
+    pub test_command: Option<String>,
+    pub keep_going: bool,
+    pub filter: Option<String>,
+    pub test_args: Vec<String>,
+    pub doctests: bool,
+    pub branch: bool,
+    pub strict_branch: bool,
+    pub diff_base: Option<String>,
+    pub show_uncovered_functions: bool,
+    pub format: Format,
+    pub export_strategy: ExportStrategy,
+    pub progress: bool,
+    pub quiet: bool,
+    pub annotate: Option<Annotate>,
+    pub upload: Option<Upload>,
+    pub lcov: Option<PathBuf>,
+    pub html: Option<PathBuf>,
+    pub badge: Option<PathBuf>,
+    pub save_baseline: Option<PathBuf>,
+    pub baseline: Option<PathBuf>,
+    pub max_coverage_drop: f32,
+    pub ratchet: bool,
+    pub record_history: bool,
+    /// Defaults to [`DEFAULT_HISTORY_FILE`] in the project dir
+    pub history_file: PathBuf,
+    /// Export line-level data even when nothing configured needs it, for callers that use the
+    /// report's data themselves. Never set from a config source.
+    pub line_data: bool,
+    /// Source file to render with its line counts into [`crate::Report::source_view`], relative
+    /// to the project dir. Never set from a config source.
+    pub show_source: Option<PathBuf>,
+    pub tools: Tools,
+}
+
+impl Settings {
+    /// Reads a config file. Relative paths in it are taken relative to the file, not to
+    /// wherever the tool happens to be run from.
+    pub fn load(path: &Path) -> Result<Settings> {
+        let contents = fs::read_to_string(path).map_err(|e| CovError::io(path, e))?;
+        let settings: Settings = toml::from_str(&contents).map_err(|e| CovError::Config {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        Ok(settings.relative_to(path.parent().unwrap_or(Path::new("."))))
+    }
+
+    /// Reads the `simple-cov` table out of a `[package.metadata]` or `[workspace.metadata]`
+    fn from_metadata(metadata: &Option<Value>, manifest_dir: &Path) -> Result<Option<Settings>> {
+        let Some(table) = metadata.as_ref().and_then(|m| m.get(METADATA_KEY)) else {
+            return Ok(None);
+        };
+        let settings: Settings =
+            serde_json::from_value(table.clone()).map_err(|e| CovError::Config {
+                path: manifest_dir.join("Cargo.toml"),
+                message: format!("invalid [metadata.{}]: {}", METADATA_KEY, e),
+            })?;
+        Ok(Some(settings.relative_to(manifest_dir)))
+    }
+
+    /// Settings from the Cargo.toml metadata tables of the project and its workspace
+    fn from_manifests(metadata: &workspace::Metadata, project_dir: &Path) -> Result<Settings> {
+        let project_manifest = crate::canonicalize(&project_dir.join("Cargo.toml")).ok();
+
+        let mut settings = Settings::from_metadata(&metadata.metadata, &metadata.workspace_root)?
+            .unwrap_or_default();
+        for package in &metadata.packages {
+            let manifest_dir = package.manifest_path.parent().unwrap();
+            let Some(package_settings) = Settings::from_metadata(&package.metadata, manifest_dir)?
+            else {
+                continue;
+            };
+            if project_manifest.as_ref() == Some(&package.manifest_path) {
+                settings = package_settings.or(settings);
+            } else if let Some(line) = package_settings.min_line_coverage {
+                // A threshold for this member in the workspace's own package-thresholds wins
+                settings
+                    .package_thresholds
+                    .entry(package.name.clone())
+                    .or_insert(PackageMinimums {
+                        line,
+                        branch: package_settings.min_branch_coverage,
+                    });
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Makes relative paths relative to `base`. Empty ones are left empty, for the paths where
+    /// that means the default file in the output dir.
+    fn relative_to(mut self, base: &Path) -> Settings {
+        for path in [
+            &mut self.lcov,
+            &mut self.html,
+            &mut self.badge,
+            &mut self.save_baseline,
+            &mut self.baseline,
+            &mut self.history_file,
+            &mut self.profile_file,
+            &mut self.output_dir,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(&mut self.profraw_dirs)
+        .chain(&mut self.merge_with)
+        .chain(&mut self.objects)
+        .filter(|path| !path.as_os_str().is_empty())
+        {
+            *path = base.join(&path);
+        }
+        self
+    }
+
+    /// Layers `self` on top of `fallback`
+    pub fn or(self, fallback: Settings) -> Settings {
+        let mut package_thresholds = fallback.package_thresholds;
+        package_thresholds.extend(self.package_thresholds);
+        let mut path_thresholds = fallback.path_thresholds;
+        path_thresholds.extend(self.path_thresholds);
+        let mut owner_thresholds = fallback.owner_thresholds;
+        owner_thresholds.extend(self.owner_thresholds);
+        let mut ignore = fallback.ignore;
+        ignore.extend(self.ignore);
+        let mut include = fallback.include;
+        include.extend(self.include);
+        let mut path_equivalence = fallback.path_equivalence;
+        path_equivalence.extend(self.path_equivalence);
+        let mut profraw_dirs = fallback.profraw_dirs;
+        profraw_dirs.extend(self.profraw_dirs);
+        let mut merge_with = fallback.merge_with;
+        merge_with.extend(self.merge_with);
+        let mut objects = fallback.objects;
+        objects.extend(self.objects);
+        let mut packages = fallback.packages;
+        packages.extend(self.packages);
+        let mut exclude = fallback.exclude;
+        exclude.extend(self.exclude);
+        let mut test = fallback.test;
+        test.extend(self.test);
+        let mut features = fallback.features;
+        features.extend(self.features);
+        let mut feature_matrix = fallback.feature_matrix;
+        feature_matrix.extend(self.feature_matrix);
+        let mut test_args = fallback.test_args;
+        test_args.extend(self.test_args);
+
+        Settings {
+            min_line_coverage: self.min_line_coverage.or(fallback.min_line_coverage),
+            min_branch_coverage: self.min_branch_coverage.or(fallback.min_branch_coverage),
+            min_function_coverage: self
+                .min_function_coverage
+                .or(fallback.min_function_coverage),
+            min_region_coverage: self.min_region_coverage.or(fallback.min_region_coverage),
+            min_patch_coverage: self.min_patch_coverage.or(fallback.min_patch_coverage),
+            min_file_line_coverage: self
+                .min_file_line_coverage
+                .or(fallback.min_file_line_coverage),
+            min_file_branch_coverage: self
+                .min_file_branch_coverage
+                .or(fallback.min_file_branch_coverage),
+            package_thresholds,
+            path_thresholds,
+            owner_thresholds,
+            warn_line_coverage: self.warn_line_coverage.or(fallback.warn_line_coverage),
+            warn_branch_coverage: self.warn_branch_coverage.or(fallback.warn_branch_coverage),
+            warn_function_coverage: self
+                .warn_function_coverage
+                .or(fallback.warn_function_coverage),
+            warn_region_coverage: self.warn_region_coverage.or(fallback.warn_region_coverage),
+            percent: self.percent.or(fallback.percent),
+            tolerance: self.tolerance.or(fallback.tolerance),
+            max_coverage_off_lines: self
+                .max_coverage_off_lines
+                .or(fallback.max_coverage_off_lines),
+            ignore,
+            include,
+            path_equivalence,
+            exclude_tests: self.exclude_tests.or(fallback.exclude_tests),
+            include_generated: self.include_generated.or(fallback.include_generated),
+            profraw_dirs,
+            profile_file: self.profile_file.or(fallback.profile_file),
+            keep_profraw: self.keep_profraw.or(fallback.keep_profraw),
+            merge_with,
+            objects,
+            output_dir: self.output_dir.or(fallback.output_dir),
+            replace_rustflags: self.replace_rustflags.or(fallback.replace_rustflags),
+            separate_target_dir: self.separate_target_dir.or(fallback.separate_target_dir),
+            workspace: self.workspace.or(fallback.workspace),
+            packages,
+            exclude,
+            lib: self.lib.or(fallback.lib),
+            bins: self.bins.or(fallback.bins),
+            tests: self.tests.or(fallback.tests),
+            test,
+            changed_only: self.changed_only.or(fallback.changed_only),
+            features,
+            feature_matrix,
+            all_features: self.all_features.or(fallback.all_features),
+            no_default_features: self.no_default_features.or(fallback.no_default_features),
+            release: self.release.or(fallback.release),
+            target: self.target.or(fallback.target),
+            target_runner: self.target_runner.or(fallback.target_runner),
+            runner: self.runner.or(fallback.runner),
+            test_command: self.test_command.or(fallback.test_command),
+            keep_going: self.keep_going.or(fallback.keep_going),
+            filter: self.filter.or(fallback.filter),
+            test_args,
+            doctests: self.doctests.or(fallback.doctests),
+            branch: self.branch.or(fallback.branch),
+            strict_branch: self.strict_branch.or(fallback.strict_branch),
+            diff_base: self.diff_base.or(fallback.diff_base),
+            show_uncovered_functions: self
+                .show_uncovered_functions
+                .or(fallback.show_uncovered_functions),
+            format: self.format.or(fallback.format),
+            export_strategy: self.export_strategy.or(fallback.export_strategy),
+            progress: self.progress.or(fallback.progress),
+            quiet: self.quiet.or(fallback.quiet),
+            annotate: self.annotate.or(fallback.annotate),
+            upload: self.upload.or(fallback.upload),
+            lcov: self.lcov.or(fallback.lcov),
+            html: self.html.or(fallback.html),
+            badge: self.badge.or(fallback.badge),
+            save_baseline: self.save_baseline.or(fallback.save_baseline),
+            baseline: self.baseline.or(fallback.baseline),
+            max_coverage_drop: self.max_coverage_drop.or(fallback.max_coverage_drop),
+            ratchet: self.ratchet.or(fallback.ratchet),
+            record_history: self.record_history.or(fallback.record_history),
+            history_file: self.history_file.or(fallback.history_file),
+            manifest_path: self.manifest_path.or(fallback.manifest_path),
+            tools: ToolSettings {
+                cargo: self.tools.cargo.or(fallback.tools.cargo),
+                llvm_profdata: self.tools.llvm_profdata.or(fallback.tools.llvm_profdata),
+                llvm_cov: self.tools.llvm_cov.or(fallback.tools.llvm_cov),
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Resolves `cli` against the config file and defaults. `config_path` overrides where the
+    /// config file is looked for; when it's not given a missing config file is fine.
+    pub fn resolve(
+        project_dir: PathBuf,
+        config_path: Option<PathBuf>,
+        cli: Settings,
+    ) -> Result<Config> {
+        let file = match config_path {
+            Some(path) => Settings::load(&path)?,
+            None => {
+                let path = project_dir.join(CONFIG_FILE);
+                if path.exists() {
+                    Settings::load(&path)?
+                } else {
+                    Settings::default()
+                }
+            }
+        };
+        let cargo = cli
+            .tools
+            .cargo
+            .clone()
+            .or(file.tools.cargo.clone())
+            .unwrap_or("cargo".to_string());
+        let manifest_path = match &cli.manifest_path {
+            Some(path) => Some(std::path::absolute(path).map_err(|e| CovError::io(path, e))?),
+            None => None,
+        };
+        let metadata = workspace::metadata(&cargo, &project_dir, manifest_path.as_deref())?;
+        let manifests = Settings::from_manifests(&metadata, &project_dir)?;
+        let settings = cli.or(file.or(manifests));
+
+        let profraw_dirs = if settings.profraw_dirs.is_empty() {
+            vec![project_dir.clone()]
+        } else {
+            settings.profraw_dirs
+        };
+        let output_dir = settings
+            .output_dir
+            .unwrap_or_else(|| metadata.target_directory.join(DEFAULT_OUTPUT_DIR));
+        let output_dir =
+            std::path::absolute(&output_dir).map_err(|e| CovError::io(&output_dir, e))?;
+        let in_output_dir = |path: Option<PathBuf>, default: &str| {
+            path.map(|p| {
+                if p.as_os_str().is_empty() {
+                    output_dir.join(default)
+                } else {
+                    p
+                }
+            })
+        };
+        let lcov = in_output_dir(settings.lcov, DEFAULT_LCOV_FILE);
+        let html = in_output_dir(settings.html, DEFAULT_HTML_DIR);
+        let profile_file = settings
+            .profile_file
+            .unwrap_or_else(|| output_dir.join(DEFAULT_PROFILE_FILE));
+        let profile_file =
+            std::path::absolute(&profile_file).map_err(|e| CovError::io(&profile_file, e))?;
+        let history_file = settings
+            .history_file
+            .unwrap_or_else(|| project_dir.join(DEFAULT_HISTORY_FILE));
+        let tools = tools::locate(settings.tools, &project_dir);
+        let percent = settings.percent.unwrap_or(false);
+        let min =
+            |name: &str, value: Option<f32>| value.map(|v| threshold(name, v, percent)).transpose();
+        let package_thresholds = settings
+            .package_thresholds
+            .into_iter()
+            .map(|(package, mins)| {
+                Ok(PackageThreshold {
+                    min_line_coverage: threshold(
+                        &format!("line minimum for package {}", package),
+                        mins.line,
+                        percent,
+                    )?,
+                    min_branch_coverage: min(
+                        &format!("branch minimum for package {}", package),
+                        mins.branch,
+                    )?,
+                    package,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let path_thresholds = settings
+            .path_thresholds
+            .into_iter()
+            .map(|(pattern, mins)| {
+                // Like .gitignore, so `src/*` is only the files directly in src
+                let glob = GlobBuilder::new(&pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| CovError::Pattern {
+                        pattern: pattern.clone(),
+                        message: e.kind().to_string(),
+                    })?;
+                let (line, branch) = mins.line_and_branch();
+                Ok(PathThreshold {
+                    glob: glob.compile_matcher(),
+                    min_line_coverage: threshold(
+                        &format!("line minimum for {}", pattern),
+                        line,
+                        percent,
+                    )?,
+                    min_branch_coverage: min(&format!("branch minimum for {}", pattern), branch)?,
+                    pattern,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let owner_thresholds = settings
+            .owner_thresholds
+            .into_iter()
+            .map(|(owner, mins)| {
+                let (line, branch) = mins.line_and_branch();
+                Ok(OwnerThreshold {
+                    min_line_coverage: threshold(
+                        &format!("line minimum for {}", owner),
+                        line,
+                        percent,
+                    )?,
+                    min_branch_coverage: min(&format!("branch minimum for {}", owner), branch)?,
+                    owner,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let tolerance = settings
+            .tolerance
+            .map(|t| points("tolerance", t))
+            .transpose()?
+            .unwrap_or(0.0);
+
+        let config = Config {
+            project_dir,
+            manifest_path,
+            min_line_coverage: min("min-line-coverage", settings.min_line_coverage)?
+                .unwrap_or(DEFAULT_MIN_LINE_COVERAGE),
+            min_branch_coverage: min("min-branch-coverage", settings.min_branch_coverage)?
+                .unwrap_or(DEFAULT_MIN_BRANCH_COVERAGE),
+            min_function_coverage: min("min-function-coverage", settings.min_function_coverage)?,
+            min_region_coverage: min("min-region-coverage", settings.min_region_coverage)?,
+            min_patch_coverage: min("min-patch-coverage", settings.min_patch_coverage)?,
+            min_file_line_coverage: min("min-file-line-coverage", settings.min_file_line_coverage)?,
+            min_file_branch_coverage: min(
+                "min-file-branch-coverage",
+                settings.min_file_branch_coverage,
+            )?,
+            package_thresholds,
+            path_thresholds,
+            owner_thresholds,
+            warn_line_coverage: min("warn-line-coverage", settings.warn_line_coverage)?,
+            warn_branch_coverage: min("warn-branch-coverage", settings.warn_branch_coverage)?,
+            warn_function_coverage: min("warn-function-coverage", settings.warn_function_coverage)?,
+            warn_region_coverage: min("warn-region-coverage", settings.warn_region_coverage)?,
+            tolerance,
+            max_coverage_off_lines: settings.max_coverage_off_lines,
+            ignore: settings.ignore,
+            include: settings.include,
+            path_equivalence: settings.path_equivalence,
+            exclude_tests: settings.exclude_tests.unwrap_or(false),
+            include_generated: settings.include_generated.unwrap_or(false),
+            profraw_dirs,
+            profile_file,
+            keep_profraw: settings.keep_profraw.unwrap_or(false),
+            merge_with: settings.merge_with,
+            objects: settings.objects,
+            output_dir,
+            replace_rustflags: settings.replace_rustflags.unwrap_or(false),
+            target_dir: settings
+                .separate_target_dir
+                .unwrap_or(true)
+                .then(|| metadata.target_directory.join(COVERAGE_TARGET_DIR)),
+            workspace: settings.workspace.unwrap_or(false),
+            packages: settings.packages,
+            exclude: settings.exclude,
+            lib: settings.lib.unwrap_or(false),
+            bins: settings.bins.unwrap_or(false),
+            tests: settings.tests.unwrap_or(false),
+            test: settings.test,
+            changed_only: settings.changed_only.unwrap_or(false),
+            features: settings.features,
+            feature_matrix: settings
+                .feature_matrix
+                .iter()
+                .map(|set| {
+                    set.split([',', ' '])
+                        .filter(|f| !f.is_empty() && *f != "default")
+                        .map(str::to_string)
+                        .collect()
+                })
+                .collect(),
+            all_features: settings.all_features.unwrap_or(false),
+            no_default_features: settings.no_default_features.unwrap_or(false),
+            release: settings.release.unwrap_or(false),
+            target: settings.target,
+            target_runner: settings.target_runner,
+            runner: settings.runner.unwrap_or(Runner::Cargo),
+            test_command: settings.test_command,
+            keep_going: settings.keep_going.unwrap_or(false),
+            filter: settings.filter,
+            test_args: settings.test_args,
+            doctests: settings.doctests.unwrap_or(false),
+            branch: settings.branch.unwrap_or(false),
+            strict_branch: settings.strict_branch.unwrap_or(false),
+            diff_base: settings.diff_base,
+            show_uncovered_functions: settings.show_uncovered_functions.unwrap_or(false),
+            format: settings.format.unwrap_or(Format::Text),
+            export_strategy: settings.export_strategy.unwrap_or(ExportStrategy::Single),
+            progress: settings.progress.unwrap_or(true),
+            quiet: settings.quiet.unwrap_or(false),
+            annotate: settings.annotate,
+            upload: settings.upload,
+            lcov,
+            html,
+            badge: settings.badge,
+            save_baseline: settings.save_baseline,
+            baseline: settings.baseline,
+            max_coverage_drop: settings
+                .max_coverage_drop
+                .map(|d| points("max-coverage-drop", d))
+                .transpose()?
+                .unwrap_or(0.0),
+            ratchet: settings.ratchet.unwrap_or(false),
+            record_history: settings.record_history.unwrap_or(false),
+            history_file,
+            line_data: false,
+            show_source: None,
+            tools,
+        };
+        debug!(?config, "resolved the config");
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_to_keeps_empty_paths_for_the_defaults() {
+        let settings: Settings = toml::from_str("lcov = \"\"\nhtml = \"reports/html\"").unwrap();
+        let settings = settings.relative_to(Path::new("/project"));
+        assert_eq!(settings.lcov, Some(PathBuf::new()));
+        assert_eq!(settings.html, Some(PathBuf::from("/project/reports/html")));
+    }
+
+    fn toml(settings: &str) -> Settings {
+        toml::from_str(settings).unwrap()
+    }
+
+    #[test]
+    fn or_prefers_self_for_scalars_and_combines_lists() {
+        let settings = toml(
+            "min-line-coverage = 0.9\nignore = [\"b\"]\n\
+             package-thresholds = { a = { line = 0.9 } }",
+        )
+        .or(toml(
+            "min-line-coverage = 0.5\nmin-branch-coverage = 0.4\nignore = [\"a\"]\n\
+             package-thresholds = { a = { line = 0.5 }, b = { line = 0.5 } }",
+        ));
+        assert_eq!(settings.min_line_coverage, Some(0.9));
+        assert_eq!(settings.min_branch_coverage, Some(0.4));
+        assert_eq!(settings.min_function_coverage, None);
+        // The fallback's first
+        assert_eq!(settings.ignore, ["a", "b"]);
+        // Only the packages it mentions are replaced
+        let lines: Vec<_> = settings
+            .package_thresholds
+            .iter()
+            .map(|(package, mins)| (package.as_str(), mins.line))
+            .collect();
+        assert_eq!(lines, [("a", 0.9), ("b", 0.5)]);
+    }
+
+    #[test]
+    fn members_thresholds_become_package_thresholds_unless_the_workspace_sets_them() {
+        let dir = std::env::temp_dir().join(format!("simple-cov-manifests-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Cargo.toml"), "").unwrap();
+        let dir = crate::canonicalize(&dir).unwrap();
+        let package =
+            |name: &str, manifest_path: PathBuf, metadata: Value| workspace::MetadataPackage {
+                name: name.to_string(),
+                manifest_path,
+                dependencies: vec![],
+                metadata: Some(serde_json::json!({ METADATA_KEY: metadata })),
+            };
+        let metadata = workspace::Metadata {
+            packages: vec![
+                package(
+                    "project",
+                    dir.join("Cargo.toml"),
+                    serde_json::json!({ "min-line-coverage": 0.7 }),
+                ),
+                package(
+                    "member",
+                    dir.join("member/Cargo.toml"),
+                    serde_json::json!({ "min-line-coverage": 0.6, "min-branch-coverage": 0.5 }),
+                ),
+                package(
+                    "set",
+                    dir.join("set/Cargo.toml"),
+                    serde_json::json!({ "min-line-coverage": 0.1 }),
+                ),
+            ],
+            workspace_root: dir.clone(),
+            target_directory: dir.join("target"),
+            metadata: Some(serde_json::json!({ METADATA_KEY: {
+                "min-line-coverage": 0.5,
+                "package-thresholds": { "set": { "line": 0.9 } },
+            } })),
+        };
+        let settings = Settings::from_manifests(&metadata, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The project's own table is layered on the workspace's
+        assert_eq!(settings.min_line_coverage, Some(0.7));
+        let thresholds: Vec<_> = settings
+            .package_thresholds
+            .iter()
+            .map(|(package, mins)| (package.as_str(), mins.line, mins.branch))
+            .collect();
+        assert_eq!(thresholds, [("member", 0.6, Some(0.5)), ("set", 0.9, None)]);
+    }
+
+    #[test]
+    fn the_command_line_then_the_file_then_the_manifests() {
+        let dir =
+            std::env::temp_dir().join(format!("simple-cov-precedence-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/lib.rs"), "").unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "\
+[package]
+name = \"precedence\"
+edition = \"2021\"
+
+[package.metadata.simple-cov]
+min-line-coverage = 0.3
+min-branch-coverage = 0.3
+min-function-coverage = 0.3
+ignore = [\"package\"]
+
+[workspace]
+
+[workspace.metadata.simple-cov]
+min-line-coverage = 0.4
+min-branch-coverage = 0.4
+min-function-coverage = 0.4
+min-region-coverage = 0.4
+ignore = [\"workspace\"]
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(CONFIG_FILE),
+            "min-line-coverage = 0.2\nmin-branch-coverage = 0.2\nignore = [\"file\"]\n",
+        )
+        .unwrap();
+        let cli = toml("min-line-coverage = 0.1\nignore = [\"cli\"]");
+        let config = Config::resolve(dir.clone(), None, cli);
+        fs::remove_dir_all(&dir).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.min_line_coverage, 0.1);
+        assert_eq!(config.min_branch_coverage, 0.2);
+        assert_eq!(config.min_function_coverage, Some(0.3));
+        assert_eq!(config.min_region_coverage, Some(0.4));
+        assert_eq!(config.ignore, ["workspace", "package", "file", "cli"]);
+    }
+
+    #[test]
+    fn thresholds_are_fractions_or_percentages() {
+        // (value, percent, fraction)
+        let cases = [
+            (0.0, false, 0.0),
+            (0.8, false, 0.8),
+            // 1 is all of it, unless everything is a percentage
+            (1.0, false, 1.0),
+            (1.0, true, 0.01),
+            // Past 1, only a percentage makes sense
+            (1.5, false, 0.015),
+            (80.0, false, 0.8),
+            (80.0, true, 0.8),
+            (0.5, true, 0.005),
+            (100.0, false, 1.0),
+        ];
+        for (value, percent, fraction) in cases {
+            assert_eq!(
+                threshold("min-line-coverage", value, percent).unwrap(),
+                fraction,
+                "{} with percent {}",
+                value,
+                percent
+            );
+        }
+        for value in [100.5, -0.1, f32::NAN] {
+            for percent in [false, true] {
+                assert!(
+                    threshold("min-line-coverage", value, percent).is_err(),
+                    "{} with percent {}",
+                    value,
+                    percent
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn points_are_points_whatever_the_value() {
+        assert_eq!(points("max-coverage-drop", 1.0).unwrap(), 0.01);
+        assert_eq!(points("max-coverage-drop", 0.5).unwrap(), 0.005);
+        assert_eq!(points("max-coverage-drop", 0.0).unwrap(), 0.0);
+        assert_eq!(points("max-coverage-drop", 100.0).unwrap(), 1.0);
+        for value in [-0.5, 100.5, f32::NAN] {
+            assert!(points("max-coverage-drop", value).is_err(), "{}", value);
+        }
+    }
+}
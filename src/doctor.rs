@@ -0,0 +1,165 @@
+//! The `doctor` command's checks of everything a run needs, so a broken setup shows up as a
+//! list of fixes up front rather than as an error halfway through a run.
+
+use std::{env, fs, path::Path, process::Command};
+
+use crate::{
+    config::Tools,
+    exec,
+    tools::{self, rustc_field},
+};
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// Fine, with what was found
+    Ok(String),
+    /// Not needed for a run, but worth knowing about
+    Warning { problem: String, fix: String },
+    /// A run would fail because of this
+    Failed { problem: String, fix: String },
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+impl Check {
+    pub fn failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed { .. })
+    }
+}
+
+const LLVM_TOOLS_HINT: &str = "Run `rustup component add llvm-tools`";
+
+/// Runs every check, in the order a run needs things
+pub fn checks(project_dir: &Path, tools: &Tools) -> Vec<Check> {
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    let rustc_info = output(project_dir, &rustc, &["-vV"]);
+    let rustc_llvm = rustc_info
+        .as_deref()
+        .ok()
+        .and_then(|i| rustc_field(i, "LLVM version: "))
+        .map(str::to_string);
+
+    let mut checks = vec![
+        Check {
+            name: "rustup",
+            outcome: match output(project_dir, "rustup", &["--version"]) {
+                Ok(version) => Outcome::Ok(first_line(&version)),
+                Err(e) => Outcome::Warning {
+                    problem: e,
+                    fix: "Install rustup from https://rustup.rs, or make sure the llvm tools match your toolchain some other way".to_string(),
+                },
+            },
+        },
+        Check {
+            name: "rustc",
+            outcome: match &rustc_info {
+                Ok(info) => Outcome::Ok(format!(
+                    "{} for {}, LLVM {}",
+                    rustc_field(info, "release: ").unwrap_or("unknown version"),
+                    rustc_field(info, "host: ").unwrap_or("an unknown host"),
+                    rustc_llvm.as_deref().unwrap_or("unknown")
+                )),
+                Err(e) => Outcome::Failed {
+                    problem: e.clone(),
+                    fix: "Install a Rust toolchain, with rustup or your package manager"
+                        .to_string(),
+                },
+            },
+        },
+        Check {
+            name: "llvm-tools",
+            outcome: match output(project_dir, "rustup", &["component", "list", "--installed"]) {
+                Ok(installed) if installed.lines().any(|c| c.starts_with("llvm-tools")) => {
+                    Outcome::Ok("installed for the active toolchain".to_string())
+                }
+                Ok(_) => Outcome::Failed {
+                    problem: "not installed for the active toolchain".to_string(),
+                    fix: "Run `rustup component add llvm-tools`".to_string(),
+                },
+                Err(_) => Outcome::Warning {
+                    problem: "couldn't ask rustup".to_string(),
+                    fix: "Make sure llvm-profdata and llvm-cov from the same LLVM version as rustc are installed".to_string(),
+                },
+            },
+        },
+        Check {
+            name: "cargo",
+            outcome: match output(project_dir, &tools.cargo, &["--version"]) {
+                Ok(version) => Outcome::Ok(first_line(&version)),
+                Err(e) => Outcome::Failed {
+                    problem: e,
+                    fix: "Install cargo, or point `tools.cargo` in the config file at it"
+                        .to_string(),
+                },
+            },
+        },
+    ];
+    for (name, tool) in [
+        ("llvm-profdata", &tools.llvm_profdata),
+        ("llvm-cov", &tools.llvm_cov),
+    ] {
+        checks.push(Check {
+            name,
+            outcome: llvm_tool(tool, rustc_llvm.as_deref()),
+        });
+    }
+    checks.push(Check {
+        name: "project dir",
+        outcome: writable(project_dir),
+    });
+    checks
+}
+
+/// Whether the tool runs, and is from the same LLVM major version as rustc. An older
+/// llvm-profdata can't read the profiles a newer LLVM writes.
+fn llvm_tool(tool: &str, rustc_llvm: Option<&str>) -> Outcome {
+    if let Err(e) = tools::version(tool) {
+        return Outcome::Failed {
+            problem: format!("couldn't run {}: {}", tool, e),
+            fix: LLVM_TOOLS_HINT.to_string(),
+        };
+    }
+    let Some(llvm) = tools::llvm_version(tool) else {
+        return Outcome::Ok(format!("{} runs", tool));
+    };
+    let major = |v: &str| v.split('.').next().unwrap_or_default().to_string();
+    match rustc_llvm {
+        Some(rustc_llvm) if major(&llvm) != major(rustc_llvm) => Outcome::Failed {
+            problem: format!(
+                "{} is LLVM {}, but rustc uses LLVM {}",
+                tool, llvm, rustc_llvm
+            ),
+            fix: format!(
+                "Use the llvm tools that come with the toolchain. {}",
+                LLVM_TOOLS_HINT
+            ),
+        },
+        _ => Outcome::Ok(format!("{}, LLVM {}", tool, llvm)),
+    }
+}
+
+fn writable(project_dir: &Path) -> Outcome {
+    let probe = project_dir.join(".simple-cov-doctor");
+    match fs::write(&probe, "").and_then(|()| fs::remove_file(&probe)) {
+        Ok(()) => Outcome::Ok(format!("{} is writable", project_dir.display())),
+        Err(e) => Outcome::Failed {
+            problem: format!("can't write to {}: {}", project_dir.display(), e),
+            fix: "The profiles and reports are written there, so run from a copy you can write to, or fix its permissions".to_string(),
+        },
+    }
+}
+
+/// The tool's stdout, or why it couldn't be had
+fn output(project_dir: &Path, tool: &str, args: &[&str]) -> Result<String, String> {
+    exec(Command::new(tool).args(args).current_dir(project_dir))
+        .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+        .map_err(|e| e.to_string().lines().next().unwrap_or_default().to_string())
+}
+
+fn first_line(text: &str) -> String {
+    text.lines().next().unwrap_or_default().to_string()
+}
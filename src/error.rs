@@ -0,0 +1,162 @@
+use std::{
+    io,
+    path::PathBuf,
+    process::{ExitStatus, Output},
+};
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, CovError>;
+
+/// Exit code when the tests passed and every coverage requirement was met
+pub const EXIT_OK: i32 = 0;
+/// Exit code when the run worked but a coverage requirement wasn't met
+pub const EXIT_THRESHOLDS: i32 = 1;
+/// Exit code when a tool is missing or broken, or the config is invalid
+pub const EXIT_SETUP: i32 = 2;
+/// Exit code when the test suite itself failed
+pub const EXIT_TESTS: i32 = 3;
+/// Exit code when a tool's output couldn't be understood
+pub const EXIT_PARSE: i32 = 4;
+/// Exit code with `keep_going` when the tests failed and a coverage requirement wasn't met
+/// either
+pub const EXIT_TESTS_AND_THRESHOLDS: i32 = 5;
+
+/// Everything that can go wrong in a coverage run, short of a bug in this tool
+#[derive(Debug, Error)]
+pub enum CovError {
+    /// An external tool couldn't be started at all, usually because it isn't installed
+    #[error("couldn't run {tool}: {source}{}", install_hint(tool))]
+    Spawn {
+        tool: String,
+        #[source]
+        source: io::Error,
+    },
+    /// An external tool ran but exited unsuccessfully
+    #[error("{tool} failed ({status}):\n{}", output_text(stdout, stderr))]
+    ToolFailed {
+        tool: String,
+        status: ExitStatus,
+        stdout: String,
+        stderr: String,
+    },
+    /// One of the instrumented test binaries failed
+    #[error("tests failed in {test} ({status}):\n{}", output_text(stdout, stderr))]
+    TestsFailed {
+        test: String,
+        status: ExitStatus,
+        stdout: String,
+        stderr: String,
+    },
+    /// The tests ran but left no raw profiles behind to merge
+    #[error(
+        "no .profraw files found in {}. Were the tests built with -C instrument-coverage?",
+        dirs.iter().map(|d| d.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    NoProfiles { dirs: Vec<PathBuf> },
+    /// There's no earlier run to report on
+    #[error(
+        "no coverage data in {}. Run the tests first, with no command or the run command",
+        path.display()
+    )]
+    NoCoverageData { path: PathBuf },
+    /// A user-supplied regex doesn't compile
+    #[error("invalid pattern {pattern}: {message}")]
+    Pattern { pattern: String, message: String },
+    /// The include patterns ruled out every covered file
+    #[error("no covered source files match {}", patterns.join(", "))]
+    NoSources { patterns: Vec<String> },
+    /// The toolchain can't do something that was asked for
+    #[error("{0}")]
+    Toolchain(String),
+    /// Output from an external tool wasn't in the shape we expected
+    #[error("couldn't parse {what}: {message}")]
+    Parse { what: String, message: String },
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("invalid config in {}: {message}", path.display())]
+    Config { path: PathBuf, message: String },
+    /// A coverage minimum that's neither a fraction nor a percentage
+    #[error("invalid {name} {value}: {reason}")]
+    Threshold {
+        name: String,
+        value: f32,
+        reason: &'static str,
+    },
+    #[error("upload failed: {0}")]
+    Upload(String),
+    /// The terminal couldn't be set up or drawn on for the `tui` command
+    #[error("terminal error: {0}")]
+    Terminal(#[source] io::Error),
+    /// The source tree couldn't be watched for `--watch`
+    #[error("couldn't watch for changes: {0}")]
+    Watch(#[source] notify::Error),
+}
+
+impl CovError {
+    /// The process exit code for this kind of failure, so CI scripts can tell "coverage too
+    /// low" apart from "the tool broke"
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CovError::TestsFailed { .. } => EXIT_TESTS,
+            CovError::Parse { .. } => EXIT_PARSE,
+            CovError::Spawn { .. }
+            | CovError::ToolFailed { .. }
+            | CovError::NoProfiles { .. }
+            | CovError::NoCoverageData { .. }
+            | CovError::Toolchain(_)
+            | CovError::Pattern { .. }
+            | CovError::NoSources { .. }
+            | CovError::Io { .. }
+            | CovError::Config { .. }
+            | CovError::Threshold { .. }
+            | CovError::Upload(_)
+            | CovError::Terminal(_)
+            | CovError::Watch(_) => EXIT_SETUP,
+        }
+    }
+
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        CovError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn parse(what: impl Into<String>, message: impl ToString) -> Self {
+        CovError::Parse {
+            what: what.into(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn tool_failed(tool: impl Into<String>, output: &Output) -> Self {
+        CovError::ToolFailed {
+            tool: tool.into(),
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+fn install_hint(tool: &str) -> &'static str {
+    match tool {
+        "rust-profdata" | "rust-cov" => {
+            "\nInstall them with `rustup component add llvm-tools`, or rerun with --yes to have that done for you"
+        }
+        _ => "",
+    }
+}
+
+fn output_text(stdout: &str, stderr: &str) -> String {
+    [stdout.trim_end(), stderr.trim_end()]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,541 @@
+//! Leaving parts of source files out of the totals. llvm-cov can only ignore whole files, so
+//! this works on the exported per-line data instead: excluded lines are dropped from each file's
+//! line and branch counts, and the totals are added up again from what's left.
+//!
+//! Lines are excluded with comments in the source, which always apply:
+//!
+//! ```text
+//! // cov:ignore-start
+//! fn not_worth_testing() {}
+//! // cov:ignore-end
+//! unreachable!(); // cov:ignore-line
+//! ```
+//!
+//! and with `exclude_tests`, which excludes `#[cfg(test)]` items. Items with `#[coverage(off)]`
+//! aren't instrumented in the first place, so they're only found to report them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    ops::Range,
+    path::Path,
+    sync::LazyLock,
+};
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    Config,
+    export::{ExportData, Metric},
+};
+
+static MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"//\s*cov:ignore-(start|end|line)\b").unwrap());
+
+/// Items that `#[cfg(test)]` can hide which are worth excluding. Anything else behind the
+/// attribute, like a single field, is too small to matter.
+const TEST_ITEMS: [&str; 3] = ["mod", "fn", "impl"];
+
+/// An item behind an attribute, like `#[cfg(test)] mod tests { ... }`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AttributedItem {
+    /// What the item is, like `fn helper` or `impl Display for Id`
+    pub item: String,
+    /// Line of the attribute
+    pub line_start: u64,
+    /// Line of the closing brace, or the semicolon of an item like `mod tests;`
+    pub line_end: u64,
+}
+
+impl AttributedItem {
+    pub fn lines(&self) -> u64 {
+        self.line_end - self.line_start + 1
+    }
+}
+
+/// Ignore regex for the `tests` directories of every package under `root`
+pub fn tests_dir_regex(root: &Path) -> String {
+    format!(
+        r"^{}[/\\](.*[/\\])?tests[/\\]",
+        regex::escape(&root.to_string_lossy())
+    )
+}
+
+/// Ignore regex for everything under a build script's `OUT_DIR`
+pub fn out_dir_regex(out_dir: &Path) -> String {
+    format!(r"^{}[/\\]", regex::escape(&out_dir.to_string_lossy()))
+}
+
+/// Lines to exclude from each file that has any, by filename
+pub fn excluded_lines(config: &Config, data: &ExportData) -> BTreeMap<String, BTreeSet<u64>> {
+    let mut excluded = BTreeMap::new();
+    for file in &data.files {
+        // Generated files can be gone by now, and there's nothing to exclude in those anyway
+        let Ok(source) = fs::read_to_string(&file.filename) else {
+            continue;
+        };
+        let mut lines = marked_lines(&source);
+        if config.exclude_tests {
+            lines.extend(test_lines(&source));
+        }
+        if !lines.is_empty() {
+            excluded.insert(file.filename.clone(), lines);
+        }
+    }
+    excluded
+}
+
+/// Marks the excluded lines of every file and recomputes the line and branch summaries without
+/// them, which leaves the numbers different from llvm-cov's own table. Needs the line-level
+/// export.
+pub fn apply(data: &mut ExportData, mut excluded: BTreeMap<String, BTreeSet<u64>>) {
+    if excluded.is_empty() {
+        return;
+    }
+
+    for file in &mut data.files {
+        let Some(lines) = excluded.remove(&file.filename) else {
+            continue;
+        };
+        file.excluded_lines = lines;
+
+        let lines = file.line_counts();
+        file.summary.lines = Metric::new(
+            lines.len() as u64,
+            lines.iter().filter(|l| l.count > 0).count() as u64,
+        );
+        let branches = file.branch_counts();
+        file.summary.branches = Metric::new(
+            branches.values().map(|b| b.total).sum(),
+            branches.values().map(|b| b.covered).sum(),
+        );
+    }
+
+    data.totals.lines = Metric::default();
+    data.totals.branches = Metric::default();
+    for file in &data.files {
+        data.totals.lines.add(&file.summary.lines);
+        data.totals.branches.add(&file.summary.branches);
+    }
+}
+
+/// Lines marked with `cov:ignore-line`, or between `cov:ignore-start` and `cov:ignore-end`,
+/// markers included. A start without an end runs to the end of the file. Only markers in
+/// comments count, not ones in a string like a test's source fixture.
+fn marked_lines(source: &str) -> BTreeSet<u64> {
+    let mut excluded = BTreeSet::new();
+    let mut in_block = false;
+    for (line, text) in (1..).zip(comments(source).lines()) {
+        match MARKER
+            .captures(text)
+            .and_then(|c| c.get(1))
+            .map(|m| m.as_str())
+        {
+            Some("start") => in_block = true,
+            Some("end") if in_block => {
+                in_block = false;
+                excluded.insert(line);
+            }
+            Some("line") => {
+                excluded.insert(line);
+            }
+            _ => {}
+        }
+        if in_block {
+            excluded.insert(line);
+        }
+    }
+    excluded
+}
+
+/// Lines of the modules, functions, and impls behind `#[cfg(test)]`, from the attribute to the
+/// closing brace
+fn test_lines(source: &str) -> BTreeSet<u64> {
+    attributed_items(source, |attr| attr == "cfg(test)")
+        .iter()
+        .flat_map(|item| item.line_start..=item.line_end)
+        .collect()
+}
+
+/// Whether an attribute, without its whitespace, turns coverage off, either directly or through
+/// `cfg_attr` the way crates that also build on stable do it
+fn is_coverage_off(attr: &str) -> bool {
+    attr == "coverage(off)" || (attr.starts_with("cfg_attr(") && attr.contains(",coverage(off)"))
+}
+
+/// The items in each file of the report that `#[coverage(off)]` switches off. llvm-cov never
+/// sees those, so this is the only place they show up.
+pub fn coverage_off(data: &ExportData) -> BTreeMap<String, Vec<AttributedItem>> {
+    let mut off = BTreeMap::new();
+    for file in &data.files {
+        let Ok(source) = fs::read_to_string(&file.filename) else {
+            continue;
+        };
+        let items = attributed_items(&source, is_coverage_off);
+        if !items.is_empty() {
+            off.insert(file.filename.clone(), items);
+        }
+    }
+    off
+}
+
+/// The [`TEST_ITEMS`] items with an attribute that `is_match` accepts. It gets the attribute's
+/// contents with the whitespace taken out, like `cfg(test)`.
+fn attributed_items(source: &str, is_match: impl Fn(&str) -> bool) -> Vec<AttributedItem> {
+    let code: Vec<char> = blank_literals(source).chars().collect();
+    // 1-based line of every char
+    let mut lines = Vec::with_capacity(code.len());
+    let mut line = 1;
+    for c in &code {
+        lines.push(line);
+        if *c == '\n' {
+            line += 1;
+        }
+    }
+
+    let mut items = vec![];
+    let mut i = 0;
+    while i < code.len() {
+        let mut pos = i + 1;
+        skip_whitespace(&code, &mut pos);
+        if code[i] != '#' || code.get(pos) != Some(&'[') {
+            i += 1;
+            continue;
+        }
+        let Some(close) = matching(&code, pos, '[', ']') else {
+            break;
+        };
+        let attr: String = code[pos + 1..close]
+            .iter()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        if is_match(&attr)
+            && let Some((end, item)) = item_end(&code, close + 1)
+        {
+            items.push(AttributedItem {
+                item,
+                line_start: lines[i],
+                line_end: lines[end],
+            });
+            i = end + 1;
+        } else {
+            i = close + 1;
+        }
+    }
+    items
+}
+
+/// Index of the `}` or `;` that ends the item starting at `pos`, and what the item is, if it's
+/// one of the [`TEST_ITEMS`]
+fn item_end(code: &[char], mut pos: usize) -> Option<(usize, String)> {
+    // Other attributes and qualifiers can come between the attribute and the keyword
+    let keyword = loop {
+        skip_whitespace(code, &mut pos);
+        if code.get(pos) == Some(&'#') {
+            pos += 1;
+            skip_whitespace(code, &mut pos);
+            if code.get(pos) != Some(&'[') {
+                return None;
+            }
+            pos = matching(code, pos, '[', ']')? + 1;
+            continue;
+        }
+        let word = ident(code, &mut pos);
+        match word.as_str() {
+            "pub" => {
+                skip_whitespace(code, &mut pos);
+                if code.get(pos) == Some(&'(') {
+                    pos = matching(code, pos, '(', ')')? + 1;
+                }
+            }
+            "unsafe" | "async" | "const" => {}
+            _ if TEST_ITEMS.contains(&word.as_str()) => break word,
+            _ => return None,
+        }
+    };
+
+    let header_start = pos;
+    let mut depth = 0;
+    while pos < code.len() {
+        let end = match code[pos] {
+            '(' | '[' => {
+                depth += 1;
+                None
+            }
+            ')' | ']' => {
+                depth -= 1;
+                None
+            }
+            ';' if depth == 0 => Some(pos),
+            '{' if depth == 0 => Some(matching(code, pos, '{', '}')?),
+            _ => None,
+        };
+        if let Some(end) = end {
+            return Some((end, item_name(&keyword, &code[header_start..pos])));
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// Like `fn helper` or `impl Display for Id`, from the keyword and what follows it up to the body
+fn item_name(keyword: &str, header: &[char]) -> String {
+    let header: String = header.iter().collect();
+    let header = header.split_whitespace().collect::<Vec<_>>().join(" ");
+    if keyword == "impl" {
+        let header = header.split(" where ").next().unwrap_or_default();
+        format!(
+            "impl{}{}",
+            if header.starts_with('<') { "" } else { " " },
+            header
+        )
+    } else {
+        let mut pos = 0;
+        let name: Vec<char> = header.chars().collect();
+        format!("{} {}", keyword, ident(&name, &mut pos))
+    }
+}
+
+/// Index of the bracket closing the one at `open_pos`
+fn matching(code: &[char], open_pos: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in code.iter().enumerate().skip(open_pos) {
+        if *c == open {
+            depth += 1;
+        } else if *c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+fn skip_whitespace(code: &[char], pos: &mut usize) {
+    while code.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+}
+
+fn ident(code: &[char], pos: &mut usize) -> String {
+    let mut word = String::new();
+    while let Some(c) = code.get(*pos)
+        && (c.is_alphanumeric() || *c == '_')
+    {
+        word.push(*c);
+        *pos += 1;
+    }
+    word
+}
+
+/// The source with comments and the contents of string and char literals replaced by spaces,
+/// so brackets and attributes inside them don't count. Line breaks are kept, so line numbers
+/// still line up.
+fn blank_literals(source: &str) -> String {
+    lex(source).0
+}
+
+/// The opposite of [`blank_literals`]: just the comments, with everything else replaced by
+/// spaces
+fn comments(source: &str) -> String {
+    let (_, spans) = lex(source);
+    let mut in_comment = vec![false; source.chars().count()];
+    for span in spans {
+        in_comment[span].fill(true);
+    }
+    source
+        .chars()
+        .zip(in_comment)
+        .map(|(c, in_comment)| if in_comment || c == '\n' { c } else { ' ' })
+        .collect()
+}
+
+/// The source with its literals and comments blanked, and where the comments were, by char
+fn lex(source: &str) -> (String, Vec<Range<usize>>) {
+    let chars: Vec<char> = source.chars().collect();
+    let mut out = String::with_capacity(source.len());
+    let mut comments = vec![];
+    let blank = |c: char| if c == '\n' { '\n' } else { ' ' };
+    let is_ident = |c: Option<&char>| c.is_some_and(|c| c.is_alphanumeric() || *c == '_');
+
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c == '/' && next == Some('/') {
+            let start = i;
+            while i < chars.len() && chars[i] != '\n' {
+                out.push(' ');
+                i += 1;
+            }
+            comments.push(start..i);
+        } else if c == '/' && next == Some('*') {
+            let start = i;
+            let mut depth = 0;
+            while i < chars.len() {
+                if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                    depth += 1;
+                    out.push_str("  ");
+                    i += 2;
+                } else if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+                    depth -= 1;
+                    out.push_str("  ");
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    out.push(blank(chars[i]));
+                    i += 1;
+                }
+            }
+            comments.push(start..i);
+        } else if c == 'r'
+            && matches!(next, Some('"' | '#'))
+            && !is_ident(
+                i.checked_sub(1)
+                    .and_then(|p| chars.get(p))
+                    .filter(|p| **p != 'b'),
+            )
+        {
+            // Raw string: r"...", r#"..."#, and so on
+            let mut hashes = 0;
+            let mut j = i + 1;
+            while chars.get(j) == Some(&'#') {
+                hashes += 1;
+                j += 1;
+            }
+            if chars.get(j) != Some(&'"') {
+                out.push(c);
+                i += 1;
+                continue;
+            }
+            out.extend(std::iter::repeat_n(' ', j - i));
+            out.push('"');
+            i = j + 1;
+            while i < chars.len() {
+                if chars[i] == '"' && (1..=hashes).all(|h| chars.get(i + h) == Some(&'#')) {
+                    out.push('"');
+                    out.extend(std::iter::repeat_n(' ', hashes));
+                    i += 1 + hashes;
+                    break;
+                }
+                out.push(blank(chars[i]));
+                i += 1;
+            }
+        } else if c == '"' {
+            out.push('"');
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                if chars[i] == '\\' {
+                    out.push(' ');
+                    i += 1;
+                }
+                if let Some(&c) = chars.get(i) {
+                    out.push(blank(c));
+                }
+                i += 1;
+            }
+            out.push('"');
+            i += 1;
+        } else if c == '\'' && (next == Some('\\') || chars.get(i + 2) == Some(&'\'')) {
+            // A char literal rather than a lifetime
+            out.push(' ');
+            i += 1;
+            while i < chars.len() && chars[i] != '\'' {
+                if chars[i] == '\\' {
+                    out.push(' ');
+                    i += 1;
+                }
+                out.push(' ');
+                i += 1;
+            }
+            out.push(' ');
+            i += 1;
+        } else {
+            out.push(c);
+            i += 1;
+        }
+    }
+    (out, comments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[u64]) -> BTreeSet<u64> {
+        lines.iter().copied().collect()
+    }
+
+    #[test]
+    fn markers_in_comments() {
+        let source = "\
+fn a() {}
+// cov:ignore-start
+fn b() {}
+// cov:ignore-end
+fn c() {} // cov:ignore-line
+fn d() {}
+";
+        assert_eq!(marked_lines(source), lines(&[2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn markers_in_strings_dont_count() {
+        let source = "\
+let fixture = \"// cov:ignore-start\";
+let raw = r#\"// cov:ignore-line \"quoted\"\"#;
+fn a() {}
+";
+        assert_eq!(marked_lines(source), lines(&[]));
+    }
+
+    #[test]
+    fn unterminated_ignore_start_runs_to_the_end() {
+        let source = "fn a() {}\n// cov:ignore-start\nfn b() {}\nfn c() {}\n";
+        assert_eq!(marked_lines(source), lines(&[2, 3, 4]));
+    }
+
+    #[test]
+    fn raw_strings_are_blanked() {
+        let source = "let s = r##\"a \"# } b\"##; let t = 1;";
+        let blanked = blank_literals(source);
+        assert_eq!(blanked.len(), source.len());
+        assert!(!blanked.contains('}'));
+        assert!(blanked.ends_with("\"  ; let t = 1;"));
+        // Not a raw string, just an identifier ending in r
+        assert_eq!(blank_literals("for\"x\""), "for\" \"");
+    }
+
+    #[test]
+    fn nested_block_comments_are_blanked() {
+        let source = "/* outer /* inner */ still } comment */ fn a() {}";
+        assert_eq!(
+            blank_literals(source),
+            format!("{}fn a() {{}}", " ".repeat(40))
+        );
+        assert_eq!(comments(source).trim_end(), &source[..39]);
+    }
+
+    #[test]
+    fn test_modules() {
+        let source = "\
+fn a() {}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn b() {
+        let s = \"}\";
+    }
+}
+fn c() {}
+";
+        assert_eq!(test_lines(source), lines(&[3, 4, 5, 6, 7, 8, 9]));
+        let items = attributed_items(source, |attr| attr == "cfg(test)");
+        assert_eq!(items[0].item, "mod tests");
+    }
+}
@@ -0,0 +1,620 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, PathEquivalence};
+
+/// The parts of `llvm-cov export` JSON this tool uses.
+#[derive(Debug, Deserialize)]
+pub struct Export {
+    pub data: Vec<ExportData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportData {
+    pub files: Vec<ExportFile>,
+    /// Empty when exported with `-summary-only`
+    #[serde(default)]
+    pub functions: Vec<ExportFunction>,
+    pub totals: Summary,
+}
+
+/// One instantiation of a function. Generic functions have one of these per set of type
+/// arguments.
+#[derive(Debug, Deserialize)]
+pub struct ExportFunction {
+    /// Mangled
+    pub name: String,
+    pub count: u64,
+    pub regions: Vec<Region>,
+    /// Files the regions point into by `file_id`, starting with the function's own file
+    pub filenames: Vec<String>,
+}
+
+/// A code region with its execution count. Exported as an array like [`Segment`].
+#[derive(Debug, Deserialize)]
+pub struct Region {
+    pub line_start: u64,
+    pub col_start: u64,
+    pub line_end: u64,
+    pub col_end: u64,
+    pub count: u64,
+    pub file_id: u64,
+    pub expanded_file_id: u64,
+    pub kind: u64,
+}
+
+/// A function that never ran
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UncoveredFunction {
+    /// Demangled, without the hash
+    pub name: String,
+    pub line: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportFile {
+    pub filename: String,
+    /// Empty when exported with `-summary-only`
+    #[serde(default, skip_serializing)]
+    pub segments: Vec<Segment>,
+    /// Empty when exported with `-summary-only`
+    #[serde(default, skip_serializing)]
+    pub branches: Vec<Branch>,
+    pub summary: Summary,
+    /// Lines left out of the line and branch counts, see [`crate::exclude`]
+    #[serde(skip)]
+    pub excluded_lines: BTreeSet<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Summary {
+    pub lines: Metric,
+    pub functions: Metric,
+    pub branches: Metric,
+    /// Code regions, which split lines with several expressions into the parts that can run
+    /// separately
+    pub regions: Metric,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Metric {
+    pub count: u64,
+    pub covered: u64,
+    pub percent: f64,
+}
+
+/// A point where a coverage region starts or ends. llvm-cov exports these as arrays, which
+/// serde maps onto the fields in order.
+#[derive(Debug, Deserialize)]
+pub struct Segment {
+    pub line: u64,
+    pub col: u64,
+    pub count: u64,
+    pub has_count: bool,
+    pub is_region_entry: bool,
+    pub is_gap_region: bool,
+}
+
+/// A branch region with how often each side was taken. Exported as an array like [`Segment`].
+#[derive(Debug, Deserialize)]
+pub struct Branch {
+    pub line_start: u64,
+    pub col_start: u64,
+    pub line_end: u64,
+    pub col_end: u64,
+    pub true_count: u64,
+    pub false_count: u64,
+    pub file_id: u64,
+    pub expanded_file_id: u64,
+    pub kind: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCount {
+    pub line: u64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    pub covered: u64,
+    pub total: u64,
+}
+
+impl Metric {
+    pub fn new(count: u64, covered: u64) -> Metric {
+        let mut metric = Metric::default();
+        metric.add(&Metric {
+            count,
+            covered,
+            percent: 0.,
+        });
+        metric
+    }
+
+    /// Covered fraction in the same 0-1 scale as the thresholds. Like the "-" cell in the text
+    /// table, a metric with nothing to cover counts as fully covered.
+    pub fn fraction(&self) -> f32 {
+        if self.count == 0 {
+            return 1.0;
+        }
+        self.covered as f32 / self.count as f32
+    }
+
+    /// Accumulates another metric's counts into this one
+    pub fn add(&mut self, other: &Metric) {
+        self.count += other.count;
+        self.covered += other.covered;
+        self.percent = self.fraction() as f64 * 100.;
+    }
+}
+
+impl ExportData {
+    /// Combines the exports of several objects into close to what one export of all of them
+    /// would be. A function compiled into more than one of them reads the same counters in each,
+    /// so it's only counted once, and a line or branch counts as run if it ran in any of them.
+    /// The files' segments are rebuilt from the combined line counts, one region per line.
+    pub fn merge(exports: Vec<ExportData>) -> ExportData {
+        let mut functions: BTreeMap<String, ExportFunction> = BTreeMap::new();
+        let mut lines: BTreeMap<String, BTreeMap<u64, u64>> = BTreeMap::new();
+        let mut branches: BTreeMap<String, BTreeMap<[u64; 4], Branch>> = BTreeMap::new();
+        for data in exports {
+            for file in data.files {
+                let counts = lines.entry(file.filename.clone()).or_default();
+                for line in file.line_counts() {
+                    let count = counts.entry(line.line).or_default();
+                    *count = (*count).max(line.count);
+                }
+                let file_branches = branches.entry(file.filename).or_default();
+                for branch in file.branches {
+                    let key = [
+                        branch.line_start,
+                        branch.col_start,
+                        branch.line_end,
+                        branch.col_end,
+                    ];
+                    match file_branches.get_mut(&key) {
+                        Some(existing) => {
+                            existing.true_count = existing.true_count.max(branch.true_count);
+                            existing.false_count = existing.false_count.max(branch.false_count);
+                        }
+                        None => {
+                            file_branches.insert(key, branch);
+                        }
+                    }
+                }
+            }
+            for function in data.functions {
+                match functions.get_mut(&function.name) {
+                    Some(existing) => {
+                        existing.count = existing.count.max(function.count);
+                        // Same function, so the same regions in the same order
+                        for (region, other) in existing.regions.iter_mut().zip(&function.regions) {
+                            region.count = region.count.max(other.count);
+                        }
+                    }
+                    None => {
+                        functions.insert(function.name.clone(), function);
+                    }
+                }
+            }
+        }
+        let functions: Vec<ExportFunction> = functions.into_values().collect();
+        let (function_metrics, region_metrics) = function_metrics(&functions);
+
+        let mut totals = Summary::default();
+        let files = lines
+            .into_iter()
+            .map(|(filename, counts)| {
+                let segments = counts
+                    .into_iter()
+                    .flat_map(|(line, count)| {
+                        let start = Segment {
+                            line,
+                            col: 1,
+                            count,
+                            has_count: true,
+                            is_region_entry: true,
+                            is_gap_region: false,
+                        };
+                        // Ends the region, so it doesn't run on into the next line
+                        let end = Segment {
+                            col: u64::MAX,
+                            count: 0,
+                            has_count: false,
+                            is_region_entry: false,
+                            ..start
+                        };
+                        [start, end]
+                    })
+                    .collect();
+                let mut file = ExportFile {
+                    branches: branches
+                        .remove(&filename)
+                        .unwrap_or_default()
+                        .into_values()
+                        .collect(),
+                    segments,
+                    summary: Summary::default(),
+                    excluded_lines: BTreeSet::new(),
+                    filename,
+                };
+                let lines = file.line_counts();
+                let branches = file.branch_counts();
+                file.summary = Summary {
+                    lines: Metric::new(
+                        lines.len() as u64,
+                        lines.iter().filter(|l| l.count > 0).count() as u64,
+                    ),
+                    branches: Metric::new(
+                        branches.values().map(|b| b.total).sum(),
+                        branches.values().map(|b| b.covered).sum(),
+                    ),
+                    functions: function_metrics
+                        .get(&file.filename)
+                        .cloned()
+                        .unwrap_or_default(),
+                    regions: region_metrics
+                        .get(&file.filename)
+                        .cloned()
+                        .unwrap_or_default(),
+                };
+                totals.lines.add(&file.summary.lines);
+                totals.branches.add(&file.summary.branches);
+                totals.functions.add(&file.summary.functions);
+                totals.regions.add(&file.summary.regions);
+                file
+            })
+            .collect();
+        ExportData {
+            files,
+            functions,
+            totals,
+        }
+    }
+
+    /// Swaps the start of every filename for the first equivalence that applies to it
+    pub fn remap_paths(&mut self, equivalences: &[PathEquivalence]) {
+        let remap = |filename: &mut String| {
+            if let Some(remapped) = config::remap_path(equivalences, Path::new(filename)) {
+                *filename = remapped.to_string_lossy().into_owned();
+            }
+        };
+        for file in &mut self.files {
+            remap(&mut file.filename);
+        }
+        for function in &mut self.functions {
+            function.filenames.iter_mut().for_each(remap);
+        }
+    }
+
+    /// Functions that never ran in any instantiation, by filename, in line order. Functions
+    /// starting on an excluded line are left out.
+    pub fn uncovered_functions(&self) -> BTreeMap<String, Vec<UncoveredFunction>> {
+        // Every instantiation of a generic function starts at the same place
+        let mut functions: BTreeMap<(&str, u64), (&ExportFunction, bool)> = BTreeMap::new();
+        for function in &self.functions {
+            let (Some(filename), Some(start)) = (
+                function.filenames.first(),
+                function.regions.iter().find(|r| r.file_id == 0),
+            ) else {
+                continue;
+            };
+            let entry = functions
+                .entry((filename, start.line_start))
+                .or_insert((function, false));
+            entry.1 |= function.count > 0;
+        }
+
+        let mut uncovered: BTreeMap<String, Vec<UncoveredFunction>> = BTreeMap::new();
+        for ((filename, line), (function, covered)) in functions {
+            let excluded = self
+                .files
+                .iter()
+                .find(|f| f.filename == filename)
+                .is_none_or(|f| f.excluded_lines.contains(&line));
+            if covered || excluded {
+                continue;
+            }
+            uncovered
+                .entry(filename.to_string())
+                .or_default()
+                .push(UncoveredFunction {
+                    name: format!("{:#}", rustc_demangle::demangle(&function.name)),
+                    line,
+                });
+        }
+        uncovered
+    }
+}
+
+/// Function and region coverage by filename, the way llvm-cov counts them: each function once
+/// however many instantiations it has, and each code region once, covered if any of them ran
+fn function_metrics(
+    functions: &[ExportFunction],
+) -> (BTreeMap<String, Metric>, BTreeMap<String, Metric>) {
+    /// Plain code, as opposed to expansions, skipped code, gaps, and branches
+    const CODE_REGION: u64 = 0;
+
+    let mut starts: BTreeMap<(&str, u64), bool> = BTreeMap::new();
+    let mut regions: BTreeMap<(&str, [u64; 4]), bool> = BTreeMap::new();
+    for function in functions {
+        if let (Some(filename), Some(start)) = (
+            function.filenames.first(),
+            function.regions.iter().find(|r| r.file_id == 0),
+        ) {
+            *starts.entry((filename, start.line_start)).or_default() |= function.count > 0;
+        }
+        for region in function.regions.iter().filter(|r| r.kind == CODE_REGION) {
+            let Some(filename) = function.filenames.get(region.file_id as usize) else {
+                continue;
+            };
+            let key = [
+                region.line_start,
+                region.col_start,
+                region.line_end,
+                region.col_end,
+            ];
+            *regions.entry((filename, key)).or_default() |= region.count > 0;
+        }
+    }
+
+    let tally = |covered: &mut BTreeMap<String, Metric>, filename: &str, ran: bool| {
+        covered
+            .entry(filename.to_string())
+            .or_default()
+            .add(&Metric::new(1, ran as u64));
+    };
+    let mut function_metrics = BTreeMap::new();
+    for ((filename, _), ran) in starts {
+        tally(&mut function_metrics, filename, ran);
+    }
+    let mut region_metrics = BTreeMap::new();
+    for ((filename, _), ran) in regions {
+        tally(&mut region_metrics, filename, ran);
+    }
+    (function_metrics, region_metrics)
+}
+
+impl Segment {
+    fn is_start_of_region(&self) -> bool {
+        !self.is_gap_region && self.has_count && self.is_region_entry
+    }
+}
+
+impl ExportFile {
+    /// Execution count of every instrumented line, in line order. This mirrors how llvm-cov
+    /// itself turns region segments into line counts (`LineCoverageStats`), so the numbers
+    /// line up with the `lines` summary.
+    pub fn line_counts(&self) -> Vec<LineCount> {
+        let mut counts = vec![];
+        let Some(last_line) = self.segments.iter().map(|s| s.line).max() else {
+            return counts;
+        };
+
+        let mut wrapped: Option<&Segment> = None;
+        let mut next = 0;
+        for line in self.segments[0].line..=last_line {
+            let start = next;
+            while next < self.segments.len() && self.segments[next].line == line {
+                next += 1;
+            }
+            let line_segments = &self.segments[start..next];
+
+            let region_starts = line_segments
+                .iter()
+                .filter(|s| s.is_start_of_region())
+                .count();
+            let starts_skipped = line_segments
+                .first()
+                .is_some_and(|s| !s.has_count && s.is_region_entry);
+            let mapped =
+                !starts_skipped && (wrapped.is_some_and(|s| s.has_count) || region_starts > 0);
+
+            if mapped && !self.excluded_lines.contains(&line) {
+                let mut count = wrapped.map(|s| s.count).unwrap_or(0);
+                for segment in line_segments.iter().filter(|s| s.is_start_of_region()) {
+                    count = count.max(segment.count);
+                }
+                counts.push(LineCount { line, count });
+            }
+
+            if let Some(last) = line_segments.last() {
+                wrapped = Some(last);
+            }
+        }
+        counts
+    }
+
+    /// Instrumented lines that never ran, in line order
+    pub fn uncovered_lines(&self) -> impl Iterator<Item = u64> {
+        self.line_counts()
+            .into_iter()
+            .filter(|l| l.count == 0)
+            .map(|l| l.line)
+    }
+
+    /// Covered and total branch outcomes per line. Every branch has two outcomes, taken and not
+    /// taken.
+    pub fn branch_counts(&self) -> BTreeMap<u64, BranchCount> {
+        let mut counts: BTreeMap<u64, BranchCount> = BTreeMap::new();
+        for branch in self
+            .branches
+            .iter()
+            .filter(|b| !self.excluded_lines.contains(&b.line_start))
+        {
+            let count = counts.entry(branch.line_start).or_default();
+            count.total += 2;
+            count.covered += (branch.true_count > 0) as u64 + (branch.false_count > 0) as u64;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(
+        line: u64,
+        col: u64,
+        count: u64,
+        has_count: bool,
+        is_region_entry: bool,
+        is_gap_region: bool,
+    ) -> Segment {
+        Segment {
+            line,
+            col,
+            count,
+            has_count,
+            is_region_entry,
+            is_gap_region,
+        }
+    }
+
+    fn file(filename: &str, segments: Vec<Segment>, branches: Vec<Branch>) -> ExportFile {
+        ExportFile {
+            filename: filename.to_string(),
+            segments,
+            branches,
+            summary: Summary::default(),
+            excluded_lines: BTreeSet::new(),
+        }
+    }
+
+    fn counts(file: &ExportFile) -> Vec<(u64, u64)> {
+        file.line_counts()
+            .iter()
+            .map(|l| (l.line, l.count))
+            .collect()
+    }
+
+    #[test]
+    fn a_gap_alone_takes_the_count_of_the_region_around_it() {
+        let file = file(
+            "src/lib.rs",
+            vec![
+                segment(1, 1, 5, true, true, false),
+                // Not an entry, so the line keeps line 1's count rather than the gap's
+                segment(2, 1, 0, true, false, true),
+                segment(3, 1, 5, true, true, false),
+                segment(3, 10, 0, false, false, false),
+            ],
+            vec![],
+        );
+        assert_eq!(counts(&file), [(1, 5), (2, 5), (3, 5)]);
+    }
+
+    #[test]
+    fn regions_starting_mid_line_count_towards_it() {
+        let file = file(
+            "src/lib.rs",
+            vec![
+                segment(1, 1, 3, true, true, false),
+                // An uncovered region starting mid-line doesn't hide the count wrapping into it
+                segment(2, 9, 0, true, true, false),
+                segment(2, 20, 0, false, false, false),
+                // Nothing wraps into this one, so only the region starting on it counts
+                segment(4, 5, 7, true, true, false),
+                segment(4, 12, 0, false, false, false),
+            ],
+            vec![],
+        );
+        assert_eq!(counts(&file), [(1, 3), (2, 3), (4, 7)]);
+    }
+
+    #[test]
+    fn a_region_that_never_ran_covers_every_line_it_wraps() {
+        let file = file(
+            "src/lib.rs",
+            vec![
+                segment(1, 1, 0, true, true, false),
+                segment(3, 2, 0, false, false, false),
+                // Line 4 is outside any region
+                segment(5, 1, 1, true, true, false),
+                segment(5, 8, 0, false, false, false),
+            ],
+            vec![],
+        );
+        assert_eq!(counts(&file), [(1, 0), (2, 0), (3, 0), (5, 1)]);
+    }
+
+    #[test]
+    fn merging_counts_shared_files_and_functions_once() {
+        let export = |line_counts: [u64; 2], branch: (u64, u64), function_count: u64| {
+            let segments = line_counts
+                .iter()
+                .zip(1..)
+                .flat_map(|(&count, line)| {
+                    [
+                        segment(line, 1, count, true, true, false),
+                        segment(line, 10, 0, false, false, false),
+                    ]
+                })
+                .collect();
+            let branches = vec![Branch {
+                line_start: 2,
+                col_start: 4,
+                line_end: 2,
+                col_end: 8,
+                true_count: branch.0,
+                false_count: branch.1,
+                file_id: 0,
+                expanded_file_id: 0,
+                kind: 4,
+            }];
+            ExportData {
+                files: vec![file("src/lib.rs", segments, branches)],
+                functions: vec![ExportFunction {
+                    name: "_RNvCs123_3lib1f".to_string(),
+                    count: function_count,
+                    regions: vec![Region {
+                        line_start: 1,
+                        col_start: 1,
+                        line_end: 2,
+                        col_end: 10,
+                        count: function_count,
+                        file_id: 0,
+                        expanded_file_id: 0,
+                        kind: 0,
+                    }],
+                    filenames: vec!["src/lib.rs".to_string()],
+                }],
+                totals: Summary::default(),
+            }
+        };
+
+        let merged = ExportData::merge(vec![export([0, 3], (1, 0), 0), export([2, 0], (0, 2), 4)]);
+        assert_eq!(merged.files.len(), 1);
+        let file = &merged.files[0];
+        assert_eq!(counts(file), [(1, 2), (2, 3)]);
+        assert_eq!(
+            file.branch_counts()[&2],
+            BranchCount {
+                covered: 2,
+                total: 2
+            }
+        );
+
+        assert_eq!(merged.functions.len(), 1);
+        assert_eq!(merged.functions[0].count, 4);
+        let summary = &file.summary;
+        let metrics = [
+            &summary.lines,
+            &summary.branches,
+            &summary.functions,
+            &summary.regions,
+        ];
+        let metrics: Vec<_> = metrics.iter().map(|m| (m.covered, m.count)).collect();
+        assert_eq!(metrics, [(2, 2), (2, 2), (1, 1), (1, 1)]);
+        assert_eq!(
+            (merged.totals.lines.covered, merged.totals.lines.count),
+            (2, 2)
+        );
+    }
+}
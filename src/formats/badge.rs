@@ -0,0 +1,79 @@
+//! Line coverage badges, either as a shields.io endpoint
+//! (<https://shields.io/badges/endpoint-badge>) or a standalone SVG.
+
+use serde_json::json;
+
+const LABEL: &str = "coverage";
+
+/// Badge color for a 0-1 coverage fraction, on the same scale most coverage services use
+fn color(coverage: f32) -> &'static str {
+    match coverage {
+        c if c >= 0.9 => "brightgreen",
+        c if c >= 0.8 => "green",
+        c if c >= 0.7 => "yellowgreen",
+        c if c >= 0.6 => "yellow",
+        c if c >= 0.5 => "orange",
+        _ => "red",
+    }
+}
+
+fn hex(color: &str) -> &'static str {
+    match color {
+        "brightgreen" => "#4c1",
+        "green" => "#97ca00",
+        "yellowgreen" => "#a4a61d",
+        "yellow" => "#dfb317",
+        "orange" => "#fe7d37",
+        _ => "#e05d44",
+    }
+}
+
+fn message(coverage: f32) -> String {
+    format!("{:.0}%", (coverage * 100.).floor())
+}
+
+pub fn endpoint(coverage: f32) -> String {
+    let badge = json!({
+        "schemaVersion": 1,
+        "label": LABEL,
+        "message": message(coverage),
+        "color": color(coverage),
+    });
+    serde_json::to_string_pretty(&badge).unwrap()
+}
+
+pub fn svg(coverage: f32) -> String {
+    let message = message(coverage);
+    let fill = hex(color(coverage));
+    // Verdana 11px averages out to about 7px a character, plus padding on both sides
+    let label_width = LABEL.len() * 7 + 10;
+    let message_width = message.len() * 7 + 10;
+    let width = label_width + message_width;
+    let label_x = label_width as f32 / 2.;
+    let message_x = label_width as f32 + message_width as f32 / 2.;
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{LABEL}: {message}">
+  <title>{LABEL}: {message}</title>
+  <linearGradient id="s" x2="0" y2="100%">
+    <stop offset="0" stop-color="#bbb" stop-opacity=".1"/>
+    <stop offset="1" stop-opacity=".1"/>
+  </linearGradient>
+  <clipPath id="r">
+    <rect width="{width}" height="20" rx="3" fill="#fff"/>
+  </clipPath>
+  <g clip-path="url(#r)">
+    <rect width="{label_width}" height="20" fill="#555"/>
+    <rect x="{label_width}" width="{message_width}" height="20" fill="{fill}"/>
+    <rect width="{width}" height="20" fill="url(#s)"/>
+  </g>
+  <g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+    <text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{LABEL}</text>
+    <text x="{label_x}" y="14">{LABEL}</text>
+    <text x="{message_x}" y="15" fill="#010101" fill-opacity=".3">{message}</text>
+    <text x="{message_x}" y="14">{message}</text>
+  </g>
+</svg>
+"##
+    )
+}
@@ -0,0 +1,177 @@
+//! Cobertura XML, as consumed by the Jenkins coverage plugin and GitLab's
+//! `coverage_report: cobertura` artifacts.
+//!
+//! Cobertura is modeled around Java, so each directory becomes a `package` and each source
+//! file becomes a `class` with no methods.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::export::{ExportData, ExportFile};
+
+use super::{escape_xml, relative_path};
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    lines_covered: u64,
+    lines_valid: u64,
+    branches_covered: u64,
+    branches_valid: u64,
+}
+
+impl Counts {
+    fn of(file: &ExportFile) -> Self {
+        Counts {
+            lines_covered: file.summary.lines.covered,
+            lines_valid: file.summary.lines.count,
+            branches_covered: file.summary.branches.covered,
+            branches_valid: file.summary.branches.count,
+        }
+    }
+
+    fn add(&mut self, other: Counts) {
+        self.lines_covered += other.lines_covered;
+        self.lines_valid += other.lines_valid;
+        self.branches_covered += other.branches_covered;
+        self.branches_valid += other.branches_valid;
+    }
+
+    fn line_rate(&self) -> f64 {
+        rate(self.lines_covered, self.lines_valid)
+    }
+
+    fn branch_rate(&self) -> f64 {
+        rate(self.branches_covered, self.branches_valid)
+    }
+}
+
+fn rate(covered: u64, valid: u64) -> f64 {
+    if valid == 0 {
+        return 1.0;
+    }
+    covered as f64 / valid as f64
+}
+
+pub fn render(data: &ExportData, root: &Path) -> String {
+    let mut packages: BTreeMap<String, Vec<(String, &ExportFile)>> = BTreeMap::new();
+    for file in &data.files {
+        let path = relative_path(&file.filename, root);
+        let package = Path::new(&path)
+            .parent()
+            .map(|p| p.to_string_lossy().replace(['/', '\\'], "."))
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| ".".to_string());
+        packages.entry(package).or_default().push((path, file));
+    }
+
+    let mut totals = Counts::default();
+    for file in &data.files {
+        totals.add(Counts::of(file));
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" ?>\n");
+    xml.push_str(
+        "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+    );
+    writeln!(
+        xml,
+        "<coverage line-rate=\"{:.4}\" branch-rate=\"{:.4}\" lines-covered=\"{}\" lines-valid=\"{}\" branches-covered=\"{}\" branches-valid=\"{}\" complexity=\"0\" version=\"{}\" timestamp=\"{}\">",
+        totals.line_rate(),
+        totals.branch_rate(),
+        totals.lines_covered,
+        totals.lines_valid,
+        totals.branches_covered,
+        totals.branches_valid,
+        env!("CARGO_PKG_VERSION"),
+        timestamp
+    )
+    .unwrap();
+    xml.push_str("  <sources>\n");
+    writeln!(
+        xml,
+        "    <source>{}</source>",
+        escape_xml(&root.to_string_lossy())
+    )
+    .unwrap();
+    xml.push_str("  </sources>\n");
+    xml.push_str("  <packages>\n");
+
+    for (package, files) in &packages {
+        let mut package_counts = Counts::default();
+        for (_, file) in files {
+            package_counts.add(Counts::of(file));
+        }
+        writeln!(
+            xml,
+            "    <package name=\"{}\" line-rate=\"{:.4}\" branch-rate=\"{:.4}\" complexity=\"0\">",
+            escape_xml(package),
+            package_counts.line_rate(),
+            package_counts.branch_rate()
+        )
+        .unwrap();
+        xml.push_str("      <classes>\n");
+        for (path, file) in files {
+            render_class(&mut xml, path, file);
+        }
+        xml.push_str("      </classes>\n");
+        xml.push_str("    </package>\n");
+    }
+
+    xml.push_str("  </packages>\n");
+    xml.push_str("</coverage>\n");
+    xml
+}
+
+fn render_class(xml: &mut String, path: &str, file: &ExportFile) {
+    let counts = Counts::of(file);
+    let name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+    writeln!(
+        xml,
+        "        <class name=\"{}\" filename=\"{}\" line-rate=\"{:.4}\" branch-rate=\"{:.4}\" complexity=\"0\">",
+        escape_xml(&name),
+        escape_xml(path),
+        counts.line_rate(),
+        counts.branch_rate()
+    )
+    .unwrap();
+    xml.push_str("          <methods/>\n");
+    xml.push_str("          <lines>\n");
+
+    let branches = file.branch_counts();
+    for line in file.line_counts() {
+        match branches.get(&line.line) {
+            Some(branch) => writeln!(
+                xml,
+                "            <line number=\"{}\" hits=\"{}\" branch=\"true\" condition-coverage=\"{}% ({}/{})\"/>",
+                line.line,
+                line.count,
+                branch.covered * 100 / branch.total,
+                branch.covered,
+                branch.total
+            )
+            .unwrap(),
+            None => writeln!(
+                xml,
+                "            <line number=\"{}\" hits=\"{}\" branch=\"false\"/>",
+                line.line, line.count
+            )
+            .unwrap(),
+        }
+    }
+
+    xml.push_str("          </lines>\n");
+    xml.push_str("        </class>\n");
+}
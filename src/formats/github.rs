@@ -0,0 +1,185 @@
+//! Markdown for GitHub Actions job summaries.
+
+use std::{fmt::Write, path::Path};
+
+use crate::export::{ExportData, Metric};
+
+use super::{least_covered, line_ranges, relative_path};
+
+/// How many of the least covered files to list
+const WORST_FILES: usize = 5;
+
+pub fn step_summary(
+    data: &ExportData,
+    root: &Path,
+    failures: &[String],
+    soft_failures: &[String],
+) -> String {
+    let mut md = String::new();
+    md.push_str("## Coverage\n\n");
+
+    md.push_str("| | Covered | Total | Coverage |\n");
+    md.push_str("|---|---:|---:|---:|\n");
+    for (name, metric) in [
+        ("Lines", &data.totals.lines),
+        ("Functions", &data.totals.functions),
+        ("Branches", &data.totals.branches),
+    ] {
+        writeln!(
+            md,
+            "| {} | {} | {} | {} |",
+            name,
+            metric.covered,
+            metric.count,
+            percent(metric)
+        )
+        .unwrap();
+    }
+    md.push('\n');
+
+    if failures.is_empty() {
+        md.push_str(":white_check_mark: All coverage requirements met\n\n");
+    } else {
+        md.push_str(":x: Coverage requirements not met\n\n");
+        for failure in failures {
+            writeln!(md, "- {}", failure).unwrap();
+        }
+        md.push('\n');
+    }
+    if !soft_failures.is_empty() {
+        md.push_str(":warning: Under the warning levels, which aren't enforced yet\n\n");
+        for failure in soft_failures {
+            writeln!(md, "- {}", failure).unwrap();
+        }
+        md.push('\n');
+    }
+
+    let files = least_covered(data);
+    if !files.is_empty() {
+        md.push_str("### Least covered files\n\n");
+        md.push_str("| File | Lines | Branches |\n");
+        md.push_str("|---|---:|---:|\n");
+        for file in files.iter().take(WORST_FILES) {
+            writeln!(
+                md,
+                "| `{}` | {} | {} |",
+                relative_path(&file.filename, root),
+                percent(&file.summary.lines),
+                percent(&file.summary.branches)
+            )
+            .unwrap();
+        }
+        md.push('\n');
+    }
+
+    md
+}
+
+/// Formats like the text table, where a metric with nothing to cover is a dash
+fn percent(metric: &Metric) -> String {
+    if metric.count == 0 {
+        return "-".to_string();
+    }
+    format!("{:.2}%", metric.fraction() * 100.)
+}
+
+/// `::warning` workflow commands for every run of uncovered lines, so they show up inline on the
+/// PR diff. Only files in `changed` are annotated when it's given.
+pub fn annotations(data: &ExportData, root: &Path, changed: Option<&[String]>) -> String {
+    let mut out = String::new();
+    for file in &data.files {
+        let path = relative_path(&file.filename, root);
+        if changed.is_some_and(|changed| !changed.contains(&path)) {
+            continue;
+        }
+
+        let property = escape_property(&path);
+        for (start, end) in line_ranges(file.uncovered_lines()) {
+            if start == end {
+                writeln!(
+                    out,
+                    "::warning file={},line={}::{}",
+                    property,
+                    start,
+                    escape_data("Uncovered line")
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    out,
+                    "::warning file={},line={},endLine={}::{}",
+                    property,
+                    start,
+                    end,
+                    escape_data("Uncovered lines")
+                )
+                .unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// A workflow command's message, where only what would end the command has to be escaped
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// A workflow command's property value, like the `file=`, which also can't have the `,` that
+/// separates properties or the `:` that ends them
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::export::{ExportFile, Segment, Summary};
+
+    #[test]
+    fn annotations_escape_what_would_break_the_command() {
+        let uncovered = |line| Segment {
+            line,
+            col: 1,
+            count: 0,
+            has_count: true,
+            is_region_entry: true,
+            is_gap_region: false,
+        };
+        let end = Segment {
+            line: 3,
+            col: 10,
+            count: 0,
+            has_count: false,
+            is_region_entry: false,
+            is_gap_region: false,
+        };
+        let data = ExportData {
+            files: vec![ExportFile {
+                filename: "/repo/src/a,b:100%\n.rs".to_string(),
+                segments: vec![uncovered(1), end, uncovered(5)],
+                branches: vec![],
+                summary: Summary::default(),
+                excluded_lines: BTreeSet::new(),
+            }],
+            functions: vec![],
+            totals: Summary::default(),
+        };
+        assert_eq!(
+            annotations(&data, Path::new("/repo"), None),
+            "\
+::warning file=src/a%2Cb%3A100%25%0A.rs,line=1,endLine=3::Uncovered lines
+::warning file=src/a%2Cb%3A100%25%0A.rs,line=5::Uncovered line
+"
+        );
+    }
+
+    #[test]
+    fn data_keeps_commas_and_colons() {
+        assert_eq!(escape_data("50%: a, b\r\n"), "50%25: a, b%0D%0A");
+    }
+}
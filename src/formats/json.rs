@@ -0,0 +1,63 @@
+//! The `--format json` summary.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    Report,
+    exclude::AttributedItem,
+    export::{Summary, UncoveredFunction},
+    patch::PatchCoverage,
+    progress::Timing,
+    report::FileCoverage,
+    thresholds::PathCoverage,
+};
+
+/// Goes up when a field is removed, renamed, or changes meaning, but not for new fields
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// JSON Schema of what [`render`] returns
+pub const SCHEMA: &str = include_str!("json_schema.json");
+
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    schema_version: u32,
+    status: &'a str,
+    failures: &'a Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    test_failures: &'a Vec<String>,
+    warnings: &'a Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    soft_failures: &'a Vec<String>,
+    totals: &'a Summary,
+    files: &'a Vec<FileCoverage>,
+    coverage_off: &'a BTreeMap<String, Vec<AttributedItem>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch: &'a Option<PatchCoverage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    path_thresholds: &'a Vec<PathCoverage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uncovered_functions: &'a Option<BTreeMap<String, Vec<UncoveredFunction>>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    timings: &'a Vec<Timing>,
+}
+
+pub fn render(report: &Report) -> String {
+    let json_report = JsonReport {
+        schema_version: SCHEMA_VERSION,
+        status: if report.passed() { "pass" } else { "fail" },
+        failures: &report.failures,
+        test_failures: &report.test_failures,
+        warnings: &report.warnings,
+        soft_failures: &report.soft_failures,
+        totals: &report.totals,
+        files: &report.files,
+        coverage_off: &report.coverage_off,
+        patch: &report.patch,
+        path_thresholds: &report.paths,
+        uncovered_functions: &report.uncovered_functions,
+        timings: &report.timings,
+    };
+    serde_json::to_string_pretty(&json_report).expect("unable to serialize report")
+}
@@ -0,0 +1,121 @@
+//! Renderers that turn exported coverage data into formats other tools understand.
+
+pub mod badge;
+pub mod cobertura;
+pub mod github;
+pub mod json;
+pub mod sonarqube;
+
+use std::{collections::BTreeMap, path::Path};
+
+use crate::export::{ExportData, ExportFile, Metric};
+
+/// Path of a covered file relative to the project root, falling back to the path llvm-cov
+/// reported when the file lives outside the project. Always uses `/` separators, which is what
+/// every consumer of these reports expects, Windows runners included.
+pub fn relative_path(filename: &str, root: &Path) -> String {
+    match Path::new(filename).strip_prefix(root) {
+        Ok(relative) => relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => filename.to_string(),
+    }
+}
+
+/// Runs of consecutive line numbers as `(start, end)` pairs, from lines in ascending order
+pub fn line_ranges(lines: impl IntoIterator<Item = u64>) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => ranges.push((line, line)),
+        }
+    }
+    ranges
+}
+
+/// Like `3, 7-9, 12`
+pub fn format_line_ranges(ranges: &[(u64, u64)]) -> String {
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Files with uncovered lines, least covered first
+pub fn least_covered(data: &ExportData) -> Vec<&ExportFile> {
+    let mut files: Vec<_> = data
+        .files
+        .iter()
+        .filter(|f| f.summary.lines.covered < f.summary.lines.count)
+        .collect();
+    files.sort_by(|a, b| {
+        a.summary
+            .lines
+            .fraction()
+            .total_cmp(&b.summary.lines.fraction())
+    });
+    files
+}
+
+/// A directory or file in [`tree`]
+#[derive(Debug)]
+pub struct TreeNode {
+    /// Relative to the project root, with a trailing `/` for directories
+    pub path: String,
+    /// 1 for what's directly in the root, like `src/`
+    pub depth: usize,
+    /// Over every file in it, for a directory
+    pub lines: Metric,
+}
+
+/// The files' line coverage totalled up for every directory they're in, as a tree in path
+/// order where each directory comes before what's in it. Directories and files deeper than
+/// `depth` are left out, though they still count towards the ones above them.
+pub fn tree(data: &ExportData, root: &Path, depth: usize) -> Vec<TreeNode> {
+    // Sorting by path keeps everything in a directory right after it
+    let mut nodes: BTreeMap<String, (usize, Metric)> = BTreeMap::new();
+    for file in &data.files {
+        let path = relative_path(&file.filename, root);
+        let parts: Vec<_> = path.split('/').collect();
+        for level in 1..=parts.len().min(depth) {
+            let mut key = parts[..level].join("/");
+            if level < parts.len() {
+                key.push('/');
+            }
+            nodes
+                .entry(key)
+                .or_insert((level, Metric::default()))
+                .1
+                .add(&file.summary.lines);
+        }
+    }
+    nodes
+        .into_iter()
+        .map(|(path, (depth, lines))| TreeNode { path, depth, lines })
+        .collect()
+}
+
+pub fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
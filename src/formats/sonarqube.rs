@@ -0,0 +1,45 @@
+//! SonarQube's generic test coverage format
+//! (<https://docs.sonarsource.com/sonarqube/latest/analyzing-source-code/test-coverage/generic-test-data/>).
+
+use std::{fmt::Write, path::Path};
+
+use crate::export::ExportData;
+
+use super::{escape_xml, relative_path};
+
+pub fn render(data: &ExportData, root: &Path) -> String {
+    let mut xml = String::new();
+    xml.push_str("<coverage version=\"1\">\n");
+    for file in &data.files {
+        writeln!(
+            xml,
+            "  <file path=\"{}\">",
+            escape_xml(&relative_path(&file.filename, root))
+        )
+        .unwrap();
+
+        let branches = file.branch_counts();
+        for line in file.line_counts() {
+            write!(
+                xml,
+                "    <lineToCover lineNumber=\"{}\" covered=\"{}\"",
+                line.line,
+                line.count > 0
+            )
+            .unwrap();
+            if let Some(branch) = branches.get(&line.line) {
+                write!(
+                    xml,
+                    " branchesToCover=\"{}\" coveredBranches=\"{}\"",
+                    branch.total, branch.covered
+                )
+                .unwrap();
+            }
+            xml.push_str("/>\n");
+        }
+
+        xml.push_str("  </file>\n");
+    }
+    xml.push_str("</coverage>\n");
+    xml
+}
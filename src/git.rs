@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{CovError, Result, decode, exec};
+
+/// Root of the git checkout containing `project_dir`
+pub fn toplevel(project_dir: &Path) -> Result<PathBuf> {
+    Ok(PathBuf::from(rev_parse(project_dir, &["--show-toplevel"])?))
+}
+
+/// Files changed between `base` and the working tree, relative to [`toplevel`]
+pub fn changed_files(project_dir: &Path, base: &str) -> Result<Vec<String>> {
+    let output = exec(
+        Command::new("git")
+            .arg("diff")
+            .arg("--name-only")
+            .arg(format!("{}...", base))
+            .current_dir(project_dir),
+    )?;
+
+    Ok(decode("git diff", &output.stdout)
+        .lines()
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Files that differ between `commit` and the working tree, untracked ones that aren't ignored
+/// included, relative to [`toplevel`]
+pub fn changed_since(project_dir: &Path, commit: &str) -> Result<BTreeSet<String>> {
+    let tracked = exec(
+        Command::new("git")
+            .args(["diff", "--name-only", "--no-renames", "-z"])
+            .arg(commit)
+            .current_dir(project_dir),
+    )?;
+    let untracked = exec(
+        Command::new("git")
+            .args([
+                "ls-files",
+                "--others",
+                "--exclude-standard",
+                "--full-name",
+                "-z",
+                ":/",
+            ])
+            .current_dir(project_dir),
+    )?;
+
+    Ok([tracked.stdout, untracked.stdout]
+        .iter()
+        .flat_map(|out| out.split(|b| *b == 0))
+        .filter(|p| !p.is_empty())
+        .map(|p| decode("git", p).into_owned())
+        .collect())
+}
+
+/// Lines added or changed since the merge base of `base` and the working tree, by file
+/// relative to [`toplevel`]. Deleted files and deleted lines don't show up.
+pub fn changed_lines(project_dir: &Path, base: &str) -> Result<BTreeMap<String, BTreeSet<u64>>> {
+    let output = exec(
+        Command::new("git")
+            .args([
+                "-c",
+                "core.quotePath=false",
+                "diff",
+                "--no-color",
+                "--no-ext-diff",
+                // Whatever diff.noprefix and diff.mnemonicPrefix say, so the paths can be found
+                "--src-prefix=a/",
+                "--dst-prefix=b/",
+            ])
+            .arg("--unified=0")
+            .arg("--merge-base")
+            .arg(base)
+            .current_dir(project_dir),
+    )?;
+    parse_diff(&decode("git diff", &output.stdout))
+}
+
+/// The added lines of a `git diff --unified=0`, by file. A file header that doesn't parse is an
+/// error, rather than a file whose changes are left out of the patch coverage.
+fn parse_diff(diff: &str) -> Result<BTreeMap<String, BTreeSet<u64>>> {
+    let mut changed: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    let mut file = None;
+    let mut old_header = false;
+    for line in diff.lines() {
+        // Only right after the old file's header, since an added line can start with ++ too
+        if old_header && let Some(path) = line.strip_prefix("+++ ") {
+            // Names with a space get a tab after them, and odd characters get them quoted
+            let path = path.strip_suffix('\t').unwrap_or(path);
+            let path = match path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+                Some(quoted) => unquote(quoted),
+                None => path.to_string(),
+            };
+            file = match path.strip_prefix("b/") {
+                Some(path) => Some(path.to_string()),
+                // A deleted file
+                None if path == "/dev/null" => None,
+                None => {
+                    return Err(CovError::parse(
+                        "git diff",
+                        format!("unexpected file header: {}", line),
+                    ));
+                }
+            };
+        } else if let Some(hunk) = line.strip_prefix("@@ ")
+            && let Some(file) = &file
+        {
+            // @@ -old_start,old_count +new_start,new_count @@, where a missing count means 1
+            let Some(new) = hunk.split_whitespace().find_map(|r| r.strip_prefix('+')) else {
+                continue;
+            };
+            let (start, count) = new.split_once(',').unwrap_or((new, "1"));
+            let (Ok(start), Ok(count)) = (start.parse::<u64>(), count.parse::<u64>()) else {
+                continue;
+            };
+            // A count of 0 is a hunk that only deletes
+            if count > 0 {
+                changed
+                    .entry(file.clone())
+                    .or_default()
+                    .extend(start..start + count);
+            }
+        }
+        old_header = line.starts_with("--- ");
+    }
+    Ok(changed)
+}
+
+/// A path git quoted for having quotes, control characters, or non-UTF-8 bytes in it, without
+/// the quotes: `\"` and `\\`, C escapes like `\t`, and octal bytes like `\303\251`
+fn unquote(quoted: &str) -> String {
+    let mut bytes = vec![];
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('t') => bytes.push(b'\t'),
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('a') => bytes.push(0x07),
+            Some('b') => bytes.push(0x08),
+            Some('f') => bytes.push(0x0c),
+            Some('v') => bytes.push(0x0b),
+            Some(d @ '0'..='7') => {
+                let mut byte = d.to_digit(8).unwrap_or(0);
+                for _ in 0..2 {
+                    let next = chars.clone().next().and_then(|c| c.to_digit(8));
+                    if let Some(digit) = next {
+                        byte = byte * 8 + digit;
+                        chars.next();
+                    }
+                }
+                bytes.push(byte as u8);
+            }
+            Some(c) => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            None => bytes.push(b'\\'),
+        }
+    }
+    decode("git diff", &bytes).into_owned()
+}
+
+/// A commit as `git blame` describes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameCommit {
+    /// All zeros for changes that aren't committed yet
+    pub id: String,
+    pub author: String,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub summary: String,
+}
+
+/// The commit that last changed each line of `file`, by line number
+pub fn blame(project_dir: &Path, file: &Path) -> Result<BTreeMap<u64, BlameCommit>> {
+    let output = exec(
+        Command::new("git")
+            .args(["blame", "--line-porcelain", "--"])
+            .arg(file)
+            .current_dir(project_dir),
+    )?;
+    Ok(parse_blame(&decode("git blame", &output.stdout)))
+}
+
+/// The commit of each line in `git blame --line-porcelain` output
+fn parse_blame(porcelain: &str) -> BTreeMap<u64, BlameCommit> {
+    // Every line is a header naming the commit and the line, then the commit's details, then
+    // the line itself after a tab
+    let mut lines = BTreeMap::new();
+    let mut line = None;
+    let mut commit = BlameCommit {
+        id: String::new(),
+        author: String::new(),
+        time: 0,
+        summary: String::new(),
+    };
+    for entry in porcelain.lines() {
+        if entry.starts_with('\t') {
+            if let Some(line) = line.take() {
+                lines.insert(line, commit.clone());
+            }
+        } else if let Some(author) = entry.strip_prefix("author ") {
+            commit.author = author.to_string();
+        } else if let Some(time) = entry.strip_prefix("author-time ") {
+            commit.time = time.parse().unwrap_or_default();
+        } else if let Some(summary) = entry.strip_prefix("summary ") {
+            commit.summary = summary.to_string();
+        } else if line.is_none()
+            && let [id, _, final_line, ..] = entry.split(' ').collect::<Vec<_>>()[..]
+            && id.len() >= 40
+            && id.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            commit.id = id.to_string();
+            line = final_line.parse().ok();
+        }
+    }
+    lines
+}
+
+/// Commit checked out in `project_dir`
+pub fn head(project_dir: &Path) -> Result<String> {
+    rev_parse(project_dir, &["HEAD"])
+}
+
+/// Current branch, or `None` on a detached HEAD
+pub fn branch(project_dir: &Path) -> Result<Option<String>> {
+    Ok(Some(rev_parse(project_dir, &["--abbrev-ref", "HEAD"])?).filter(|b| b != "HEAD"))
+}
+
+fn rev_parse(project_dir: &Path, args: &[&str]) -> Result<String> {
+    let output = exec(
+        Command::new("git")
+            .arg("rev-parse")
+            .args(args)
+            .current_dir(project_dir),
+    )?;
+
+    Ok(decode("git rev-parse", &output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[u64]) -> BTreeSet<u64> {
+        lines.iter().copied().collect()
+    }
+
+    #[test]
+    fn diff_hunks() {
+        let diff = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -3 +3 @@ fn a() {
+-    old();
++++ b/wrong.rs, an added line that starts with ++
+@@ -10,2 +10,3 @@ fn b() {
+-x
+-y
++x
++y
++z
+@@ -20,4 +22,0 @@ fn c() {
+-gone
+-gone
+-gone
+-gone
+@@ -30 +28,2 @@
+-last
++last
++last
+\\ No newline at end of file
+";
+        let changed = parse_diff(diff).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed["src/lib.rs"], lines(&[3, 10, 11, 12, 28, 29]));
+    }
+
+    #[test]
+    fn diff_renames_new_and_deleted_files() {
+        let diff = "\
+diff --git a/src/old.rs b/src/new.rs
+similarity index 90%
+rename from src/old.rs
+rename to src/new.rs
+--- a/src/old.rs
++++ b/src/new.rs
+@@ -5,0 +6 @@
++added
+diff --git a/src/added.rs b/src/added.rs
+new file mode 100644
+--- /dev/null
++++ b/src/added.rs
+@@ -0,0 +1,2 @@
++one
++two
+diff --git a/src/deleted.rs b/src/deleted.rs
+deleted file mode 100644
+--- a/src/deleted.rs
++++ /dev/null
+@@ -1,2 +0,0 @@
+-one
+-two
+";
+        let changed = parse_diff(diff).unwrap();
+        assert_eq!(
+            changed.keys().collect::<Vec<_>>(),
+            ["src/added.rs", "src/new.rs"]
+        );
+        assert_eq!(changed["src/new.rs"], lines(&[6]));
+        assert_eq!(changed["src/added.rs"], lines(&[1, 2]));
+    }
+
+    #[test]
+    fn diff_paths_with_spaces_and_quotes() {
+        let diff = "\
+--- /dev/null
++++ b/a b.rs\t
+@@ -0,0 +1 @@
++x
+--- /dev/null
++++ \"b/q\\\"t\\303\\251.rs\"
+@@ -0,0 +1 @@
++y
+";
+        let changed = parse_diff(diff).unwrap();
+        assert_eq!(changed.keys().collect::<Vec<_>>(), ["a b.rs", "q\"té.rs"]);
+    }
+
+    #[test]
+    fn diff_with_other_prefixes_is_an_error() {
+        // What diff.mnemonicPrefix gives, which changed_lines overrides
+        let diff = "\
+diff --git i/src/lib.rs w/src/lib.rs
+--- i/src/lib.rs
++++ w/src/lib.rs
+@@ -3 +3 @@
++new
+";
+        assert!(parse_diff(diff).is_err());
+    }
+
+    #[test]
+    fn blame_porcelain() {
+        let id = "0123456789abcdef0123456789abcdef01234567";
+        let porcelain = format!(
+            "\
+{id} 1 1 2
+author Alice
+author-mail <alice@example.com>
+author-time 1700000000
+author-tz +0000
+committer Alice
+summary Add the parser
+filename src/lib.rs
+\tfn parse() {{
+{id} 2 2
+author Alice
+author-mail <alice@example.com>
+author-time 1700000000
+author-tz +0000
+committer Alice
+summary Add the parser
+filename src/lib.rs
+\t    author not a header, but after a tab
+{zeros} 3 3 1
+author Not Committed Yet
+author-time 1700000100
+summary Version of src/lib.rs from src/lib.rs
+filename src/lib.rs
+\t}}
+",
+            zeros = "0".repeat(40)
+        );
+        let blamed = parse_blame(&porcelain);
+        assert_eq!(blamed.len(), 3);
+        assert_eq!(blamed[&1], blamed[&2]);
+        assert_eq!(
+            blamed[&1],
+            BlameCommit {
+                id: id.to_string(),
+                author: "Alice".to_string(),
+                time: 1700000000,
+                summary: "Add the parser".to_string(),
+            }
+        );
+        assert_eq!(blamed[&3].id, "0".repeat(40));
+        assert_eq!(blamed[&3].author, "Not Committed Yet");
+    }
+}
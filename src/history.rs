@@ -0,0 +1,169 @@
+//! A record of every run's totals, one JSON object per line, for seeing how coverage changes
+//! over time without an external dashboard.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::Write as _,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CovError, Result,
+    export::{Metric, Summary},
+};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Commit that was checked out, if the project is in a git checkout
+    pub commit: Option<String>,
+    pub totals: Summary,
+}
+
+impl Entry {
+    pub fn new(totals: &Summary, commit: Option<String>) -> Self {
+        Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            commit,
+            totals: totals.clone(),
+        }
+    }
+}
+
+/// Adds an entry to the end of the history file, creating it if needed
+pub fn append(path: &Path, entry: &Entry) -> Result<()> {
+    let line = serde_json::to_string(entry).expect("unable to serialize history entry");
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| CovError::io(path, e))
+}
+
+/// Every entry in the history file, oldest first
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let contents = fs::read_to_string(path).map_err(|e| CovError::io(path, e))?;
+    contents
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| CovError::Config {
+                path: path.to_path_buf(),
+                message: format!("line {} isn't a history entry: {}", i + 1, e),
+            })
+        })
+        .collect()
+}
+
+/// Bars from lowest to highest, for [`sparkline`]
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// A table of the given entries with how line coverage changed from one to the next, followed
+/// by a sparkline of it
+pub fn trend(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<16}  {:<7}  {:>8}  {:>8}  {:>8}  {:>9}",
+        "Date (UTC)", "Commit", "Lines", "Change", "Branches", "Functions"
+    )
+    .unwrap();
+    let mut previous: Option<&Metric> = None;
+    for entry in entries {
+        let lines = &entry.totals.lines;
+        let change = match previous {
+            Some(previous) => format!("{:+.2}", lines.percent - previous.percent),
+            None => String::new(),
+        };
+        let commit = entry.commit.as_deref().unwrap_or("-");
+        writeln!(
+            out,
+            "{:<16}  {:<7}  {:>8}  {:>8}  {:>8}  {:>9}",
+            format_timestamp(entry.timestamp),
+            &commit[..commit.len().min(7)],
+            percent(lines),
+            change,
+            percent(&entry.totals.branches),
+            percent(&entry.totals.functions)
+        )
+        .unwrap();
+        previous = Some(lines);
+    }
+
+    let percents: Vec<f64> = entries.iter().map(|e| e.totals.lines.percent).collect();
+    if percents.len() > 1 {
+        let (min, max) = percents.iter().fold((f64::MAX, f64::MIN), |(min, max), p| {
+            (min.min(*p), max.max(*p))
+        });
+        writeln!(
+            out,
+            "\nLines  {:.2}% {} {:.2}%",
+            min,
+            sparkline(&percents, min, max),
+            max
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// One bar per value, scaled from `min` to `max` so that small changes still show. A flat line
+/// sits in the middle.
+fn sparkline(values: &[f64], min: f64, max: f64) -> String {
+    values
+        .iter()
+        .map(|v| {
+            if (max - min).abs() < f64::EPSILON {
+                return SPARKS[SPARKS.len() / 2];
+            }
+            let level = ((v - min) / (max - min) * (SPARKS.len() - 1) as f64).round() as usize;
+            SPARKS[level.min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+/// Like llvm-cov's table, with `-` for nothing to cover
+fn percent(metric: &Metric) -> String {
+    if metric.count == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.2}%", metric.percent)
+    }
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC
+pub(crate) fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let minutes = timestamp % 86_400 / 60;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
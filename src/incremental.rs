@@ -0,0 +1,257 @@
+//! `changed_only` runs, which only rerun the tests of packages that changed and reuse the
+//! coverage of the rest. Each package's merged profile is kept in the target dir between runs,
+//! along with what git said the tree looked like, so the next run can tell what changed since.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Config, CovError, Result,
+    config::Runner,
+    git, profdata,
+    runner::{self, TestBinary},
+    workspace::{self, MetadataPackage},
+};
+
+/// Under the coverage target dir
+const CACHE_DIR: &str = "simple-cov-profiles";
+const STATE_FILE: &str = "state.json";
+
+/// Files outside every package that still change how everything builds
+const BUILD_FILES: [&str; 4] = [
+    "Cargo.toml",
+    "Cargo.lock",
+    "rust-toolchain",
+    "rust-toolchain.toml",
+];
+
+/// What the cached profiles were made from
+#[derive(Debug, Deserialize, Serialize)]
+struct State {
+    /// [`cache_key`] of the config they were made with
+    key: String,
+    /// Commit checked out at the time
+    commit: String,
+    /// Files that differed from `commit` at the time. They were measured as they were then, so
+    /// they count as changed even if they've been reverted since.
+    dirty: BTreeSet<String>,
+    /// Packages whose tests failed, which run again next time even if nothing changed, rather
+    /// than having their partial coverage reused
+    #[serde(default)]
+    failed: BTreeSet<String>,
+}
+
+/// Runs the tests of the packages that changed since the last `changed_only` run, or any that
+/// depend on them. Returns the indexed profiles to merge, fresh ones for those and cached ones
+/// for the rest, and the names of the packages that were reused. The first run, or one with
+/// different settings, runs everything. Test failures go to `failures` with `keep_going`.
+pub fn run_changed(
+    config: &Config,
+    tests: &[TestBinary],
+    failures: &mut Vec<String>,
+) -> Result<(Vec<PathBuf>, Vec<String>)> {
+    let metadata = workspace::metadata(
+        &config.tools.cargo,
+        &config.project_dir,
+        config.manifest_path.as_deref(),
+    )?;
+    let cache_dir = config
+        .target_dir
+        .clone()
+        .unwrap_or(metadata.target_directory)
+        .join(CACHE_DIR);
+    let state_path = cache_dir.join(STATE_FILE);
+    let key = cache_key(config);
+
+    let previous: Option<State> = fs::read_to_string(&state_path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .filter(|s: &State| s.key == key);
+    if previous.is_none() && cache_dir.exists() {
+        fs::remove_dir_all(&cache_dir).map_err(|e| CovError::io(&cache_dir, e))?;
+    }
+    fs::create_dir_all(&cache_dir).map_err(|e| CovError::io(&cache_dir, e))?;
+
+    let git_root = git::toplevel(&config.project_dir)?;
+    // A repo without commits has nothing to compare against, so everything runs every time
+    let commit = git::head(&config.project_dir).ok();
+    let dirty = match &commit {
+        Some(commit) => git::changed_since(&config.project_dir, commit)?,
+        None => BTreeSet::new(),
+    };
+    let affected = match (&previous, &commit) {
+        (Some(previous), Some(_)) => {
+            let mut changed = git::changed_since(&config.project_dir, &previous.commit)?;
+            changed.extend(dirty.iter().cloned());
+            changed.extend(previous.dirty.iter().cloned());
+            affected_packages(&metadata.packages, &changed, &git_root)
+        }
+        _ => None,
+    };
+
+    let packages = workspace::packages(&metadata.packages);
+    let mut by_package: BTreeMap<String, Vec<TestBinary>> = BTreeMap::new();
+    for test in tests {
+        let package = workspace::package_for(&packages, &test.package_dir.to_string_lossy())
+            .map(|p| p.name.clone())
+            .unwrap_or_default();
+        by_package.entry(package).or_default().push(test.clone());
+    }
+
+    let mut profiles = vec![];
+    let mut reused = vec![];
+    let mut failed = BTreeSet::new();
+    for (package, tests) in &by_package {
+        let profile = cache_dir.join(format!("{}.profdata", package));
+        let changed = affected.as_ref().is_none_or(|a| a.contains(package))
+            || previous
+                .as_ref()
+                .is_some_and(|p| p.failed.contains(package));
+        if changed || !profile.exists() {
+            let failures_before = failures.len();
+            // Merging straight away keeps these runs' profiles apart from the next package's
+            match config.runner {
+                Runner::Cargo => runner::run_tests(config, tests, failures)?,
+                Runner::Nextest => {
+                    runner::run_nextest(config, Some(slice::from_ref(package)), failures)?
+                }
+            }
+            profdata::merge(config, &[], &profile)?;
+            if failures.len() > failures_before {
+                failed.insert(package.clone());
+            }
+        } else {
+            reused.push(package.clone());
+        }
+        profiles.push(profile);
+    }
+
+    if let Some(commit) = commit {
+        let state = State {
+            key,
+            commit,
+            dirty,
+            failed,
+        };
+        let json = serde_json::to_string_pretty(&state).expect("unable to serialize cache state");
+        fs::write(&state_path, json).map_err(|e| CovError::io(&state_path, e))?;
+    }
+    Ok((profiles, reused))
+}
+
+/// Names of the packages containing a changed file, plus every member that depends on one of
+/// them, directly or not. `None` means everything is affected, for a change to the build setup,
+/// which counts even when it's inside a member, like a member's own Cargo.toml.
+fn affected_packages(
+    metadata: &[MetadataPackage],
+    changed: &BTreeSet<String>,
+    git_root: &Path,
+) -> Option<BTreeSet<String>> {
+    let packages = workspace::packages(metadata);
+    let mut containing = BTreeSet::new();
+    for file in changed {
+        if is_build_file(Path::new(file)) {
+            return None;
+        }
+        let path = git_root.join(file);
+        if let Some(package) = workspace::package_for(&packages, &path.to_string_lossy()) {
+            containing.insert(package.name.clone());
+        }
+    }
+    Some(workspace::dependents(metadata, &containing))
+}
+
+fn is_build_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|n| BUILD_FILES.iter().any(|b| n == *b))
+        || path.components().any(|c| c.as_os_str() == ".cargo")
+}
+
+/// Changes whenever a setting that changes what the tests do does, since coverage from a run
+/// with other features or filters can't stand in for this one
+fn cache_key(config: &Config) -> String {
+    let settings = serde_json::json!({
+        "features": config.features,
+        "all_features": config.all_features,
+        "no_default_features": config.no_default_features,
+        "release": config.release,
+        "target": config.target,
+        "targets": [config.lib, config.bins, config.tests],
+        "test": config.test,
+        "filter": config.filter,
+        "test_args": config.test_args,
+        "branch": config.branch,
+        "replace_rustflags": config.replace_rustflags,
+    });
+    format!("{:x}", Md5::digest(settings.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::MetadataDependency;
+
+    /// The workspace at `/repo`: `core`, `app` depending on it, and `cli` depending on `app`,
+    /// plus `other` on its own
+    fn workspace() -> Vec<MetadataPackage> {
+        let package = |name: &str, dependencies: &[&str]| MetadataPackage {
+            name: name.to_string(),
+            manifest_path: PathBuf::from(format!("/repo/{}/Cargo.toml", name)),
+            dependencies: dependencies
+                .iter()
+                .map(|d| MetadataDependency {
+                    name: d.to_string(),
+                    path: Some(PathBuf::from(format!("/repo/{}", d))),
+                })
+                .collect(),
+            metadata: None,
+        };
+        vec![
+            package("core", &[]),
+            package("app", &["core"]),
+            package("cli", &["app"]),
+            package("other", &[]),
+        ]
+    }
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn changed_sources_affect_their_package_and_dependents() {
+        let affected = affected_packages(
+            &workspace(),
+            &names(&["core/src/lib.rs", "README.md"]),
+            Path::new("/repo"),
+        );
+        assert_eq!(affected, Some(names(&["app", "cli", "core"])));
+
+        let affected = affected_packages(
+            &workspace(),
+            &names(&["cli/src/main.rs"]),
+            Path::new("/repo"),
+        );
+        assert_eq!(affected, Some(names(&["cli"])));
+    }
+
+    #[test]
+    fn build_files_affect_everything_even_inside_a_member() {
+        for file in [
+            "Cargo.lock",
+            "other/Cargo.toml",
+            ".cargo/config.toml",
+            "rust-toolchain",
+        ] {
+            let affected = affected_packages(&workspace(), &names(&[file]), Path::new("/repo"));
+            assert_eq!(affected, None, "{}", file);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! The `init` command's starter files, for setting up a project in one go.
+
+use std::{fs, path::Path};
+
+use crate::{CovError, Result, config::CONFIG_FILE};
+
+/// What a run can leave in the project dir that shouldn't be committed. Everything else goes in
+/// the target dir, which cargo already keeps out of git.
+pub const GITIGNORE_ENTRIES: [&str; 1] = ["*.profraw"];
+
+const STARTER_CONFIG: &str = r#"# Settings for simple-rust-cov. Any option from `simple-rust-cov --help` can go here, with
+# dashes, and the command line takes precedence. Paths are relative to this file.
+
+# The run fails when the total coverage is below these
+min-line-coverage = 0.8
+min-function-coverage = 0.8
+min-region-coverage = 0.8
+# Stable Rust doesn't instrument branches, see --branch
+min-branch-coverage = 0.0
+
+# Regexes for source files to leave out of the report
+ignore = []
+
+# Also write an lcov file, for editors and coverage services
+# lcov = "target/lcov.info"
+"#;
+
+/// Printed by `init --github-actions`, to paste into a workflow
+pub const GITHUB_WORKFLOW: &str = r#"# .github/workflows/coverage.yml
+name: Coverage
+on: [push, pull_request]
+jobs:
+  coverage:
+    runs-on: ubuntu-latest
+    steps:
+      - uses: actions/checkout@v4
+      - uses: dtolnay/rust-toolchain@stable
+        with:
+          components: llvm-tools
+      - run: cargo install --git https://github.com/steve-kaufman/simple-rust-cov
+      - run: simple-rust-cov
+"#;
+
+/// Writes a starter config file into `project_dir`. Returns false without touching it if
+/// there's already one, unless `force` is set.
+pub fn write_config(project_dir: &Path, force: bool) -> Result<bool> {
+    let path = project_dir.join(CONFIG_FILE);
+    if path.exists() && !force {
+        return Ok(false);
+    }
+    fs::write(&path, STARTER_CONFIG).map_err(|e| CovError::io(&path, e))?;
+    Ok(true)
+}
+
+/// Adds whichever of the [`GITIGNORE_ENTRIES`] are missing to the `.gitignore` in
+/// `project_dir`, creating it if need be. Returns the ones it added.
+pub fn update_gitignore(project_dir: &Path) -> Result<Vec<&'static str>> {
+    let path = project_dir.join(".gitignore");
+    let mut gitignore = match fs::read_to_string(&path) {
+        Ok(gitignore) => gitignore,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(CovError::io(&path, e)),
+    };
+    let missing: Vec<_> = GITIGNORE_ENTRIES
+        .into_iter()
+        .filter(|entry| !gitignore.lines().any(|line| line.trim() == *entry))
+        .collect();
+    if missing.is_empty() {
+        return Ok(missing);
+    }
+
+    if !gitignore.is_empty() && !gitignore.ends_with('\n') {
+        gitignore.push('\n');
+    }
+    for entry in &missing {
+        gitignore.push_str(entry);
+        gitignore.push('\n');
+    }
+    fs::write(&path, gitignore).map_err(|e| CovError::io(&path, e))?;
+    Ok(missing)
+}
@@ -0,0 +1,557 @@
+//! The coverage pipeline behind the `simple-rust-cov` binary, for tools that want to run it
+//! programmatically instead of shelling out.
+//!
+//! ```no_run
+//! use simple_rust_cov::config::{Config, Settings};
+//!
+//! # fn main() -> simple_rust_cov::Result<()> {
+//! let config = Config::resolve(".".into(), None, Settings::default())?;
+//! let report = simple_rust_cov::run_coverage(&config)?;
+//! assert!(report.failures.is_empty(), "{:?}", report.failures);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Instant,
+};
+
+use tracing::{debug, warn};
+
+pub mod baseline;
+pub mod blame;
+pub mod cargo_config;
+pub mod clean;
+pub mod codeowners;
+pub mod config;
+pub mod doctor;
+pub mod error;
+pub mod exclude;
+pub mod export;
+pub mod formats;
+pub mod git;
+pub mod history;
+pub mod incremental;
+pub mod init;
+pub mod patch;
+pub mod plan;
+pub mod profdata;
+pub mod progress;
+pub mod ratchet;
+pub mod report;
+pub mod runner;
+pub mod serve;
+pub mod thresholds;
+pub mod tools;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod upload;
+pub mod watch;
+pub mod workspace;
+
+pub use config::Config;
+pub use error::{CovError, Result};
+pub use profdata::Collected;
+pub use report::{Inputs, Report};
+
+use progress::Phase;
+
+/// In the profiles dir, for the merged profile of each `feature_matrix` run
+const MATRIX_DIR: &str = "matrix";
+
+/// Runs the tests with instrumentation, merges the profiles, writes any configured output files
+/// (lcov, HTML, badge), and checks the results against the thresholds.
+pub fn run_coverage(config: &Config) -> Result<Report> {
+    let report = report_coverage(config, collect(config)?)?;
+    record_history(config, &report)?;
+    Ok(report)
+}
+
+/// Does what [`run_coverage`] does with `.profraw` files that are already in the `profraw_dirs`,
+/// like ones downloaded from other CI jobs, instead of running the tests
+pub fn merge_coverage(config: &Config) -> Result<Report> {
+    let report = report_coverage(config, collect_profraw(config)?)?;
+    record_history(config, &report)?;
+    Ok(report)
+}
+
+fn record_history(config: &Config, report: &Report) -> Result<()> {
+    if config.record_history {
+        let commit = git::head(&config.project_dir).ok();
+        history::append(
+            &config.history_file,
+            &history::Entry::new(&report.data.totals, commit),
+        )?;
+    }
+    Ok(())
+}
+
+/// Does what [`run_coverage`] does with the coverage from the last run, without running the
+/// tests again. It isn't recorded in the history, since it's the same run.
+pub fn check_coverage(config: &Config) -> Result<Report> {
+    tools::check(&config.tools)?;
+    // Anything left over from a run that failed part way isn't part of this one
+    progress::take_timings();
+    report_coverage(config, Collected::load(config)?)
+}
+
+/// Runs the tests with instrumentation and merges the profiles, saving what's needed to report
+/// on them
+pub fn collect(config: &Config) -> Result<Collected> {
+    tools::check(&config.tools)?;
+    progress::take_timings();
+    let earlier = profdata::Earlier::read_all(config)?;
+    profdata::clear_profdata(config)?;
+
+    let mut test_failures = vec![];
+    let (mut collected, mut profiles) = if config.feature_matrix.is_empty() {
+        test_once(config, &mut test_failures)?
+    } else {
+        test_matrix(config, &mut test_failures)?
+    };
+    if config.doctests {
+        let phase = Phase::start(config, "Running doctests");
+        collected
+            .objects
+            .extend(runner::run_doctests(config, &mut test_failures)?);
+        phase.finish();
+    }
+    collected.test_failures = test_failures;
+    add_earlier(config, &earlier, &mut collected, &mut profiles)?;
+    merge_profiles(config, &profiles)?;
+    collected.save(config)?;
+    Ok(collected)
+}
+
+fn merge_profiles(config: &Config, profiles: &[PathBuf]) -> Result<()> {
+    let phase = Phase::start(config, "Merging profiles");
+    profdata::generate_profdata(config, profiles)?;
+    phase.finish();
+    Ok(())
+}
+
+/// Builds and runs the tests once. Returns the binaries, and any indexed profiles to merge on
+/// top of the raw ones the tests leave.
+fn test_once(config: &Config, failures: &mut Vec<String>) -> Result<(Collected, Vec<PathBuf>)> {
+    let build = build(config)?;
+    let phase = Phase::start(config, "Running tests");
+    let (profiles, reused) = if let Some(command) = &config.test_command {
+        runner::run_test_command(config, command, failures)?;
+        (vec![], vec![])
+    } else if config.changed_only {
+        incremental::run_changed(config, &build.tests, failures)?
+    } else {
+        match config.runner {
+            config::Runner::Cargo => runner::run_tests(config, &build.tests, failures)?,
+            config::Runner::Nextest => runner::run_nextest(config, None, failures)?,
+        }
+        (vec![], vec![])
+    };
+    phase.finish();
+    let collected = Collected {
+        objects: build.objects,
+        out_dirs: build.out_dirs,
+        reused,
+        ..Default::default()
+    };
+    Ok((collected, profiles))
+}
+
+/// Runs the tests once per `feature_matrix` entry, like [`test_once`]. Each run's profiles are
+/// merged before the next one starts, so they're kept apart, and its failures say which
+/// features they were with.
+fn test_matrix(config: &Config, failures: &mut Vec<String>) -> Result<(Collected, Vec<PathBuf>)> {
+    let dir = profdata::profiles_dir(config).join(MATRIX_DIR);
+    fs::create_dir_all(&dir).map_err(|e| CovError::io(&dir, e))?;
+
+    let mut collected = Collected::default();
+    let mut profiles = vec![];
+    for (index, features) in config.feature_matrix.iter().enumerate() {
+        let mut run = config.clone();
+        run.features.extend(features.iter().cloned());
+        let failures_before = failures.len();
+        let (theirs, their_profiles) = test_once(&run, failures)?;
+        for failure in &mut failures[failures_before..] {
+            *failure = format!("with features {}: {}", features.join(","), failure);
+        }
+
+        let profile = dir.join(format!("{}.profdata", index));
+        profdata::merge(&run, &their_profiles, &profile)?;
+        profiles.push(profile);
+        collected.add(&theirs);
+        for package in theirs.reused {
+            if !collected.reused.contains(&package) {
+                collected.reused.push(package);
+            }
+        }
+    }
+    Ok((collected, profiles))
+}
+
+/// Merges the `.profraw` files already in the `profraw_dirs` instead of running the tests, which
+/// are only built, for the coverage mappings. They have to be built the same way as the tests
+/// that left the files, from the same source and with the same toolchain.
+pub fn collect_profraw(config: &Config) -> Result<Collected> {
+    tools::check(&config.tools)?;
+    progress::take_timings();
+    let earlier = profdata::Earlier::read_all(config)?;
+    profdata::clear_profdata(config)?;
+
+    let build = build(config)?;
+    let mut profiles = vec![];
+    let mut collected = Collected {
+        objects: build.objects,
+        out_dirs: build.out_dirs,
+        ..Default::default()
+    };
+    add_earlier(config, &earlier, &mut collected, &mut profiles)?;
+    merge_profiles(config, &profiles)?;
+    collected.save(config)?;
+    Ok(collected)
+}
+
+fn build(config: &Config) -> Result<runner::Build> {
+    let phase = Phase::start(config, "Building");
+    let build = runner::build(config)?;
+    phase.finish();
+    Ok(build)
+}
+
+/// Adds the `merge_with` profiles to the ones to merge, and the binaries their runs saved to
+/// `collected`
+fn add_earlier(
+    config: &Config,
+    earlier: &[profdata::Earlier],
+    collected: &mut Collected,
+    profiles: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for (index, run) in earlier.iter().enumerate() {
+        profiles.push(run.restore(config, index)?);
+        if let Some(theirs) = &run.collected {
+            collected.add(theirs);
+        }
+    }
+    Ok(())
+}
+
+/// Writes the output files and checks the thresholds for coverage that's already been collected
+pub fn report_coverage(config: &Config, collected: Collected) -> Result<Report> {
+    let phase = Phase::start(config, "Generating the report");
+    let mut report = write_report(config, collected)?;
+    phase.finish();
+    report.timings = progress::take_timings();
+    Ok(report)
+}
+
+fn write_report(config: &Config, collected: Collected) -> Result<Report> {
+    let inputs = inputs(config, &collected)?;
+    let Collected {
+        reused,
+        test_failures,
+        ..
+    } = collected;
+    let root = project_root(config)?;
+
+    if let Some(lcov_path) = &config.lcov {
+        report::export_lcov(config, &inputs, lcov_path)?;
+    }
+    if let Some(html_dir) = &config.html {
+        report::show_html(config, &inputs, html_dir)?;
+    }
+    let source_view = match &config.show_source {
+        Some(source) => Some(report::show_source(config, &inputs, source)?),
+        None => None,
+    };
+
+    // Line-level data is only exported when something needs it, since it's much bigger
+    let needs_lines = config.format.needs_lines()
+        || config.annotate.is_some()
+        || config.upload.is_some()
+        || config.diff_base.is_some()
+        || config.show_uncovered_functions
+        || config.line_data;
+    let mut data = report::export_data(config, &inputs, !needs_lines)?;
+    let excluded = exclude::excluded_lines(config, &data);
+    let excluded_any = !excluded.is_empty();
+    if excluded_any && !needs_lines {
+        // The summary can't say which lines to take out
+        data = report::export_data(config, &inputs, false)?;
+    }
+    exclude::apply(&mut data, excluded);
+
+    if let Some(badge_path) = &config.badge {
+        report::write_badge(&data, badge_path)?;
+    }
+
+    let mut report = match config.format {
+        config::Format::Text => report::execute_report(config, &inputs, data)?,
+        _ => Report::from_export(data),
+    };
+    // llvm-cov's table can't leave out parts of files
+    if excluded_any && config.format == config::Format::Text {
+        report.warnings.push(format!(
+            "the table above still counts the excluded lines. Without them, line coverage is {:.2}%",
+            report.data.totals.lines.percent
+        ));
+    }
+    report.source_view = source_view;
+    report.reused = reused;
+    report.test_failures = test_failures;
+    report.coverage_off = exclude::coverage_off(&report.data);
+    if config.show_uncovered_functions {
+        report.uncovered_functions = Some(report.data.uncovered_functions());
+    }
+    if let Some(base) = &config.diff_base {
+        let changed = git::changed_lines(&config.project_dir, base)?;
+        let git_root = git::toplevel(&config.project_dir)?;
+        report.patch = Some(patch::PatchCoverage::new(
+            base,
+            &report.data,
+            &changed,
+            &git_root,
+        ));
+    }
+
+    report.paths = thresholds::check_paths(config, &report.data.files, &root);
+    report.failures = thresholds::check(config, &report, &root)?;
+    report.soft_failures = thresholds::check_soft(config, &report);
+    if config.baseline.is_some() || config.save_baseline.is_some() {
+        let current = baseline::Baseline::new(&report.data, &root);
+        let regressions = match &config.baseline {
+            Some(path) => {
+                baseline::Baseline::load(path)?.regressions(&current, config.max_coverage_drop)
+            }
+            None => vec![],
+        };
+        // Saving over a baseline that was just dropped below would let coverage slip a little
+        // on every run
+        if let Some(path) = &config.save_baseline
+            && regressions.is_empty()
+        {
+            current.save(path)?;
+        }
+        report.failures.extend(regressions);
+    }
+    if config.ratchet {
+        report
+            .failures
+            .extend(ratchet::ratchet(&report.data, &root)?);
+    }
+    if !config.strict_branch {
+        report
+            .warnings
+            .extend(thresholds::missing_branch_data(config, &report));
+    }
+    Ok(report)
+}
+
+/// What llvm-cov should read for collected coverage, with the configured files left out
+pub fn inputs(config: &Config, collected: &Collected) -> Result<Inputs> {
+    let mut ignore = vec![];
+    if !config.include_generated {
+        for dir in &collected.out_dirs {
+            ignore.push(exclude::out_dir_regex(dir));
+            // Whichever spelling llvm-cov matches the regexes against
+            if let Some(remapped) = config::remap_path(&config.path_equivalence, dir) {
+                ignore.push(exclude::out_dir_regex(&remapped));
+            }
+        }
+    }
+    if config.exclude_tests {
+        ignore.push(exclude::tests_dir_regex(&project_root(config)?));
+    }
+
+    let mut objects = collected.objects.clone();
+    for object in &config.objects {
+        // llvm-cov's error for a missing one doesn't say it came from the config
+        fs::metadata(object).map_err(|e| CovError::io(object, e))?;
+        if !objects.contains(object) {
+            objects.push(object.clone());
+        }
+    }
+    let inputs = Inputs {
+        sources: report::included_sources(config, &objects, &ignore)?,
+        objects,
+        ignore,
+    };
+    debug!(objects = ?inputs.objects, sources = ?inputs.sources, ignore = ?inputs.ignore, "llvm-cov inputs");
+    Ok(inputs)
+}
+
+/// Where the HTML report goes for the commands that always write one, when there's no `html`
+pub fn default_html_dir(config: &Config) -> PathBuf {
+    config.output_dir.join(config::DEFAULT_HTML_DIR)
+}
+
+/// Absolute path of the project dir, which is what llvm-cov reports file paths relative to
+pub fn project_root(config: &Config) -> Result<PathBuf> {
+    canonicalize(&config.project_dir).map_err(|e| CovError::io(&config.project_dir, e))
+}
+
+/// [`fs::canonicalize`], minus the `\\?\` prefix it adds on Windows. llvm-cov and cargo report
+/// plain `C:\...` paths, which wouldn't match a verbatim one.
+pub(crate) fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    let path = fs::canonicalize(path)?;
+    Ok(match path.to_str().and_then(|p| p.strip_prefix(r"\\?\")) {
+        // UNC shares have no plain spelling that's as exact, so keep those verbatim
+        Some(plain) if !plain.starts_with("UNC") => PathBuf::from(plain),
+        _ => path,
+    })
+}
+
+/// See [`set_verbose`]
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Prints every command that's run to stderr before it runs, with the environment it changes and
+/// the directory it runs in, so that a failing one can be rerun by hand
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Prints the command with `verbose`, and logs it
+fn echo(cmd: &Command) {
+    let line = command_line(cmd);
+    debug!(command = %line, "running");
+    if VERBOSE.load(Ordering::Relaxed) {
+        progress::suspend(|| eprintln!("+ {}", line));
+    }
+}
+
+/// The command as a line for a POSIX shell
+fn command_line(cmd: &Command) -> String {
+    let mut parts = vec![];
+    if let Some(dir) = cmd.get_current_dir() {
+        parts.extend(["cd".to_string(), quote(dir.as_os_str()), "&&".to_string()]);
+    }
+    let envs: Vec<_> = cmd.get_envs().collect();
+    if !envs.is_empty() {
+        parts.push("env".to_string());
+    }
+    for (name, _) in envs.iter().filter(|(_, value)| value.is_none()) {
+        parts.extend(["-u".to_string(), quote(name)]);
+    }
+    for (name, value) in &envs {
+        if let Some(value) = value {
+            parts.push(format!("{}={}", name.to_string_lossy(), quote(value)));
+        }
+    }
+    parts.push(quote(cmd.get_program()));
+    parts.extend(cmd.get_args().map(quote));
+    parts.join(" ")
+}
+
+/// Quotes a word for a POSIX shell, when it needs it
+fn quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Runs a command to completion, turning a failure to start it or an unsuccessful exit into a
+/// [`CovError`]
+pub(crate) fn exec(cmd: &mut Command) -> Result<Output> {
+    echo(cmd);
+    let started = Instant::now();
+    let tool = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd.output().map_err(|source| CovError::Spawn {
+        tool: tool.clone(),
+        source,
+    })?;
+    debug!(tool, status = %output.status, elapsed = ?started.elapsed(), "finished");
+    if !output.status.success() {
+        return Err(CovError::tool_failed(tool, &output));
+    }
+    Ok(output)
+}
+
+/// Like [`exec`], but passes the command's output on as it comes, for test runs long enough
+/// that you'd want to see how they're going. Both streams go to stderr, leaving stdout to the
+/// report. The output is still captured for the error when the command fails.
+pub(crate) fn exec_streamed(cmd: &mut Command) -> Result<Output> {
+    echo(cmd);
+    let started = Instant::now();
+    let tool = cmd.get_program().to_string_lossy().into_owned();
+    let spawn_error = |source| CovError::Spawn {
+        tool: tool.clone(),
+        source,
+    };
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let (stdout, stderr) = thread::scope(|scope| {
+        let stdout = scope.spawn(|| tee(stdout));
+        let stderr = tee(stderr);
+        (stdout.join().expect("stdout thread panicked"), stderr)
+    });
+    let output = Output {
+        status: child.wait().map_err(spawn_error)?,
+        stdout,
+        stderr,
+    };
+    debug!(tool, status = %output.status, elapsed = ?started.elapsed(), "finished");
+    if !output.status.success() {
+        return Err(CovError::tool_failed(tool, &output));
+    }
+    Ok(output)
+}
+
+/// Copies everything read from `from` to stderr a line at a time, keeping the lines clear of
+/// the progress spinner, and returns it
+fn tee(mut from: impl Read) -> Vec<u8> {
+    // Output that can't be shown is still worth capturing, so write errors are ignored
+    let show = |lines: &[u8]| progress::suspend(|| io::stderr().write_all(lines).ok());
+    let mut copy = vec![];
+    let mut shown = 0;
+    let mut buf = [0; 8192];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                copy.extend_from_slice(&buf[..n]);
+                if let Some(end) = copy[shown..].iter().rposition(|b| *b == b'\n') {
+                    show(&copy[shown..=shown + end]);
+                    shown += end + 1;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    if shown < copy.len() {
+        show(&copy[shown..]);
+    }
+    copy
+}
+
+/// What `tool` printed, as text. Bytes that aren't UTF-8, from a file name or a tool printing
+/// in the locale's encoding, become U+FFFD with a warning rather than failing the run.
+pub(crate) fn decode<'a>(tool: &str, bytes: &'a [u8]) -> Cow<'a, str> {
+    let text = String::from_utf8_lossy(bytes);
+    if let Cow::Owned(_) = text {
+        warn!(tool, "output wasn't UTF-8");
+        progress::suspend(|| {
+            eprintln!(
+                "warning: {} printed text that isn't UTF-8, which was replaced with U+FFFD",
+                tool
+            )
+        });
+    }
+    text
+}
@@ -1,229 +1,1760 @@
 use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
     fs::{self},
-    path::PathBuf,
-    process::{self, Command, Output},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
 };
 
-use clap::Parser;
-use serde_json::Value;
-
-const DEFAULT_MIN_LINE_COVERAGE: f32 = 1.0;
-const DEFAULT_MIN_BRANCH_COVERAGE: f32 = 1.0;
-
-const PROFDATA_DIR: &'static str = ".profdata";
-const PROFDATA_PATH: &'static str = ".profdata/unittest.profdata";
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, error::ErrorKind};
+use clap_complete::Shell;
+use simple_rust_cov::{
+    Collected, CovError, Report,
+    blame::{self, BlameBy},
+    clean,
+    codeowners::{self, CodeOwners},
+    config::{
+        Annotate, CONFIG_FILE, Config, ExportStrategy, Format, JSON_FILE, PackageMinimums,
+        PathEquivalence, Runner, Settings, ToolSettings, Upload,
+    },
+    default_html_dir, doctor,
+    error::{EXIT_OK, EXIT_SETUP, EXIT_TESTS},
+    export::Summary,
+    formats, git, history, init, plan, profdata, progress, report, tools, upload, workspace,
+};
+use tracing_subscriber::filter::LevelFilter;
 
+// Options here, and their SIMPLE_COV_* environment variables, override the config file. See
+// `config` for how they're resolved.
 #[derive(Debug, Parser)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, after_help = EXIT_CODES)]
 struct Args {
-    #[arg(long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_LINE_COVERAGE",
+        help(
+            "Minimum total line coverage, as a fraction like 0.8 or a percentage like 80 [default: 1.0]"
+        )
+    )]
     min_line_coverage: Option<f32>,
-    #[arg(long)]
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_BRANCH_COVERAGE",
+        help("Minimum total branch coverage [default: 1.0]")
+    )]
     min_branch_coverage: Option<f32>,
-    #[arg(help("Path to Cargo project. Defaults to current working directory"))]
-    project_dir: Option<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_FUNCTION_COVERAGE",
+        help("Minimum fraction of functions that ran at least once")
+    )]
+    min_function_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_REGION_COVERAGE",
+        help("Minimum total region coverage")
+    )]
+    min_region_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_PATCH_COVERAGE",
+        requires = "diff_base",
+        help("Minimum coverage of the lines changed since --diff-base")
+    )]
+    min_patch_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_FILE_LINE_COVERAGE",
+        help("Minimum line coverage for every individual file")
+    )]
+    min_file_line_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_MIN_FILE_BRANCH_COVERAGE",
+        help("Minimum branch coverage for every individual file")
+    )]
+    min_file_branch_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_WARN_LINE_COVERAGE",
+        help("Line coverage to warn under without failing, for a minimum that isn't enforced yet")
+    )]
+    warn_line_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_WARN_BRANCH_COVERAGE",
+        help("Branch coverage to warn under without failing")
+    )]
+    warn_branch_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_WARN_FUNCTION_COVERAGE",
+        help("Function coverage to warn under without failing")
+    )]
+    warn_function_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_WARN_REGION_COVERAGE",
+        help("Region coverage to warn under without failing")
+    )]
+    warn_region_coverage: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_PERCENT",
+        help(
+            "Read the minimums as percentages, even ones of 1 or less. Without it, a minimum over 1 is a percentage and one up to 1 is a fraction"
+        )
+    )]
+    percent: bool,
+    #[arg(
+        long,
+        value_name = "POINTS",
+        env = "SIMPLE_COV_TOLERANCE",
+        help(
+            "Percentage points coverage can be under a minimum and still meet it, like 0.5, for coverage that sits right at one"
+        )
+    )]
+    tolerance: Option<f32>,
+    // Not a setting, since it's about the exit code rather than anything in the report
+    #[arg(
+        long,
+        env = "SIMPLE_COV_NO_FAIL",
+        help(
+            "Only report the coverage, and exit 0 even if a requirement isn't met. Failing tests still fail without --keep-going"
+        )
+    )]
+    no_fail: bool,
+    #[arg(
+        long,
+        value_enum,
+        env = "SIMPLE_COV_FORMAT",
+        help("Output format [default: text]")
+    )]
+    format: Option<Format>,
+    #[arg(
+        long,
+        value_enum,
+        env = "SIMPLE_COV_EXPORT_STRATEGY",
+        help(
+            "How to export the coverage of several test binaries. parallel is faster with many of them [default: single]"
+        )
+    )]
+    export_strategy: Option<ExportStrategy>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_NO_PROGRESS",
+        help("Don't show which step the run is on. It's only shown when stdout is a terminal")
+    )]
+    no_progress: bool,
+    // Not a setting, since it's about what this run prints rather than what it does
+    #[arg(
+        short,
+        long,
+        env = "SIMPLE_COV_VERBOSE",
+        help(
+            "Print every command that's run, with the environment it changes and the directory it runs in"
+        )
+    )]
+    verbose: bool,
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        env = "SIMPLE_COV_LOG_LEVEL",
+        help(
+            "Log what the run is doing to stderr, for troubleshooting: error, warn, info, debug or trace [default: off]"
+        )
+    )]
+    log_level: Option<LevelFilter>,
+    #[arg(
+        short,
+        long,
+        env = "SIMPLE_COV_QUIET",
+        conflicts_with = "verbose",
+        help(
+            "Only print the totals and whether the requirements were met, leaving out the table and the tests' output"
+        )
+    )]
+    quiet: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_DRY_RUN",
+        conflicts_with = "watch",
+        help(
+            "Print what running the tests, merge, or report would do, without doing any of it: the commands with their environment, and the files they'd remove and write"
+        )
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        help("Print the JSON Schema of the --format json output, and exit")
+    )]
+    print_schema: bool,
+    #[arg(
+        long,
+        value_enum,
+        env = "SIMPLE_COV_ANNOTATE",
+        help("Print annotations for uncovered lines in changed files")
+    )]
+    annotate: Option<Annotate>,
+    #[arg(
+        long,
+        value_enum,
+        env = "SIMPLE_COV_UPLOAD",
+        help("Upload the report to a coverage service")
+    )]
+    upload: Option<Upload>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_LCOV",
+        value_name = "PATH",
+        help("Also write an lcov .info file to this path, or to lcov.info in the output dir")
+    )]
+    lcov: Option<Option<PathBuf>>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_HTML",
+        value_name = "DIR",
+        help(
+            "Also write a browsable HTML report into this directory, or to html in the output dir"
+        )
+    )]
+    html: Option<Option<PathBuf>>,
+    // Only on the command line, since a browser popping up is never what CI wants
+    #[arg(long, help("Open the HTML report in a browser once it's written"))]
+    open: bool,
+    #[arg(
+        long,
+        help("Rerun whenever a source file changes, printing the totals and how they changed")
+    )]
+    watch: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_YES",
+        help(
+            "Install missing llvm tools with rustup without asking, e.g. in CI. Otherwise it asks when run in a terminal"
+        )
+    )]
+    yes: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_BADGE",
+        help(
+            "Also write a line coverage badge to this path, as SVG if it ends in .svg and as a shields.io endpoint otherwise"
+        )
+    )]
+    badge: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "SIMPLE_COV_SAVE_BASELINE",
+        help("Save the totals and per-file coverage to FILE, for --baseline to compare against")
+    )]
+    save_baseline: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "SIMPLE_COV_BASELINE",
+        help("Fail if coverage dropped below a baseline saved with --save-baseline")
+    )]
+    baseline: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "POINTS",
+        env = "SIMPLE_COV_MAX_COVERAGE_DROP",
+        help(
+            "Percentage points coverage can drop below the baseline, like 0.5 for half a point [default: 0]"
+        )
+    )]
+    max_coverage_drop: Option<f32>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_RATCHET",
+        help(
+            "Fail if any file's coverage drops below the best recorded in simple-cov.lock, and record improvements there"
+        )
+    )]
+    ratchet: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_RECORD_HISTORY",
+        help("Add this run's totals to the history file, for the trend command")
+    )]
+    record_history: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "SIMPLE_COV_HISTORY_FILE",
+        help("History file to record to and read from [default: .simple-cov-history.jsonl]")
+    )]
+    history_file: Option<PathBuf>,
+    #[arg(
+        long = "package-threshold",
+        value_name = "PACKAGE=LINE[,BRANCH]",
+        value_parser = parse_package_threshold,
+        env = "SIMPLE_COV_PACKAGE_THRESHOLDS",
+        value_delimiter = ' ',
+        help("Minimum coverage for a single workspace member. Can be repeated, or space-separated in the env var")
+    )]
+    package_thresholds: Vec<(String, PackageMinimums)>,
+    #[arg(
+        long,
+        value_name = "LINES",
+        env = "SIMPLE_COV_MAX_COVERAGE_OFF_LINES",
+        help("Fail if more than LINES lines of code are behind #[coverage(off)]")
+    )]
+    max_coverage_off_lines: Option<u64>,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        env = "SIMPLE_COV_IGNORE",
+        value_delimiter = ' ',
+        help(
+            "Leave source files matching REGEX out of the report, e.g. tests/ or generated/. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    ignore: Vec<String>,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        env = "SIMPLE_COV_INCLUDE",
+        value_delimiter = ' ',
+        help(
+            "Only report on source files matching REGEX, e.g. src/. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    include: Vec<String>,
+    #[arg(
+        long,
+        value_name = "FROM,TO",
+        env = "SIMPLE_COV_PATH_EQUIVALENCE",
+        value_delimiter = ' ',
+        help(
+            "Report source paths under FROM as under TO, for coverage collected where the sources were somewhere else, like in a container. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    path_equivalence: Vec<PathEquivalence>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_EXCLUDE_TESTS",
+        help("Leave #[cfg(test)] code and tests/ directories out of the coverage numbers")
+    )]
+    exclude_tests: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_INCLUDE_GENERATED",
+        help("Keep code that build scripts generate into OUT_DIR in the report")
+    )]
+    include_generated: bool,
+    #[arg(
+        long = "profraw-dir",
+        value_name = "DIR",
+        env = "SIMPLE_COV_PROFRAW_DIRS",
+        value_delimiter = ' ',
+        help(
+            "Directory to search recursively for .profraw files [default: the project dir]. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    profraw_dirs: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        env = "SIMPLE_COV_PROFILE_FILE",
+        help(
+            "LLVM_PROFILE_FILE pattern for the test run [default: <OUTPUT_DIR>/profiles/raw/%p-%m.profraw]"
+        )
+    )]
+    profile_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_KEEP_PROFRAW",
+        help(
+            "Keep the raw profiles in <OUTPUT_DIR>/profiles/kept once they're merged, until the next run"
+        )
+    )]
+    keep_profraw: bool,
+    #[arg(
+        long,
+        value_name = "PROFDATA",
+        env = "SIMPLE_COV_MERGE_WITH",
+        value_delimiter = ' ',
+        help(
+            "Indexed profile from another run to merge into this one's, along with the binaries saved next to it. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    merge_with: Vec<PathBuf>,
+    #[arg(
+        long = "object",
+        value_name = "PATH",
+        env = "SIMPLE_COV_OBJECTS",
+        value_delimiter = ' ',
+        help(
+            "Instrumented binary to read coverage from besides the ones the tests built, like a helper built outside cargo test. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    objects: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        env = "SIMPLE_COV_OUTPUT_DIR",
+        help(
+            "Directory for the profiles and the reports that aren't given a path [default: simple-cov in the target dir]"
+        )
+    )]
+    output_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_REPLACE_RUSTFLAGS",
+        help(
+            "Build with only -C instrument-coverage, ignoring RUSTFLAGS and rustflags from .cargo/config.toml"
+        )
+    )]
+    replace_rustflags: bool,
+    #[arg(
+        long,
+        value_name = "BOOL",
+        env = "SIMPLE_COV_SEPARATE_TARGET_DIR",
+        help(
+            "Build into target/coverage, so coverage runs don't invalidate normal builds [default: true]"
+        )
+    )]
+    separate_target_dir: Option<bool>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_WORKSPACE",
+        help("Test every member of the workspace")
+    )]
+    workspace: bool,
+    #[arg(
+        short,
+        long = "package",
+        value_name = "PACKAGE",
+        env = "SIMPLE_COV_PACKAGES",
+        value_delimiter = ' ',
+        help(
+            "Only test this workspace member. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    packages: Vec<String>,
+    #[arg(
+        long,
+        value_name = "PACKAGE",
+        env = "SIMPLE_COV_EXCLUDE",
+        value_delimiter = ' ',
+        requires = "workspace",
+        help(
+            "Leave this member out with --workspace. Can be repeated, or space-separated in the env var"
+        )
+    )]
+    exclude: Vec<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_LIB",
+        help("Only test the library. Combines with --bins, --tests and --test")
+    )]
+    lib: bool,
+    #[arg(long, env = "SIMPLE_COV_BINS", help("Only test the binaries"))]
+    bins: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_TESTS",
+        help("Only run the test targets, like the integration tests in tests/")
+    )]
+    tests: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        env = "SIMPLE_COV_TEST",
+        value_delimiter = ' ',
+        help("Only run this integration test. Can be repeated, or space-separated in the env var")
+    )]
+    test: Vec<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_CHANGED_ONLY",
+        help(
+            "Only rerun the tests of packages changed since the last --changed-only run according to git, and reuse the coverage of the rest"
+        )
+    )]
+    changed_only: bool,
+    #[arg(
+        short = 'F',
+        long,
+        env = "SIMPLE_COV_FEATURES",
+        value_delimiter = ',',
+        help("Features to build the tests with. Can be repeated, or comma-separated")
+    )]
+    features: Vec<String>,
+    #[arg(
+        long,
+        value_name = "SETS",
+        env = "SIMPLE_COV_FEATURE_MATRIX",
+        value_delimiter = ';',
+        help(
+            "Run the tests once per feature set and report the combined coverage, like \"default;serde;full,async\". Each set is added to --features"
+        )
+    )]
+    feature_matrix: Vec<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_ALL_FEATURES",
+        help("Build the tests with every feature")
+    )]
+    all_features: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_NO_DEFAULT_FEATURES",
+        help("Build the tests without the default features")
+    )]
+    no_default_features: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_RELEASE",
+        help("Build the tests in release mode")
+    )]
+    release: bool,
+    #[arg(
+        long,
+        value_name = "TRIPLE",
+        env = "SIMPLE_COV_TARGET",
+        help("Build the tests for another target, like aarch64-unknown-linux-gnu")
+    )]
+    target: Option<String>,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        env = "SIMPLE_COV_TARGET_RUNNER",
+        help(
+            "Run each test binary with this, like \"qemu-aarch64 -L /usr/aarch64-linux-gnu\" [default: CARGO_TARGET_<TRIPLE>_RUNNER]"
+        )
+    )]
+    target_runner: Option<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_RUNNER",
+        help("What runs the tests once they're built [default: cargo]")
+    )]
+    runner: Option<Runner>,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        env = "SIMPLE_COV_TEST_COMMAND",
+        help(
+            "Shell command to run the tests with instead, like \"just test-all\". It gets the instrumentation env vars, and its profiles are collected afterwards"
+        )
+    )]
+    test_command: Option<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_KEEP_GOING",
+        help(
+            "Carry on when tests fail, and still report the coverage. The exit code says whether the tests failed, the requirements weren't met, or both"
+        )
+    )]
+    keep_going: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        env = "SIMPLE_COV_FILTER",
+        help("Only run tests whose names contain NAME")
+    )]
+    filter: Option<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_DOCTESTS",
+        help("Also measure coverage from doctests. Needs a nightly toolchain")
+    )]
+    doctests: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_BRANCH",
+        help("Instrument branches, which stable Rust doesn't do. Needs a nightly toolchain")
+    )]
+    branch: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_STRICT_BRANCH",
+        help(
+            "Fail, instead of warning, when there's a branch coverage minimum but no branch data to check it against"
+        )
+    )]
+    strict_branch: bool,
+    #[arg(
+        long,
+        value_name = "REF",
+        env = "SIMPLE_COV_DIFF_BASE",
+        help("Also report coverage of the lines changed since REF, e.g. origin/main")
+    )]
+    diff_base: Option<String>,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_SHOW_UNCOVERED_FUNCTIONS",
+        help("List every function that never ran, by file")
+    )]
+    show_uncovered_functions: bool,
+    #[arg(
+        long,
+        env = "SIMPLE_COV_CONFIG",
+        help("Config file to use instead of .simple-cov.toml in the project dir")
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "SIMPLE_COV_LLVM_PROFDATA",
+        help(
+            "llvm-profdata to use. By default, rust-profdata, then the toolchain's own, then llvm-profdata from the PATH"
+        )
+    )]
+    llvm_profdata: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "SIMPLE_COV_LLVM_COV",
+        help(
+            "llvm-cov to use. By default, rust-cov, then the toolchain's own, then llvm-cov from the PATH"
+        )
+    )]
+    llvm_cov: Option<String>,
+    #[arg(
+        env = "SIMPLE_COV_PROJECT_DIR",
+        help("Path to Cargo project. Defaults to current working directory")
+    )]
+    project_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "SIMPLE_COV_MANIFEST_PATH",
+        conflicts_with = "project_dir",
+        help("Cargo.toml of the project, instead of the project dir. It's passed on to cargo")
+    )]
+    manifest_path: Option<PathBuf>,
+    #[arg(
+        last = true,
+        value_name = "TEST_ARGS",
+        env = "SIMPLE_COV_TEST_ARGS",
+        value_delimiter = ' ',
+        help(
+            "Arguments for the test binaries, like the ones after -- in cargo test. A test name filter like my_module:: only runs those tests"
+        )
+    )]
+    test_args: Vec<String>,
 }
 
-#[derive(Debug)]
-struct Report {
-    line_coverage: f32,
-    branch_coverage: f32,
+// Without a command, the tests are run and the coverage is checked, the same as `run` followed
+// by `check`. Options go before the command, since they're shared with the default run.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the tests and collect the coverage, without reporting on it
+    Run,
+    /// Build the tests without running them, and report on the .profraw files in --profraw-dir,
+    /// like ones from other CI jobs. Checks the requirements like the default run.
+    Merge,
+    /// Print and write the reports for the last run's coverage, without checking the requirements
+    Report,
+    /// Check the coverage from the last run again, without rerunning the tests. For trying out
+    /// other requirements or output options.
+    Check,
+    /// Write the HTML report for the last run's coverage, into --html or html in the output dir
+    Html,
+    /// Check that everything a run needs is installed and working, and say how to fix what isn't
+    Doctor,
+    /// Write a starter .simple-cov.toml and add the coverage files to .gitignore
+    Init {
+        #[arg(long, help("Replace an existing .simple-cov.toml"))]
+        force: bool,
+        #[arg(
+            long,
+            help("Also print a GitHub Actions workflow that runs the coverage")
+        )]
+        github_actions: bool,
+    },
+    /// Delete the coverage data, the coverage build, stray .profraw files, and the reports
+    Clean {
+        #[arg(long, help("List what would be deleted without deleting anything"))]
+        dry_run: bool,
+    },
+    /// Print the totals of the runs recorded with --record-history, oldest first
+    Trend {
+        #[arg(long, value_name = "N", help("Only show the last N runs"))]
+        last: Option<usize>,
+    },
+    /// Run the tests and list the least covered files, with how many lines each leaves uncovered
+    Worst {
+        #[arg(
+            short = 'n',
+            long,
+            value_name = "N",
+            default_value_t = 10,
+            help("How many files to list")
+        )]
+        count: usize,
+    },
+    /// Run the tests and print the line coverage totalled up by directory, like src/ and
+    /// src/parser/, as a tree
+    Tree {
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 2,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+            help(
+                "How many levels of directories and files to show. Deeper ones still count towards the directories above them"
+            )
+        )]
+        depth: usize,
+    },
+    /// Run the tests and print the line coverage of the files each owner in CODEOWNERS owns
+    Owners,
+    /// Run the tests and count the uncovered lines by who last changed them in git, to find the
+    /// changes that brought in untested code
+    Blame {
+        #[arg(
+            long,
+            value_enum,
+            default_value = "author",
+            help("Count them by author, or by commit with the newest first")
+        )]
+        by: BlameBy,
+    },
+    /// Run the tests and print every file's uncovered lines, like `src/lib.rs: 10-14, 27`
+    Uncovered {
+        #[arg(long, help("Print JSON, with the start and end line of every range"))]
+        json: bool,
+    },
+    /// Run the tests and print a source file with each line's hit count, uncovered lines
+    /// highlighted
+    Show {
+        #[arg(value_name = "FILE", help("The file, relative to the project dir"))]
+        file: PathBuf,
+    },
+    /// Run the tests and serve the HTML report over HTTP, rerunning them on demand
+    Serve {
+        #[arg(long, default_value_t = 8080, help("Port to listen on"))]
+        port: u16,
+        #[arg(
+            long,
+            default_value = "127.0.0.1",
+            help("Address to listen on. Use 0.0.0.0 to accept connections from other machines")
+        )]
+        host: String,
+    },
+    /// Run the tests and browse the files and their annotated source in the terminal
+    #[cfg(feature = "tui")]
+    Tui,
+    /// Print the completion script for a shell, to source from its startup file
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
 }
 
-fn main() {
-    let args = Args::parse();
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  All coverage requirements met
+  1  A coverage requirement wasn't met
+  2  A tool is missing or failed, or the config is invalid
+  3  The test suite failed
+  4  Output from a tool couldn't be parsed
+  5  The test suite failed and a coverage requirement wasn't met, with --keep-going";
 
-    let min_line_coverage = args.min_line_coverage.unwrap_or(DEFAULT_MIN_LINE_COVERAGE);
-    let min_branch_coverage = args
-        .min_branch_coverage
-        .unwrap_or(DEFAULT_MIN_BRANCH_COVERAGE);
-    let project_dir = args.project_dir.unwrap_or(".".to_string());
+impl Args {
+    /// The positional project dir, or the one --manifest-path is in
+    fn project_dir(&self) -> PathBuf {
+        match (&self.project_dir, &self.manifest_path) {
+            (Some(project_dir), _) => project_dir.clone(),
+            (None, Some(manifest_path)) => manifest_path
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .map_or(PathBuf::from("."), Path::to_path_buf),
+            (None, None) => PathBuf::from("."),
+        }
+    }
 
-    run_test_with_profiling(&project_dir);
+    fn settings(&self) -> Settings {
+        Settings {
+            min_line_coverage: self.min_line_coverage,
+            min_branch_coverage: self.min_branch_coverage,
+            min_function_coverage: self.min_function_coverage,
+            min_region_coverage: self.min_region_coverage,
+            min_patch_coverage: self.min_patch_coverage,
+            min_file_line_coverage: self.min_file_line_coverage,
+            min_file_branch_coverage: self.min_file_branch_coverage,
+            package_thresholds: self.package_thresholds.iter().cloned().collect(),
+            // Only in the config file, where a table reads better than flags
+            path_thresholds: BTreeMap::new(),
+            owner_thresholds: BTreeMap::new(),
+            warn_line_coverage: self.warn_line_coverage,
+            warn_branch_coverage: self.warn_branch_coverage,
+            warn_function_coverage: self.warn_function_coverage,
+            warn_region_coverage: self.warn_region_coverage,
+            percent: self.percent.then_some(true),
+            tolerance: self.tolerance,
+            max_coverage_off_lines: self.max_coverage_off_lines,
+            ignore: self.ignore.clone(),
+            include: self.include.clone(),
+            path_equivalence: self.path_equivalence.clone(),
+            exclude_tests: self.exclude_tests.then_some(true),
+            include_generated: self.include_generated.then_some(true),
+            profraw_dirs: self.profraw_dirs.clone(),
+            profile_file: self.profile_file.clone(),
+            keep_profraw: self.keep_profraw.then_some(true),
+            merge_with: self.merge_with.clone(),
+            objects: self.objects.clone(),
+            output_dir: self.output_dir.clone(),
+            replace_rustflags: self.replace_rustflags.then_some(true),
+            separate_target_dir: self.separate_target_dir,
+            workspace: self.workspace.then_some(true),
+            packages: self.packages.clone(),
+            exclude: self.exclude.clone(),
+            lib: self.lib.then_some(true),
+            bins: self.bins.then_some(true),
+            tests: self.tests.then_some(true),
+            test: self.test.clone(),
+            changed_only: self.changed_only.then_some(true),
+            features: self.features.clone(),
+            feature_matrix: self.feature_matrix.clone(),
+            all_features: self.all_features.then_some(true),
+            no_default_features: self.no_default_features.then_some(true),
+            release: self.release.then_some(true),
+            target: self.target.clone(),
+            target_runner: self.target_runner.clone(),
+            runner: self.runner,
+            test_command: self.test_command.clone(),
+            keep_going: self.keep_going.then_some(true),
+            filter: self.filter.clone(),
+            test_args: self.test_args.clone(),
+            doctests: self.doctests.then_some(true),
+            branch: self.branch.then_some(true),
+            strict_branch: self.strict_branch.then_some(true),
+            diff_base: self.diff_base.clone(),
+            show_uncovered_functions: self.show_uncovered_functions.then_some(true),
+            format: self.format,
+            export_strategy: self.export_strategy,
+            progress: self.no_progress.then_some(false),
+            quiet: self.quiet.then_some(true),
+            annotate: self.annotate,
+            upload: self.upload,
+            // An empty path means the default in the output dir
+            lcov: self.lcov.clone().map(Option::unwrap_or_default),
+            html: self.html.clone().map(Option::unwrap_or_default),
+            badge: self.badge.clone(),
+            save_baseline: self.save_baseline.clone(),
+            baseline: self.baseline.clone(),
+            max_coverage_drop: self.max_coverage_drop,
+            ratchet: self.ratchet.then_some(true),
+            record_history: self.record_history.then_some(true),
+            history_file: self.history_file.clone(),
+            manifest_path: self.manifest_path.clone(),
+            tools: ToolSettings {
+                cargo: None,
+                llvm_profdata: self.llvm_profdata.clone(),
+                llvm_cov: self.llvm_cov.clone(),
+            },
+        }
+    }
+}
 
-    generate_profdata(&project_dir);
+fn parse_package_threshold(value: &str) -> Result<(String, PackageMinimums), String> {
+    let (package, mins) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected PACKAGE=LINE[,BRANCH], got {}", value))?;
+    let (line, branch) = match mins.split_once(',') {
+        Some((line, branch)) => (line, Some(branch)),
+        None => (mins, None),
+    };
+    let parse = |v: &str| {
+        v.parse::<f32>()
+            .map_err(|e| format!("invalid coverage {}: {}", v, e))
+    };
+    Ok((
+        package.to_string(),
+        PackageMinimums {
+            line: parse(line)?,
+            branch: branch.map(parse).transpose()?,
+        },
+    ))
+}
 
-    let objects = get_objects(&project_dir);
+/// What `cargo simple-cov` runs `cargo-simple-cov` with before the arguments it was given
+const CARGO_SUBCOMMAND: &str = "simple-cov";
+/// `simple-rust-cov` or `cargo-simple-cov`, whichever of the two binaries this is
+const BIN_NAME: &str = env!("CARGO_BIN_NAME");
 
-    let report = execute_report(&project_dir, &objects);
+fn main() {
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    let cargo_subcommand = argv.get(1).is_some_and(|a| a == CARGO_SUBCOMMAND)
+        && Path::new(&argv[0])
+            .file_stem()
+            .is_some_and(|s| s == "cargo-simple-cov");
+    let mut command = Args::command();
+    if cargo_subcommand {
+        argv.remove(1);
+        command = command.bin_name("cargo simple-cov");
+    }
+    let matches = command.clone().get_matches_from(argv);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if args.watch && args.command.is_some() {
+        command
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--watch can't be combined with a command",
+            )
+            .exit();
+    }
+    if args.print_schema {
+        print!("{}", formats::json::SCHEMA);
+        return;
+    }
+    simple_rust_cov::set_verbose(args.verbose);
+    if cargo_subcommand && args.project_dir.is_none() && args.manifest_path.is_none() {
+        args.project_dir = Some(cargo_project_dir());
+    }
+    if let Some(level) = args.log_level {
+        tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_writer(|| progress::Stderr)
+            .init();
+    }
+    let mut result = dispatch(&args);
+    if let Err(CovError::Spawn { tool, .. }) = &result
+        && tools::installable(tool)
+        && offer_install(&args, tool)
+    {
+        result = dispatch(&args);
+    }
+    match result {
+        Ok(EXIT_OK) => {}
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(e.exit_code())
+        }
+    }
+}
 
-    if report.line_coverage < min_line_coverage {
-        eprintln!(
-            "Line coverage requirement not met ({} < {})",
-            &report.line_coverage, &min_line_coverage
-        );
-        process::exit(1)
+/// The project `cargo simple-cov` is for without a project dir: `CARGO_MANIFEST_DIR` if it's set,
+/// or else the package cargo's own commands would act on here, from `cargo locate-project`
+fn cargo_project_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("CARGO_MANIFEST_DIR") {
+        return dir.into();
     }
-    if report.branch_coverage < min_branch_coverage {
-        eprintln!(
-            "Branch coverage requirement not met ({} < {})",
-            &report.line_coverage, &min_line_coverage
+    // cargo sets CARGO to itself for the subcommands it runs
+    let cargo = env::var("CARGO").unwrap_or("cargo".to_string());
+    workspace::locate_project(&cargo, Path::new("."))
+        .ok()
+        .and_then(|manifest| manifest.parent().map(Path::to_path_buf))
+        .unwrap_or(PathBuf::from("."))
+}
+
+/// Writes the completion script for `shell`, generated from the options and commands here, for
+/// the binary it's run as. Shells complete a command by its first word, which for
+/// `cargo simple-cov` is cargo, so that spelling is left to cargo's own completions.
+fn write_completions(shell: Shell, out: &mut dyn Write) {
+    // zsh's script names its states after the command as well
+    let mut command = Args::command().name(BIN_NAME);
+    clap_complete::generate(shell, &mut command, BIN_NAME, out);
+}
+
+/// Runs the command, returning the exit code
+fn dispatch(args: &Args) -> simple_rust_cov::Result<i32> {
+    match &args.command {
+        None if args.watch => watch(args),
+        None => run(args, Source::Tests, true),
+        Some(Command::Run) => collect(args),
+        Some(Command::Merge) => run(args, Source::Profraw, true),
+        Some(Command::Report) => run(args, Source::LastRun, false),
+        Some(Command::Check) => run(args, Source::LastRun, true),
+        Some(Command::Html) => html(args),
+        Some(Command::Doctor) => doctor(args),
+        Some(Command::Init {
+            force,
+            github_actions,
+        }) => init(args, *force, *github_actions),
+        Some(Command::Clean { dry_run }) => clean(args, *dry_run),
+        Some(Command::Trend { last }) => trend(args, *last),
+        Some(Command::Worst { count }) => worst(args, *count),
+        Some(Command::Tree { depth }) => tree(args, *depth),
+        Some(Command::Owners) => owners(args),
+        Some(Command::Blame { by }) => blame(args, *by),
+        Some(Command::Uncovered { json }) => uncovered(args, *json),
+        Some(Command::Show { file }) => show(args, file),
+        Some(Command::Serve { port, host }) => serve(args, host, *port),
+        #[cfg(feature = "tui")]
+        Some(Command::Tui) => tui(args),
+        Some(Command::Completions { shell }) => {
+            write_completions(*shell, &mut io::stdout());
+            Ok(EXIT_OK)
+        }
+    }
+}
+
+/// Installs the llvm tools after a missing one stopped the command, with --yes or when the
+/// user agrees to it. Returns whether they were installed, so the command can be run again.
+fn offer_install(args: &Args, tool: &str) -> bool {
+    let command = tools::INSTALL_COMMAND.join(" ");
+    if !args.yes {
+        if !io::stdin().is_terminal() {
+            return false;
+        }
+        eprint!(
+            "{} isn't installed. Run `{}` to install it? [y/N] ",
+            tool, command
         );
-        process::exit(1)
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+            return false;
+        }
     }
-    println!("SUCCESS - All coverage requirements met");
+    eprintln!("Running `{}`", command);
+    let project_dir = args.project_dir();
+    match tools::install(&project_dir) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            false
+        }
+    }
+}
+
+/// Where the coverage [`run`] reports on comes from
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    /// Running the tests
+    Tests,
+    /// What the last run collected
+    LastRun,
+    /// `.profraw` files that are already there
+    Profraw,
 }
 
-fn run_test_with_profiling(project_dir: &String) {
-    let cmd = Command::new("cargo")
-        .arg("test")
-        .env("RUSTFLAGS", "-C instrument-coverage")
-        .current_dir(project_dir)
-        .output()
-        .expect("failed to run cargo test");
+/// Gets the coverage from `source` and prints the report. Without `enforce`, unmet requirements
+/// are only warnings, like with `--no-fail`. Returns the exit code, see [`Report::exit_code`].
+/// The other commands return one too.
+fn run(args: &Args, source: Source, enforce: bool) -> simple_rust_cov::Result<i32> {
+    let no_fail = args.no_fail || !enforce;
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let project_dir = config.project_dir.as_path();
+
+    if args.dry_run {
+        print_plan(&match source {
+            Source::Tests => plan::run_coverage(&config),
+            Source::LastRun => plan::check_coverage(&config),
+            Source::Profraw => plan::merge_coverage(&config),
+        });
+        return Ok(EXIT_OK);
+    }
+    let report = match source {
+        Source::Tests => simple_rust_cov::run_coverage(&config)?,
+        Source::LastRun => simple_rust_cov::check_coverage(&config)?,
+        Source::Profraw => simple_rust_cov::merge_coverage(&config)?,
+    };
+    let root = simple_rust_cov::project_root(&config)?;
+
+    match config.format {
+        Format::Text if config.quiet => print_totals(&report),
+        Format::Text => {
+            print!("{}", report.table.as_deref().unwrap_or_default());
+            print_coverage_off(&report, &root);
+            print_patch_coverage(&report);
+            print_path_thresholds(&report);
+            print_uncovered_functions(&report, &root);
+            if !report.reused.is_empty() {
+                println!(
+                    "Reused the coverage of unchanged packages: {}\n",
+                    report.reused.join(", ")
+                );
+            }
+        }
+        Format::Json => {
+            let json = formats::json::render(&report);
+            let path = config.output_dir.join(JSON_FILE);
+            fs::write(&path, &json).map_err(|e| CovError::io(&path, e))?;
+            println!("{}", json);
+        }
+        Format::Cobertura => print!("{}", formats::cobertura::render(&report.data, &root)),
+        Format::Sonarqube => print!("{}", formats::sonarqube::render(&report.data, &root)),
+    }
+
+    if let Some(Annotate::Github) = config.annotate {
+        print_github_annotations(project_dir, &report)?;
+    }
+
+    if let Some(service) = config.upload {
+        let ci = upload::CiInfo::detect(project_dir)?;
+        match service {
+            Upload::Codecov => {
+                upload::codecov::upload(&formats::cobertura::render(&report.data, &root), &ci)?;
+            }
+            Upload::Coveralls => {
+                upload::coveralls::upload(&report.data, &git::toplevel(project_dir)?, &ci)?;
+            }
+        }
+    }
+
+    // GitHub Actions sets this for every step, so the summary shows up without any setup
+    if let Ok(step_summary) = env::var("GITHUB_STEP_SUMMARY") {
+        write_step_summary(&report, &root, &step_summary)?;
+    }
+
+    if args.open {
+        match &config.html {
+            Some(html_dir) => open_report(html_dir),
+            None => eprintln!("warning: --open does nothing without --html"),
+        }
+    }
 
-    panic_on_fail("cargo test failed", &cmd);
+    if config.format != Format::Json {
+        for warning in report.warnings.iter().filter(|_| !config.quiet) {
+            eprintln!("warning: {}", warning);
+        }
+        for failure in &report.soft_failures {
+            eprintln!("warning: {}", failure);
+        }
+        for failure in &report.test_failures {
+            eprintln!("error: {}", failure);
+        }
+        for failure in &report.failures {
+            if no_fail {
+                eprintln!("warning: {}", failure);
+            } else {
+                eprintln!("{}", failure);
+            }
+        }
+    }
+    if report.passed() && config.format == Format::Text {
+        println!("SUCCESS - All coverage requirements met");
+    }
+    if config.format == Format::Text && !config.quiet {
+        print_timings(&report.timings);
+    }
+    if no_fail {
+        return Ok(EXIT_OK);
+    }
+    Ok(report.exit_code())
 }
 
-fn generate_profdata(project_dir: &String) {
-    clear_profdata(project_dir);
-    let cmd = Command::new("sh")
-        .arg("-c")
-        .arg(format!(
-            "rust-profdata merge -sparse default_*.profraw -o {}",
-            PathBuf::from_iter([project_dir.as_str(), PROFDATA_PATH])
-                .to_str()
-                .unwrap()
-        ))
-        .current_dir(project_dir)
-        .output()
-        .expect("failed to run rust-profdata");
-    panic_on_fail("rust-profdata failed", &cmd);
-    clear_profraw(project_dir);
+/// The `run` command, which leaves the profile and the list of binaries for the others
+fn collect(args: &Args) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    if args.dry_run {
+        print_plan(&plan::collect(&config));
+        return Ok(EXIT_OK);
+    }
+    let collected = simple_rust_cov::collect(&config)?;
+    for failure in &collected.test_failures {
+        eprintln!("error: {}", failure);
+    }
+    println!(
+        "Collected the coverage of {} binaries in {}",
+        collected.objects.len(),
+        profdata::profdata_path(&config).display()
+    );
+    if !config.quiet {
+        print_timings(&progress::take_timings());
+    }
+    if !collected.test_failures.is_empty() {
+        return Ok(EXIT_TESTS);
+    }
+    Ok(EXIT_OK)
 }
 
-fn clear_profdata(project_dir: &String) {
-    let profdata_dir = PathBuf::from_iter([project_dir.as_str(), PROFDATA_DIR]);
-    if fs::exists(&profdata_dir).unwrap() {
-        fs::remove_dir_all(&profdata_dir).expect("failed to clean profdata dir");
+/// Writes just the HTML report for the last run, skipping the exports the others need
+fn html(args: &Args) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let html_dir = config
+        .html
+        .clone()
+        .unwrap_or_else(|| default_html_dir(&config));
+    let inputs = simple_rust_cov::inputs(&config, &Collected::load(&config)?)?;
+    report::show_html(&config, &inputs, &html_dir)?;
+    println!("Wrote the HTML report to {}", html_dir.display());
+    if args.open {
+        open_report(&html_dir);
     }
-    fs::create_dir(&profdata_dir).unwrap();
+    Ok(EXIT_OK)
 }
 
-fn clear_profraw(project_dir: &String) {
-    let files = fs::read_dir(project_dir)
-        .expect(format!("unable to list files in {}", project_dir).as_str());
-    for file in files {
-        let file = file.expect(format!("unable to stat file in {}", project_dir).as_str());
-        if !file.file_type().unwrap().is_file() {
-            continue;
+/// Prints the result of every check. A config that doesn't resolve is one of the problems it
+/// reports, in which case the tools are checked under their default names.
+fn doctor(args: &Args) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let mut failed = false;
+    let tools = match Config::resolve(project_dir.clone(), args.config.clone(), args.settings()) {
+        Ok(config) => {
+            println!("ok    config: {}", config.project_dir.display());
+            config.tools
         }
-        let file_name = file.file_name().into_string().unwrap();
-        if file_name.starts_with("default") && file_name.ends_with(".profraw") {
-            fs::remove_file(PathBuf::from_iter([
-                project_dir.as_str(),
-                file_name.as_str(),
-            ]))
-            .unwrap();
-        }
-    }
-}
-
-fn get_objects(project_dir: &String) -> Vec<String> {
-    let cmd = Command::new("cargo")
-        .arg("test")
-        .arg("--no-run")
-        .arg("--message-format=json")
-        .env("RUSTFLAGS", "-C instrument-coverage")
-        .current_dir(project_dir)
-        .output()
-        .unwrap_or_else(|e| panic!("failed to execute cargo command: {:?}", e));
-
-    panic_on_fail("cargo command failed", &cmd);
-
-    let stdout = String::from_utf8(cmd.stdout).expect("Cargo output was not UTF-8");
-    let mut objects: Vec<String> = vec![];
-    for line in stdout.lines() {
-        let target: Value = serde_json::from_str(line).expect("Unable to parse output as JSON");
-        if let Some(test) = target["profile"]["test"].as_bool()
-            && test
-        {
-            let object_paths = target["filenames"]
-                .as_array()
-                .expect("filenames was not an array");
-            for object_path in object_paths {
-                objects.push(
-                    object_path
-                        .as_str()
-                        .expect("filename was not a string")
-                        .to_string(),
-                );
+        Err(e) => {
+            println!("FAIL  config: {}", e.to_string().replace('\n', "\n      "));
+            println!("      Fix the config, or run it in a cargo project");
+            failed = true;
+            tools::locate(args.settings().tools, &project_dir)
+        }
+    };
+    for check in doctor::checks(&project_dir, &tools) {
+        failed |= check.failed();
+        match check.outcome {
+            doctor::Outcome::Ok(found) => println!("ok    {}: {}", check.name, found),
+            doctor::Outcome::Warning { problem, fix } => {
+                println!("warn  {}: {}\n      {}", check.name, problem, fix)
+            }
+            doctor::Outcome::Failed { problem, fix } => {
+                println!("FAIL  {}: {}\n      {}", check.name, problem, fix)
             }
         }
     }
-    objects
+    Ok(if failed { EXIT_SETUP } else { EXIT_OK })
 }
 
-fn execute_report(project_dir: &String, objects: &Vec<String>) -> Report {
-    let mut cmd = Command::new("rust-cov");
-    cmd.arg("report")
-        .arg("--use-color")
-        .arg("--show-region-summary=false")
-        .arg("--ignore-filename-regex='/.cargo/registry'")
-        .arg("-instr-profile")
-        .arg(PROFDATA_PATH);
+/// Sets up the project dir without resolving a config, since there may not be a valid one yet
+fn init(args: &Args, force: bool, github_actions: bool) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config_path = project_dir.join(CONFIG_FILE);
+    if init::write_config(&project_dir, force)? {
+        println!("Wrote {}", config_path.display());
+    } else {
+        println!(
+            "{} already exists, pass --force to replace it",
+            config_path.display()
+        );
+    }
+    let added = init::update_gitignore(&project_dir)?;
+    if !added.is_empty() {
+        println!("Added {} to .gitignore", added.join(" and "));
+    }
+    if github_actions {
+        print!("\n{}", init::GITHUB_WORKFLOW);
+    }
+    Ok(EXIT_OK)
+}
 
-    for object in objects {
-        cmd.arg("--object").arg(object.as_str());
+fn clean(args: &Args, dry_run: bool) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let artifacts = clean::artifacts(&config)?;
+    if artifacts.is_empty() {
+        println!("Nothing to clean");
     }
+    for path in &artifacts {
+        let action = if dry_run { "Would remove" } else { "Removing" };
+        println!("{} {}", action, path.display());
+    }
+    if !dry_run {
+        clean::remove(&artifacts)?;
+    }
+    Ok(EXIT_OK)
+}
 
-    let output = cmd
-        .current_dir(project_dir)
-        .output()
-        .unwrap_or_else(|e| panic!("failed to execute rust-cov: {:?}", e));
+fn open_report(html_dir: &Path) {
+    let index = html_dir.join("index.html");
+    if let Err(e) = open_in_browser(&index) {
+        eprintln!("warning: couldn't open {}: {}", index.display(), e);
+    }
+}
 
-    panic_on_fail("rust-cov failed", &output);
+/// Runs coverage every time the source changes, until killed. A failed run is reported and
+/// waited out like any other, since the next change might well fix it.
+fn watch(args: &Args) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir.clone(), args.config.clone(), args.settings())?;
+    let watcher = simple_rust_cov::watch::Watcher::new(&simple_rust_cov::project_root(&config)?)?;
 
-    let stdout = String::from_utf8(output.stdout).unwrap();
-    let stderr = String::from_utf8(output.stderr).unwrap();
+    let mut previous: Option<Summary> = None;
+    loop {
+        // Resolved again every time, to pick up changes to the config file
+        let report = Config::resolve(project_dir.clone(), args.config.clone(), args.settings())
+            .and_then(|config| simple_rust_cov::run_coverage(&config));
+        match report {
+            Ok(report) => {
+                print_watch_summary(&report, previous.as_ref());
+                previous = Some(report.data.totals.clone());
+            }
+            Err(e) => eprintln!("error: {}", e),
+        }
+        println!("Watching for changes...");
+        let changed = watcher.wait()?;
+        println!(
+            "\nChanged: {}",
+            changed.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+}
+
+/// The totals, with how each moved since the last run, then anything that failed
+fn print_watch_summary(report: &Report, previous: Option<&Summary>) {
+    let totals = &report.data.totals;
+    let mut metrics = vec![
+        ("Lines", &totals.lines, previous.map(|p| &p.lines)),
+        (
+            "Functions",
+            &totals.functions,
+            previous.map(|p| &p.functions),
+        ),
+        ("Regions", &totals.regions, previous.map(|p| &p.regions)),
+    ];
+    if report.has_branch_data() {
+        metrics.push(("Branches", &totals.branches, previous.map(|p| &p.branches)));
+    }
+    for (name, metric, previous) in metrics {
+        let change = match previous {
+            Some(previous) if metric.percent != previous.percent => {
+                format!("  ({:+.2})", metric.percent - previous.percent)
+            }
+            _ => String::new(),
+        };
+        println!("{:<10} {:>7.2}%{}", name, metric.percent, change);
+    }
+    for warning in report.warnings.iter().chain(&report.soft_failures) {
+        eprintln!("warning: {}", warning);
+    }
+    for failure in &report.test_failures {
+        eprintln!("error: {}", failure);
+    }
+    for failure in &report.failures {
+        eprintln!("{}", failure);
+    }
+    if report.passed() {
+        println!("SUCCESS - All coverage requirements met");
+    }
+}
+
+/// Opens a file with `$BROWSER`, or the system's default handler, like `cargo doc --open`
+fn open_in_browser(path: &Path) -> std::io::Result<()> {
+    let mut cmd = match env::var_os("BROWSER") {
+        Some(browser) => process::Command::new(browser),
+        None if cfg!(target_os = "macos") => process::Command::new("open"),
+        None if cfg!(windows) => {
+            let mut cmd = process::Command::new("cmd");
+            cmd.args(["/C", "start", ""]);
+            cmd
+        }
+        None => process::Command::new("xdg-open"),
+    };
+    let status = cmd.arg(path).status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            cmd.get_program().to_string_lossy(),
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Prints the recorded history without running anything
+fn trend(args: &Args, last: Option<usize>) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let entries = history::load(&config.history_file)?;
+    let skip = last.map_or(0, |last| entries.len().saturating_sub(last));
+    print!("{}", history::trend(&entries[skip..]));
+    Ok(EXIT_OK)
+}
+
+/// Prints the least covered files instead of the usual report. It's for finding what to test
+/// next, so it doesn't fail when a requirement isn't met, and neither do the other commands
+/// below that replace the report with another view of it.
+fn worst(args: &Args, count: usize) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let report = simple_rust_cov::run_coverage(&config)?;
+    let root = simple_rust_cov::project_root(&config)?;
+
+    let files = formats::least_covered(&report.data);
+    if files.is_empty() {
+        println!("Every line is covered");
+        return Ok(EXIT_OK);
+    }
+    println!("{:>8}  {:>9}  File", "Lines", "Uncovered");
+    for file in files.iter().take(count) {
+        let lines = &file.summary.lines;
+        println!(
+            "{:>7.2}%  {:>9}  {}",
+            lines.percent,
+            lines.count - lines.covered,
+            formats::relative_path(&file.filename, &root)
+        );
+    }
+    Ok(EXIT_OK)
+}
+
+/// Prints the coverage by directory, down to `depth` levels under the project root, instead of
+/// the usual report
+fn tree(args: &Args, depth: usize) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let report = simple_rust_cov::run_coverage(&config)?;
+    let root = simple_rust_cov::project_root(&config)?;
+
+    println!("{:>8}  {:>9}  Path", "Lines", "Uncovered");
+    for node in formats::tree(&report.data, &root, depth) {
+        // Just the last part, indented under the directory it's in
+        let name = node.path.trim_end_matches('/');
+        let name = &node.path[name.rfind('/').map_or(0, |i| i + 1)..];
+        println!(
+            "{:>7.2}%  {:>9}  {:indent$}{}",
+            node.lines.percent,
+            node.lines.count - node.lines.covered,
+            "",
+            name,
+            indent = 2 * (node.depth - 1)
+        );
+    }
+    Ok(EXIT_OK)
+}
+
+/// Prints the coverage by CODEOWNERS owner instead of the usual report. Without a CODEOWNERS
+/// file there's nothing to group by, which is a setup error before anything runs.
+fn owners(args: &Args) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let root = codeowners::repository_root(&config)?;
+    let Some(owners) = CodeOwners::find(&root)? else {
+        eprintln!(
+            "error: there's no CODEOWNERS in {}, .github/ or docs/",
+            root.display()
+        );
+        return Ok(EXIT_SETUP);
+    };
+    let report = simple_rust_cov::run_coverage(&config)?;
+
+    println!("{:>8}  {:>9}  {:>5}  Owner", "Lines", "Uncovered", "Files");
+    // Nobody comes first in the map, but reads better last
+    let mut by_owner: Vec<_> = codeowners::by_owner(&report.data, &owners, &root)
+        .into_iter()
+        .collect();
+    if by_owner.first().is_some_and(|(owner, _)| owner.is_none()) {
+        by_owner.rotate_left(1);
+    }
+    for (owner, coverage) in by_owner {
+        println!(
+            "{:>7.2}%  {:>9}  {:>5}  {}",
+            coverage.lines.percent,
+            coverage.lines.count - coverage.lines.covered,
+            coverage.files,
+            owner.as_deref().unwrap_or("(no owner)")
+        );
+    }
+    Ok(EXIT_OK)
+}
 
-    println!("{}", &stdout);
-    println!("{}", &stderr);
+/// Prints the uncovered lines by author or commit instead of the usual report, from `git blame`
+/// of each file with any
+fn blame(args: &Args, by: BlameBy) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let mut config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    config.line_data = true;
+    let report = simple_rust_cov::run_coverage(&config)?;
 
-    let coverage_line = find_coverage_line(&stdout);
-    let coverage_line_parts: Vec<&str> = coverage_line.split_whitespace().collect();
-    let line_coverage_str = coverage_line_parts[6].to_string();
-    let branch_coverage_str = coverage_line_parts[9].to_string();
+    let blamed = blame::blame(&report.data, &config.project_dir)?;
+    if blamed.is_empty() {
+        println!("Every line is covered");
+        return Ok(EXIT_OK);
+    }
+    print!("{}", blame::render(&blamed, by));
+    Ok(EXIT_OK)
+}
 
-    Report {
-        line_coverage: coverage_pct_from_str(&line_coverage_str),
-        branch_coverage: coverage_pct_from_str(&branch_coverage_str),
+fn print_uncovered_functions(report: &Report, root: &Path) {
+    let Some(functions) = &report.uncovered_functions else {
+        return;
+    };
+    if functions.is_empty() {
+        println!("Every function ran\n");
+        return;
     }
+    println!("Functions that never ran:");
+    for (filename, functions) in functions {
+        println!("  {}", formats::relative_path(filename, root));
+        for function in functions {
+            println!("    {:>5}  {}", function.line, function.name);
+        }
+    }
+    println!();
 }
 
-fn find_coverage_line(stdout: &String) -> String {
-    for line in stdout.lines() {
-        if line.contains("TOTAL") {
-            return line.to_string();
+/// Prints the uncovered lines instead of the usual report, as ranges per file or as JSON, for
+/// scripts and quick checks
+fn uncovered(args: &Args, json: bool) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let mut config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    config.line_data = true;
+    let report = simple_rust_cov::run_coverage(&config)?;
+    let root = simple_rust_cov::project_root(&config)?;
+
+    let mut files: Vec<(String, Vec<(u64, u64)>)> = report
+        .data
+        .files
+        .iter()
+        .map(|f| {
+            (
+                formats::relative_path(&f.filename, &root),
+                formats::line_ranges(f.uncovered_lines()),
+            )
+        })
+        .filter(|(_, ranges)| !ranges.is_empty())
+        .collect();
+    files.sort();
+
+    if json {
+        let files: Vec<_> = files
+            .iter()
+            .map(|(path, ranges)| {
+                let ranges: Vec<_> = ranges
+                    .iter()
+                    .map(|(start, end)| serde_json::json!({ "start": start, "end": end }))
+                    .collect();
+                serde_json::json!({ "path": path, "uncovered": ranges })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&files).expect("unable to serialize uncovered lines")
+        );
+    } else {
+        for (path, ranges) in &files {
+            println!("{}: {}", path, formats::format_line_ranges(ranges));
         }
     }
-    panic!(
-        "couldn't find coverage percentages in rust-cov output: {}",
-        stdout
+    Ok(EXIT_OK)
+}
+
+/// Prints one file annotated by llvm-cov instead of the usual report
+fn show(args: &Args, file: &Path) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let mut config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    config.show_source = Some(file.to_path_buf());
+    let report = simple_rust_cov::run_coverage(&config)?;
+    print!("{}", report.source_view.unwrap_or_default());
+    Ok(EXIT_OK)
+}
+
+/// Serves the HTML report until killed, writing it to `--html` or html in the output dir. A rerun
+/// through the regenerate page that fails is reported to the page rather than stopping the
+/// server.
+fn serve(args: &Args, host: &str, port: u16) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let mut config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    let html_dir = config.html.get_or_insert(default_html_dir(&config)).clone();
+
+    let report = simple_rust_cov::run_coverage(&config)?;
+    println!(
+        "Line coverage is {:.2}%. Serving {} at http://{}:{}/",
+        report.line_coverage * 100.,
+        html_dir.display(),
+        host,
+        port
+    );
+    println!(
+        "Visit {} to rerun the tests, or press Ctrl-C to stop",
+        simple_rust_cov::serve::REGENERATE_PATH
+    );
+    simple_rust_cov::serve::serve(&format!("{}:{}", host, port), &html_dir, || {
+        let report = simple_rust_cov::run_coverage(&config)?;
+        println!(
+            "Regenerated, line coverage is {:.2}%",
+            report.line_coverage * 100.
+        );
+        Ok(())
+    })?;
+    Ok(EXIT_OK)
+}
+
+/// Runs the tests, then browses the files and their uncovered lines in the terminal until it's
+/// closed. It needs line-level data, so that's turned on whatever the config says.
+#[cfg(feature = "tui")]
+fn tui(args: &Args) -> simple_rust_cov::Result<i32> {
+    let project_dir = args.project_dir();
+    let mut config = Config::resolve(project_dir, args.config.clone(), args.settings())?;
+    config.line_data = true;
+    let report = simple_rust_cov::run_coverage(&config)?;
+    let root = simple_rust_cov::project_root(&config)?;
+    simple_rust_cov::tui::browse(&report, &root)?;
+    Ok(EXIT_OK)
+}
+
+/// Lists what `#[coverage(off)]` left out, which the table has no trace of
+fn print_coverage_off(report: &Report, root: &Path) {
+    if report.coverage_off.is_empty() {
+        return;
+    }
+    println!(
+        "Excluded with #[coverage(off)] ({} lines):",
+        report.coverage_off_lines()
     );
+    for (filename, items) in &report.coverage_off {
+        for item in items {
+            println!(
+                "  {}:{} {} ({} lines)",
+                formats::relative_path(filename, root),
+                item.line_start,
+                item.item,
+                item.lines()
+            );
+        }
+    }
+    println!();
 }
 
-fn coverage_pct_from_str(coverage_str: &str) -> f32 {
-    if coverage_str == "-" {
-        return 1.0;
+fn print_plan(steps: &[plan::Step]) {
+    for step in steps {
+        println!("{}", step);
     }
-    let coverage_str = coverage_str
-        .split('%')
-        .next()
-        .expect(format!("unable to parse coverage percent from: {}", coverage_str).as_str());
-    let coverage_pct = coverage_str
-        .parse::<f32>()
-        .expect("coverage string was not a valid float");
-    return coverage_pct / 100.;
 }
 
-fn panic_on_fail(msg: &str, output: &Output) {
-    if !output.status.success() {
-        panic!(
-            "{}:\n{}\n{}",
-            msg,
-            String::from_utf8((&output.stdout).clone()).unwrap(),
-            String::from_utf8((&output.stderr).clone()).unwrap()
+/// How long the run took, and where the time went
+fn print_timings(timings: &[progress::Timing]) {
+    if timings.is_empty() {
+        return;
+    }
+    let phases: Vec<String> = timings
+        .iter()
+        .map(|t| format!("{} {:.1}s", t.phase.to_lowercase(), t.seconds))
+        .collect();
+    println!(
+        "Took {:.1}s: {}",
+        timings.iter().map(|t| t.seconds).sum::<f64>(),
+        phases.join(", ")
+    );
+}
+
+/// The one line `quiet` prints instead of the table
+fn print_totals(report: &Report) {
+    let branches = if report.has_branch_data() {
+        format!("{:.2}%", report.branch_coverage * 100.)
+    } else {
+        "-".to_string()
+    };
+    println!(
+        "Coverage: lines {:.2}%, functions {:.2}%, regions {:.2}%, branches {}",
+        report.line_coverage * 100.,
+        report.function_coverage * 100.,
+        report.region_coverage * 100.,
+        branches
+    );
+}
+
+fn print_patch_coverage(report: &Report) {
+    let Some(patch) = &report.patch else {
+        return;
+    };
+    if patch.lines.count == 0 {
+        println!("No instrumented lines changed since {}\n", patch.base);
+        return;
+    }
+    println!(
+        "Patch coverage against {}: {:.2}% ({} of {} changed lines)",
+        patch.base, patch.lines.percent, patch.lines.covered, patch.lines.count
+    );
+    for file in patch.files.iter().filter(|f| !f.uncovered.is_empty()) {
+        println!("  {} uncovered: {}", file.path, file.uncovered_ranges());
+    }
+    println!();
+}
+
+/// Each path threshold with how its files did, and the files under it
+fn print_path_thresholds(report: &Report) {
+    if report.paths.is_empty() {
+        return;
+    }
+    println!("Path thresholds:");
+    for group in &report.paths {
+        if group.files.is_empty() {
+            println!("  {}: no files", group.pattern);
+            continue;
+        }
+        let mut line = format!(
+            "  {}: lines {:.2}% (minimum {:.2}%)",
+            group.pattern,
+            group.lines.percent,
+            group.min_line_coverage * 100.
         );
+        if let Some(min) = group.min_branch_coverage {
+            line.push_str(&format!(
+                ", branches {:.2}% (minimum {:.2}%)",
+                group.branches.fraction() * 100.,
+                min * 100.
+            ));
+        }
+        let result = if group.failures.is_empty() {
+            "met"
+        } else {
+            "NOT MET"
+        };
+        println!("{} - {}", line, result);
+        for file in &group.files {
+            println!("    {} {:.2}%", file.path, file.lines.percent);
+        }
+    }
+    println!();
+}
+
+fn print_github_annotations(project_dir: &Path, report: &Report) -> simple_rust_cov::Result<()> {
+    let root = git::toplevel(project_dir)?;
+    // Set on pull_request runs. Anywhere else there's no obvious base to diff against, so every
+    // file gets annotated.
+    let changed = env::var("GITHUB_BASE_REF")
+        .ok()
+        .filter(|base| !base.is_empty())
+        .map(|base| git::changed_files(project_dir, &format!("origin/{}", base)))
+        .transpose()?;
+    print!(
+        "{}",
+        formats::github::annotations(&report.data, &root, changed.as_deref())
+    );
+    for failure in &report.soft_failures {
+        println!("::warning title=Coverage::{}", failure);
+    }
+    Ok(())
+}
+
+fn write_step_summary(
+    report: &Report,
+    root: &Path,
+    step_summary: &str,
+) -> simple_rust_cov::Result<()> {
+    let markdown =
+        formats::github::step_summary(&report.data, root, &report.failures, &report.soft_failures);
+
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(step_summary)
+        .and_then(|mut file| file.write_all(markdown.as_bytes()))
+        .map_err(|e| CovError::io(step_summary, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs once for each binary, since both are built from this file
+    #[test]
+    fn completions_cover_commands_and_options() {
+        let other = match BIN_NAME {
+            "cargo-simple-cov" => "simple-rust-cov",
+            _ => "cargo-simple-cov",
+        };
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+            let mut out = vec![];
+            write_completions(shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            assert!(script.contains("worst"), "{shell} completions lack `worst`");
+            assert!(
+                script.contains("min-line-coverage"),
+                "{shell} completions lack --min-line-coverage"
+            );
+            assert!(
+                script.contains(BIN_NAME) && !script.contains(other),
+                "{shell} completions aren't for {BIN_NAME}"
+            );
+        }
     }
 }
@@ -0,0 +1,87 @@
+//! Patch coverage: how much of the code a change adds or modifies is covered, which is what
+//! matters for a pull request regardless of how well the rest of the codebase is tested.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    export::{ExportData, Metric},
+    formats::{format_line_ranges, line_ranges, relative_path},
+};
+
+#[derive(Debug, Serialize)]
+pub struct PatchCoverage {
+    /// What the change was diffed against
+    pub base: String,
+    /// The changed lines that are instrumented, and how many of them ran
+    pub lines: Metric,
+    /// Every changed file with instrumented lines, in path order
+    pub files: Vec<PatchFile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PatchFile {
+    /// Relative to the root of the git checkout, with `/` separators
+    pub path: String,
+    pub lines: Metric,
+    /// Changed lines that never ran
+    pub uncovered: Vec<u64>,
+}
+
+impl PatchFile {
+    /// The uncovered lines with runs of consecutive ones collapsed, like `3, 7-9, 12`
+    pub fn uncovered_ranges(&self) -> String {
+        format_line_ranges(&line_ranges(self.uncovered.iter().copied()))
+    }
+}
+
+impl PatchCoverage {
+    /// Checks the changed lines from [`crate::git::changed_lines`] against the line counts.
+    /// Changed lines that aren't instrumented, like comments or blank lines, don't count, and
+    /// neither do lines that were excluded.
+    pub fn new(
+        base: &str,
+        data: &ExportData,
+        changed: &BTreeMap<String, BTreeSet<u64>>,
+        git_root: &Path,
+    ) -> Self {
+        let mut patch = PatchCoverage {
+            base: base.to_string(),
+            lines: Metric::default(),
+            files: vec![],
+        };
+        for file in &data.files {
+            let path = relative_path(&file.filename, git_root);
+            let Some(changed) = changed.get(&path) else {
+                continue;
+            };
+
+            let lines: Vec<_> = file
+                .line_counts()
+                .into_iter()
+                .filter(|l| changed.contains(&l.line))
+                .collect();
+            if lines.is_empty() {
+                continue;
+            }
+            let uncovered: Vec<u64> = lines
+                .iter()
+                .filter(|l| l.count == 0)
+                .map(|l| l.line)
+                .collect();
+            let metric = Metric::new(lines.len() as u64, (lines.len() - uncovered.len()) as u64);
+            patch.lines.add(&metric);
+            patch.files.push(PatchFile {
+                path,
+                lines: metric,
+                uncovered,
+            });
+        }
+        patch.files.sort_by(|a, b| a.path.cmp(&b.path));
+        patch
+    }
+}
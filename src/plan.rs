@@ -0,0 +1,267 @@
+//! What a run would do, for `--dry-run`: the commands it would run, with the environment they'd
+//! get, and what it would remove and write. Nothing runs, so the binaries the build would list
+//! only appear as placeholders.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    Config, Inputs, MATRIX_DIR, command_line,
+    config::{ExportStrategy, Format, JSON_FILE, Runner, Upload},
+    profdata::{self, Collected, Earlier},
+    ratchet, report, runner,
+};
+
+/// Stands for each binary the build would list
+const BINARY: &str = "BINARY";
+/// Stands for the directory of the package a test binary is from
+const PACKAGE_DIR: &str = "PACKAGE_DIR";
+
+#[derive(Debug)]
+pub enum Step {
+    /// A command, as a line for a POSIX shell, and what the placeholders in it stand for
+    Run {
+        command: String,
+        note: Option<String>,
+    },
+    Remove(PathBuf),
+    Write(PathBuf),
+    Upload(Upload),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Run { command, note } => {
+                write!(f, "Would run {}", command)?;
+                if let Some(note) = note {
+                    write!(f, "\n  {}", note)?;
+                }
+                Ok(())
+            }
+            Step::Remove(path) => write!(f, "Would remove {}", path.display()),
+            Step::Write(path) => write!(f, "Would write {}", path.display()),
+            Step::Upload(Upload::Codecov) => write!(f, "Would upload the coverage to Codecov"),
+            Step::Upload(Upload::Coveralls) => write!(f, "Would upload the coverage to Coveralls"),
+        }
+    }
+}
+
+fn run(cmd: &Command, note: Option<String>) -> Step {
+    Step::Run {
+        command: command_line(cmd),
+        note,
+    }
+}
+
+/// What [`crate::run_coverage`] would do
+pub fn run_coverage(config: &Config) -> Vec<Step> {
+    let mut steps = collect(config);
+    steps.extend(report(config, true));
+    steps.extend(history(config));
+    steps
+}
+
+/// What [`crate::merge_coverage`] would do
+pub fn merge_coverage(config: &Config) -> Vec<Step> {
+    let mut steps = collect_profraw(config);
+    steps.extend(report(config, true));
+    steps.extend(history(config));
+    steps
+}
+
+/// What [`crate::check_coverage`] would do
+pub fn check_coverage(config: &Config) -> Vec<Step> {
+    report(config, false)
+}
+
+/// What [`crate::collect`] would do
+pub fn collect(config: &Config) -> Vec<Step> {
+    let mut steps = clear(config);
+    let mut profiles = vec![];
+    if config.feature_matrix.is_empty() {
+        test_once(config, &mut steps);
+    } else {
+        for (index, features) in config.feature_matrix.iter().enumerate() {
+            let mut run = config.clone();
+            run.features.extend(features.iter().cloned());
+            test_once(&run, &mut steps);
+            let profile = profdata::profiles_dir(config)
+                .join(MATRIX_DIR)
+                .join(format!("{}.profdata", index));
+            steps.push(merge(&run, &[], &profile));
+            profiles.push(profile);
+        }
+    }
+    if config.doctests {
+        let doctest_dir = profdata::profiles_dir(config).join(runner::DOCTEST_DIR);
+        steps.push(run(&runner::doctest_command(config, &doctest_dir), None));
+    }
+    merge_all(config, profiles, &mut steps);
+    steps
+}
+
+/// What [`crate::collect_profraw`] would do
+pub fn collect_profraw(config: &Config) -> Vec<Step> {
+    let mut steps = clear(config);
+    steps.push(run(&runner::build_command(config), None));
+    merge_all(config, vec![], &mut steps);
+    steps
+}
+
+fn clear(config: &Config) -> Vec<Step> {
+    let dir = profdata::profiles_dir(config);
+    if dir.exists() {
+        vec![Step::Remove(dir)]
+    } else {
+        vec![]
+    }
+}
+
+fn test_once(config: &Config, steps: &mut Vec<Step>) {
+    steps.push(run(&runner::build_command(config), None));
+    if let Some(command) = &config.test_command {
+        steps.push(run(&runner::shell_command(config, command), None));
+        return;
+    }
+    let changed = if config.changed_only {
+        " whose package changed since the last --changed-only run"
+    } else {
+        ""
+    };
+    match config.runner {
+        Runner::Cargo => {
+            let cmd = runner::test_command(
+                config,
+                &runner::target_runner(config),
+                Path::new(BINARY),
+                Path::new(PACKAGE_DIR),
+            );
+            let note = format!(
+                "for each test {} the build lists{}, with {} its package's dir",
+                BINARY, changed, PACKAGE_DIR
+            );
+            steps.push(run(&cmd, Some(note)));
+        }
+        Runner::Nextest => {
+            let note = config.changed_only.then(|| {
+                "once for each package that changed since the last --changed-only run, with --package"
+                    .to_string()
+            });
+            steps.push(run(&runner::nextest_command(config, None), note));
+        }
+    }
+}
+
+/// Restores the `merge_with` profiles, merges everything, and saves the binaries
+fn merge_all(config: &Config, mut profiles: Vec<PathBuf>, steps: &mut Vec<Step>) {
+    for index in 0..config.merge_with.len() {
+        let path = Earlier::path(config, index);
+        steps.push(Step::Write(path.clone()));
+        profiles.push(path);
+    }
+    steps.push(merge(config, &profiles, &profdata::profdata_path(config)));
+    steps.push(Step::Write(Collected::path(config)));
+}
+
+fn merge(config: &Config, profiles: &[PathBuf], output: &Path) -> Step {
+    let list = profdata::input_files(output);
+    let cmd = profdata::merge_command(config, Some(&list), profiles, output);
+    let mut dirs: Vec<String> = config
+        .profile_file
+        .parent()
+        .filter(|dir| !dir.to_string_lossy().contains('%'))
+        .into_iter()
+        .chain(config.profraw_dirs.iter().map(PathBuf::as_path))
+        .map(|dir| dir.display().to_string())
+        .collect();
+    dirs.dedup();
+    let afterwards = if config.keep_profraw {
+        let kept_dir = profdata::profiles_dir(config).join(profdata::KEPT_DIR);
+        format!("moved into {}", kept_dir.display())
+    } else {
+        "removed".to_string()
+    };
+    let supplied: Vec<String> = profdata::supplied_dirs(config)
+        .map(|dir| dir.display().to_string())
+        .collect();
+    let except = if supplied.is_empty() {
+        String::new()
+    } else {
+        format!(", except the ones in {}", supplied.join(", "))
+    };
+    let note = format!(
+        "with {} listing the .profraw files in {}, which are {} afterwards{}",
+        list.display(),
+        dirs.join(", "),
+        afterwards,
+        except
+    );
+    run(&cmd, Some(note))
+}
+
+/// What [`crate::report_coverage`] would do, with the binaries the build would list when it's
+/// `built`, or else the ones the last run saved
+fn report(config: &Config, built: bool) -> Vec<Step> {
+    let (mut objects, note) = match Collected::load(config) {
+        Ok(collected) if !built => (collected.objects, None),
+        _ => {
+            let from = if built {
+                "the build lists"
+            } else {
+                "the last run saved"
+            };
+            let note = format!("with {} each binary {}", BINARY, from);
+            (vec![PathBuf::from(BINARY)], Some(note))
+        }
+    };
+    objects.extend(config.objects.iter().cloned());
+    let inputs = Inputs {
+        objects,
+        ..Default::default()
+    };
+
+    let mut steps = vec![];
+    if let Some(lcov) = &config.lcov {
+        steps.push(run(&report::lcov_command(config, &inputs), note.clone()));
+        steps.push(Step::Write(lcov.clone()));
+    }
+    if let Some(html_dir) = &config.html {
+        let cmd = report::html_command(config, &inputs, html_dir);
+        steps.push(run(&cmd, note.clone()));
+        steps.push(Step::Write(html_dir.clone()));
+    }
+    let export = match (config.export_strategy, &note) {
+        (ExportStrategy::Single, _) => note.clone(),
+        (ExportStrategy::Parallel, Some(note)) => Some(format!("{}, one binary at a time", note)),
+        (ExportStrategy::Parallel, None) => Some("one --object at a time".to_string()),
+    };
+    steps.push(run(&report::rust_cov(config, "export", &inputs), export));
+    if let Some(badge) = &config.badge {
+        steps.push(Step::Write(badge.clone()));
+    }
+    match config.format {
+        Format::Text => steps.push(run(&report::report_command(config, &inputs), note)),
+        Format::Json => steps.push(Step::Write(config.output_dir.join(JSON_FILE))),
+        Format::Cobertura | Format::Sonarqube => {}
+    }
+    if let Some(baseline) = &config.save_baseline {
+        steps.push(Step::Write(baseline.clone()));
+    }
+    if config.ratchet
+        && let Ok(root) = crate::project_root(config)
+    {
+        steps.push(Step::Write(root.join(ratchet::LOCK_FILE)));
+    }
+    steps.extend(config.upload.map(Step::Upload));
+    steps
+}
+
+fn history(config: &Config) -> Option<Step> {
+    config
+        .record_history
+        .then(|| Step::Write(config.history_file.clone()))
+}
@@ -0,0 +1,384 @@
+//! Merging raw profiles into the indexed profile llvm-cov reads, and keeping track of what
+//! else it needs to read them.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, CovError, Result, config, exec, tools};
+
+/// Under the output dir, and emptied at the start of every run
+pub const PROFILES_DIR: &str = "profiles";
+/// In the profiles dir
+const PROFDATA_FILE: &str = "unittest.profdata";
+/// In the profiles dir, next to the profile
+const COLLECTED_FILE: &str = "collected.json";
+/// In the profiles dir, for the raw profiles `keep_profraw` keeps
+pub(crate) const KEPT_DIR: &str = "kept";
+/// In the profiles dir, for copies of the `merge_with` profiles
+const EARLIER_DIR: &str = "earlier";
+/// Next to the merged profile, listing the raw profiles going into it
+const INPUT_FILES: &str = "profraw-files.txt";
+
+/// Where a run's profiles go, along with anything else that's only good until the next run
+pub fn profiles_dir(config: &Config) -> PathBuf {
+    config.output_dir.join(PROFILES_DIR)
+}
+
+/// The indexed profile llvm-cov reads
+pub fn profdata_path(config: &Config) -> PathBuf {
+    profiles_dir(config).join(PROFDATA_FILE)
+}
+
+/// What a test run left for llvm-cov to read besides the profile. It's saved next to the profile
+/// so the coverage can be reported on again later without running the tests.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Collected {
+    /// Instrumented binaries, for their coverage mappings
+    pub objects: Vec<PathBuf>,
+    /// `OUT_DIR` of every build script that ran, see [`crate::runner::Build`]
+    pub out_dirs: Vec<PathBuf>,
+    /// Packages whose coverage came from an earlier `changed_only` run
+    #[serde(skip)]
+    pub reused: Vec<String>,
+    /// Tests that failed in a `keep_going` run
+    #[serde(skip)]
+    pub test_failures: Vec<String>,
+}
+
+impl Collected {
+    /// Where it's saved, next to the profile
+    pub fn path(config: &Config) -> PathBuf {
+        profiles_dir(config).join(COLLECTED_FILE)
+    }
+
+    /// Adds the binaries and build dirs of another run that aren't here already. Binaries that
+    /// have been rebuilt or cleaned since have nothing left to map, so those are left out.
+    pub fn add(&mut self, other: &Collected) {
+        for object in &other.objects {
+            if object.exists() && !self.objects.contains(object) {
+                self.objects.push(object.clone());
+            }
+        }
+        for dir in &other.out_dirs {
+            if !self.out_dirs.contains(dir) {
+                self.out_dirs.push(dir.clone());
+            }
+        }
+    }
+
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let path = Collected::path(config);
+        let json = serde_json::to_string_pretty(self).expect("unable to serialize collected data");
+        fs::write(&path, json).map_err(|e| CovError::io(&path, e))
+    }
+
+    /// What the last run saved, as long as its profile is still there too
+    pub fn load(config: &Config) -> Result<Self> {
+        let profile = profdata_path(config);
+        let path = Collected::path(config);
+        if !profile.exists() || !path.exists() {
+            return Err(CovError::NoCoverageData { path: profile });
+        }
+        let json = fs::read_to_string(&path).map_err(|e| CovError::io(&path, e))?;
+        let mut collected: Collected =
+            serde_json::from_str(&json).map_err(|e| CovError::parse(COLLECTED_FILE, e))?;
+        // Coverage collected in a container lists the binaries where they were built
+        for object in &mut collected.objects {
+            if !object.exists()
+                && let Some(remapped) = config::remap_path(&config.path_equivalence, object)
+            {
+                *object = remapped;
+            }
+        }
+        Ok(collected)
+    }
+}
+
+/// A `merge_with` profile from another run. It's read before the profiles dir is emptied, so it
+/// can be the last run's own profile.
+#[derive(Debug)]
+pub struct Earlier {
+    profile: Vec<u8>,
+    /// What the run saved next to the profile, if it did, so its binaries can be read too
+    pub collected: Option<Collected>,
+}
+
+impl Earlier {
+    /// Where [`Earlier::restore`] puts the `index`th one
+    pub fn path(config: &Config, index: usize) -> PathBuf {
+        profiles_dir(config)
+            .join(EARLIER_DIR)
+            .join(format!("{}.profdata", index))
+    }
+
+    pub fn read_all(config: &Config) -> Result<Vec<Earlier>> {
+        config
+            .merge_with
+            .iter()
+            .map(|path| {
+                let profile = fs::read(path).map_err(|e| CovError::io(path, e))?;
+                let collected_path = path.with_file_name(COLLECTED_FILE);
+                let collected = match fs::read_to_string(&collected_path) {
+                    Ok(json) => Some(
+                        serde_json::from_str(&json)
+                            .map_err(|e| CovError::parse(COLLECTED_FILE, e))?,
+                    ),
+                    Err(_) => None,
+                };
+                Ok(Earlier { profile, collected })
+            })
+            .collect()
+    }
+
+    /// Writes the profile back into the emptied profiles dir, returning where it went
+    pub fn restore(&self, config: &Config, index: usize) -> Result<PathBuf> {
+        let path = Earlier::path(config, index);
+        let dir = path.parent().expect("the earlier profiles are in a dir");
+        fs::create_dir_all(dir).map_err(|e| CovError::io(dir, e))?;
+        fs::write(&path, &self.profile).map_err(|e| CovError::io(&path, e))?;
+        Ok(path)
+    }
+}
+
+/// Merges the raw profiles from the test run, along with the already indexed `profiles`, into
+/// the profile llvm-cov reads
+pub fn generate_profdata(config: &Config, profiles: &[PathBuf]) -> Result<()> {
+    merge(config, profiles, &profdata_path(config))
+}
+
+/// Merges the raw profiles from whatever tests ran since the last merge, and the already indexed
+/// `profiles`, into `output`. Looks in the `profraw_dirs` and wherever `profile_file` points.
+pub fn merge(config: &Config, profiles: &[PathBuf], output: &Path) -> Result<()> {
+    let mut dirs = config.profraw_dirs.clone();
+    // A pattern can put %p or %m in a directory name, in which case the dirs have to cover it
+    if let Some(dir) = config.profile_file.parent()
+        && !dir.to_string_lossy().contains('%')
+        && dir.is_dir()
+    {
+        dirs.push(dir.to_path_buf());
+    }
+
+    let kept_dir = profiles_dir(config).join(KEPT_DIR);
+    let mut profraw = vec![];
+    for dir in &dirs {
+        find_profraw(dir, &mut profraw)?;
+    }
+    // Profiles kept from an earlier merge in this run are already in one. The profiles found are
+    // canonical, so the dirs they're compared with have to be too.
+    if let Ok(kept_dir) = crate::canonicalize(&kept_dir) {
+        profraw.retain(|p| !p.starts_with(&kept_dir));
+    }
+    // Sorted so the merge command is the same from run to run. Overlapping dirs would otherwise
+    // list a file twice.
+    profraw.sort();
+    profraw.dedup();
+    if profraw.is_empty() && profiles.is_empty() {
+        return Err(CovError::NoProfiles { dirs });
+    }
+    // A big workspace can leave more raw profiles than fit on a command line, so they're listed
+    // in a file for llvm-profdata instead
+    let list = input_files(output);
+    let input_files = if profraw.is_empty() {
+        None
+    } else {
+        let mut paths = vec![];
+        for file in &profraw {
+            paths.extend_from_slice(file.as_os_str().as_encoded_bytes());
+            paths.push(b'\n');
+        }
+        fs::write(&list, paths).map_err(|e| CovError::io(&list, e))?;
+        Some(list.as_path())
+    };
+    let merged = exec(&mut merge_command(config, input_files, profiles, output));
+    if input_files.is_some() {
+        let _ = fs::remove_file(&list);
+    }
+    if let Err(CovError::ToolFailed { stderr, .. }) = &merged
+        && is_version_error(stderr)
+    {
+        return Err(version_mismatch(config, profraw.first()));
+    }
+    merged?;
+
+    if config.keep_profraw {
+        fs::create_dir_all(&kept_dir).map_err(|e| CovError::io(&kept_dir, e))?;
+    }
+    // Only what this run's tests wrote is cleaned up. Profiles in a --profraw-dir, like shards
+    // downloaded from other jobs, stay where they are for the merge to be run again.
+    // A dir that isn't there has no profiles in it
+    let canonical = |path: &Path| crate::canonicalize(path).ok();
+    let profile_dir = config.profile_file.parent().and_then(canonical);
+    let project_dir = canonical(&config.project_dir);
+    let supplied: Vec<PathBuf> = supplied_dirs(config).filter_map(|d| canonical(d)).collect();
+    let ours = |file: &Path| {
+        let under = |dir: &Option<PathBuf>| dir.as_ref().is_some_and(|dir| file.starts_with(dir));
+        under(&profile_dir)
+            || (under(&project_dir) && !supplied.iter().any(|dir| file.starts_with(dir)))
+    };
+    for file in profraw.iter().filter(|file| ours(file)) {
+        if config.keep_profraw {
+            keep(file, &kept_dir)?;
+        } else {
+            fs::remove_file(file).map_err(|e| CovError::io(file, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// The `profraw_dirs` that were passed in, rather than the project dir they default to. What's in
+/// them isn't removed after merging.
+pub(crate) fn supplied_dirs(config: &Config) -> impl Iterator<Item = &PathBuf> {
+    config
+        .profraw_dirs
+        .iter()
+        .filter(|dir| **dir != config.project_dir)
+}
+
+/// Where [`merge`] lists the raw profiles going into `output`
+pub(crate) fn input_files(output: &Path) -> PathBuf {
+    output.with_file_name(INPUT_FILES)
+}
+
+/// What [`merge`] runs, with the raw profiles listed in `input_files`
+pub(crate) fn merge_command(
+    config: &Config,
+    input_files: Option<&Path>,
+    profiles: &[PathBuf],
+    output: &Path,
+) -> Command {
+    let mut cmd = Command::new(&config.tools.llvm_profdata);
+    cmd.arg("merge").arg("-sparse");
+    if let Some(input_files) = input_files {
+        let mut arg = OsString::from("--input-files=");
+        arg.push(input_files);
+        cmd.arg(arg);
+    }
+    cmd.args(profiles).arg("-o").arg(output);
+    cmd
+}
+
+/// Moves a merged raw profile into `kept_dir`, numbering it if a profile from another directory
+/// already has its name
+fn keep(file: &Path, kept_dir: &Path) -> Result<()> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let mut kept = kept_dir.join(&*name);
+    let mut n = 1;
+    while kept.exists() {
+        kept = kept_dir.join(format!("{}-{}", n, name));
+        n += 1;
+    }
+    // Renaming doesn't work across filesystems, for a profraw dir on another one
+    if fs::rename(file, &kept).is_err() {
+        fs::copy(file, &kept).map_err(|e| CovError::io(file, e))?;
+        fs::remove_file(file).map_err(|e| CovError::io(file, e))?;
+    }
+    Ok(())
+}
+
+/// How llvm-profdata says it can't read a profile from another LLVM version, in the versions
+/// where it says so at all
+fn is_version_error(stderr: &str) -> bool {
+    [
+        "profile format version",
+        "profile version mismatch",
+        "unsupported instrumentation profile format",
+    ]
+    .iter()
+    .any(|m| stderr.contains(m))
+}
+
+/// Explains a profile llvm-profdata couldn't read in terms of the toolchains involved, instead
+/// of llvm's own message
+fn version_mismatch(config: &Config, profraw: Option<&PathBuf>) -> CovError {
+    let info = tools::rustc_output(&config.project_dir, &["-vV"]).unwrap_or_default();
+    let release = tools::rustc_field(&info, "release: ").unwrap_or("unknown");
+    let rustc_llvm = tools::rustc_field(&info, "LLVM version: ").unwrap_or("unknown");
+    let toolchain = exec(
+        Command::new("rustup")
+            .args(["show", "active-toolchain"])
+            .current_dir(&config.project_dir),
+    )
+    .ok()
+    .and_then(|o| {
+        let stdout = String::from_utf8_lossy(&o.stdout).into_owned();
+        stdout
+            .split_whitespace()
+            .next()
+            .map(|t| format!("the {} toolchain, ", t))
+    })
+    .unwrap_or_default();
+    let tool_version = tools::llvm_version(&config.tools.llvm_profdata)
+        .map(|v| format!("LLVM {}", v))
+        .unwrap_or("from an unknown LLVM version".to_string());
+    let raw_version = profraw
+        .and_then(|p| raw_profile_version(p))
+        .map(|v| format!(", raw profile format version {}", v))
+        .unwrap_or_default();
+
+    CovError::Toolchain(format!(
+        "{} can't read the profiles the tests wrote. They're from {}rustc {} with LLVM {}{}, but {} is {}. Use the llvm tools from the same toolchain with `rustup component add llvm-tools`, or point --llvm-profdata at a matching llvm-profdata",
+        config.tools.llvm_profdata,
+        toolchain,
+        release,
+        rustc_llvm,
+        raw_version,
+        config.tools.llvm_profdata,
+        tool_version
+    ))
+}
+
+/// The format version in a raw profile's header, which changes with the LLVM version that wrote
+/// it. The header is the magic number followed by the version, both in the writer's byte order.
+fn raw_profile_version(path: &Path) -> Option<u64> {
+    const MAGIC: u64 = 0xff6c_7072_6f66_7281;
+    let mut header = [0; 16];
+    fs::File::open(path).ok()?.read_exact(&mut header).ok()?;
+    let [magic, version] = [&header[..8], &header[8..]].map(|b| b.try_into().unwrap());
+    let version = if u64::from_le_bytes(magic) == MAGIC {
+        u64::from_le_bytes(version)
+    } else if u64::from_be_bytes(magic) == MAGIC {
+        u64::from_be_bytes(version)
+    } else {
+        return None;
+    };
+    // The top half holds flags for the kind of instrumentation
+    Some(version & 0xffff_ffff)
+}
+
+/// Starts from an empty profiles dir, so profiles left over from an earlier run don't get merged
+/// into this one. The rest of the output dir, like the reports, is left alone.
+pub fn clear_profdata(config: &Config) -> Result<()> {
+    let profiles_dir = profiles_dir(config);
+    if profiles_dir.exists() {
+        fs::remove_dir_all(&profiles_dir).map_err(|e| CovError::io(&profiles_dir, e))?;
+    }
+    fs::create_dir_all(&profiles_dir).map_err(|e| CovError::io(&profiles_dir, e))
+}
+
+/// Adds every `.profraw` under `dir` to `profraw`. Tests that change their working directory
+/// leave profiles wherever they ended up, so this looks through the whole tree, `target` included.
+pub(crate) fn find_profraw(dir: &Path, profraw: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).map_err(|e| CovError::io(dir, e))? {
+        let entry = entry.map_err(|e| CovError::io(dir, e))?;
+        // Symlinks aren't followed, so a link back up the tree can't loop forever
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            if entry.file_name() != ".git" {
+                find_profraw(&path, profraw)?;
+            }
+        } else if file_type.is_file() && path.extension().is_some_and(|e| e == "profraw") {
+            profraw.push(crate::canonicalize(&path).map_err(|e| CovError::io(&path, e))?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! The spinner showing which step of a run is going, and how long each one took. It's drawn on
+//! stderr, and only when stdout is a terminal, so logs and piped reports never get it.
+
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use tracing::info;
+
+use crate::Config;
+
+const TICK: Duration = Duration::from_millis(100);
+
+/// The spinner of the step that's going, for output that has to get past it
+static CURRENT: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// The steps that finished since [`take_timings`] was last called
+static TIMINGS: Mutex<Vec<Timing>> = Mutex::new(vec![]);
+
+/// How long a step took
+#[derive(Debug, Clone, Serialize)]
+pub struct Timing {
+    pub phase: &'static str,
+    pub seconds: f64,
+}
+
+/// How long each step that finished since the last call took, in the order they started. Steps
+/// that ran more than once, like the builds of a feature matrix, are added up.
+pub fn take_timings() -> Vec<Timing> {
+    let finished = std::mem::take(&mut *TIMINGS.lock().unwrap_or_else(PoisonError::into_inner));
+    let mut timings: Vec<Timing> = vec![];
+    for timing in finished {
+        match timings.iter_mut().find(|t| t.phase == timing.phase) {
+            Some(existing) => existing.seconds += timing.seconds,
+            None => timings.push(timing),
+        }
+    }
+    timings
+}
+
+/// A step of the run, shown with a spinner and the time so far until it's finished. One that's
+/// dropped without finishing, on an error, is cleared away instead.
+pub struct Phase {
+    bar: Option<ProgressBar>,
+    message: &'static str,
+    started: Instant,
+}
+
+impl Phase {
+    /// Starts showing `message`, unless `progress` is off, it's `quiet`, or stdout isn't a
+    /// terminal
+    pub fn start(config: &Config, message: &'static str) -> Phase {
+        let shown = config.progress && !config.quiet && io::stdout().is_terminal();
+        let bar = shown.then(|| {
+            let bar = ProgressBar::new_spinner()
+                .with_style(
+                    ProgressStyle::with_template("{spinner} {msg} {elapsed}")
+                        .expect("the spinner template is valid"),
+                )
+                .with_message(message);
+            bar.enable_steady_tick(TICK);
+            bar
+        });
+        set_current(bar.clone());
+        Phase {
+            bar,
+            message,
+            started: Instant::now(),
+        }
+    }
+
+    /// Replaces the spinner with a line saying how long the step took
+    pub fn finish(mut self) {
+        let elapsed = self.started.elapsed();
+        info!(phase = self.message, ?elapsed, "finished");
+        TIMINGS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Timing {
+                phase: self.message,
+                seconds: elapsed.as_secs_f64(),
+            });
+        if let Some(bar) = self.bar.take() {
+            bar.set_style(ProgressStyle::with_template("{msg}").expect("the template is valid"));
+            bar.finish_with_message(format!(
+                "{} took {:.1}s",
+                self.message,
+                bar.elapsed().as_secs_f64()
+            ));
+            set_current(None);
+        }
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+            set_current(None);
+        }
+    }
+}
+
+fn set_current(bar: Option<ProgressBar>) {
+    *CURRENT.lock().unwrap_or_else(PoisonError::into_inner) = bar;
+}
+
+/// Stderr, written to with the spinner out of the way, for writers like the log's that write
+/// whole lines at a time
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        suspend(|| io::stderr().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        suspend(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Runs `f`, which writes to the terminal, with the spinner out of the way. Whole lines should
+/// be written, since the spinner goes back on the line after.
+pub(crate) fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let current = CURRENT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    match current {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
@@ -0,0 +1,115 @@
+//! Ratchet mode: a lock file with the best coverage each file has reached, which no run may drop
+//! below and which moves up by itself as coverage improves.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CovError, Result, export::ExportData, export::Metric, formats::relative_path};
+
+/// In the project dir, meant to be committed
+pub const LOCK_FILE: &str = "simple-cov.lock";
+
+const LOCK_HEADER: &str = "\
+# Best coverage each file has reached. Updated by `simple-rust-cov --ratchet` when coverage
+# improves, and runs fail if a file drops below it. Commit this file.
+";
+
+/// Decimal places kept in the lock, so float noise can't fail a run that covers exactly as much
+const PRECISION: f64 = 10_000.;
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Lock {
+    /// By path relative to the project root
+    #[serde(default)]
+    files: BTreeMap<String, Best>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Best {
+    lines: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branches: Option<f64>,
+}
+
+/// Checks every file in the report against the lock file in `root`, then raises the lock to
+/// whatever improved. Returns the files that dropped, as messages for the user.
+///
+/// Files that dropped keep their old best, so the next run fails too until they're fixed. Files
+/// that aren't in this run's report, say because only some packages were tested, keep theirs
+/// as long as they still exist.
+pub fn ratchet(data: &ExportData, root: &Path) -> Result<Vec<String>> {
+    let path = root.join(LOCK_FILE);
+    let old = if path.exists() {
+        let contents = fs::read_to_string(&path).map_err(|e| CovError::io(&path, e))?;
+        toml::from_str(&contents).map_err(|e| CovError::Config {
+            path: path.clone(),
+            message: e.to_string(),
+        })?
+    } else {
+        Lock::default()
+    };
+
+    let mut failures = vec![];
+    let mut new = Lock {
+        files: old
+            .files
+            .iter()
+            .filter(|(file, _)| root.join(file).exists())
+            .map(|(file, best)| (file.clone(), *best))
+            .collect(),
+    };
+    for file in &data.files {
+        if file.summary.lines.count == 0 {
+            continue;
+        }
+        let name = relative_path(&file.filename, root);
+        let current = Best {
+            lines: rounded(&file.summary.lines),
+            branches: (file.summary.branches.count > 0).then(|| rounded(&file.summary.branches)),
+        };
+        let best = new.files.entry(name.clone()).or_insert(current);
+
+        if current.lines < best.lines {
+            failures.push(format!(
+                "Line coverage of {} dropped below its best ({:.2}% < {:.2}%)",
+                name,
+                current.lines * 100.0,
+                best.lines * 100.0
+            ));
+        } else {
+            best.lines = current.lines;
+        }
+        match (current.branches, best.branches) {
+            (Some(current), Some(recorded)) if current < recorded => failures.push(format!(
+                "Branch coverage of {} dropped below its best ({:.2}% < {:.2}%)",
+                name,
+                current * 100.0,
+                recorded * 100.0
+            )),
+            (Some(current), Some(recorded)) => best.branches = Some(current.max(recorded)),
+            (Some(current), None) => best.branches = Some(current),
+            // Without branch data this run, there's nothing to compare
+            (None, _) => {}
+        }
+    }
+
+    if new != old {
+        let contents = toml::to_string(&new).expect("unable to serialize lock file");
+        fs::write(&path, format!("{}\n{}", LOCK_HEADER, contents))
+            .map_err(|e| CovError::io(&path, e))?;
+    }
+    Ok(failures)
+}
+
+/// Covered fraction rounded down to [`PRECISION`]
+fn rounded(metric: &Metric) -> f64 {
+    let fraction = if metric.count == 0 {
+        1.0
+    } else {
+        metric.covered as f64 / metric.count as f64
+    };
+    (fraction * PRECISION).floor() / PRECISION
+}
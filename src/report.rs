@@ -0,0 +1,388 @@
+//! Getting coverage out of llvm-cov.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    Config, CovError, Result,
+    config::{self, ExportStrategy},
+    decode,
+    error::{EXIT_OK, EXIT_TESTS, EXIT_TESTS_AND_THRESHOLDS, EXIT_THRESHOLDS},
+    exclude::AttributedItem,
+    exec,
+    export::{Export, ExportData, Summary, UncoveredFunction},
+    formats,
+    patch::PatchCoverage,
+    profdata,
+    progress::Timing,
+    thresholds::PathCoverage,
+};
+
+/// What llvm-cov reads coverage from
+#[derive(Debug, Clone, Default)]
+pub struct Inputs {
+    /// Instrumented binaries, for their coverage mappings
+    pub objects: Vec<PathBuf>,
+    /// Source files to report on, or empty for every file the objects cover
+    pub sources: Vec<String>,
+    /// Filename regexes to ignore on top of the configured ones, for files this run decided to
+    /// leave out
+    pub ignore: Vec<String>,
+}
+
+/// Coverage of one source file
+#[derive(Debug, Clone, Serialize)]
+pub struct FileCoverage {
+    pub filename: String,
+    pub summary: Summary,
+}
+
+/// Everything a run found out. It serializes to what the thresholds were checked against, the
+/// totals and file summaries, and the rest of the findings, leaving out llvm-cov's raw export
+/// and terminal output.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub line_coverage: f32,
+    /// 1.0 when there's no branch data at all, see [`Report::has_branch_data`]
+    pub branch_coverage: f32,
+    pub function_coverage: f32,
+    pub region_coverage: f32,
+    /// Line, branch, function and region counts over every file, with their percentages
+    pub totals: Summary,
+    /// The same for each file, in filename order
+    pub files: Vec<FileCoverage>,
+    /// Everything llvm-cov exported, for line-level data and the other output formats
+    #[serde(skip)]
+    pub data: ExportData,
+    /// llvm-cov's text table, only produced for the text format
+    #[serde(skip)]
+    pub table: Option<String>,
+    /// Requirements that weren't met. Empty when the run passes.
+    pub failures: Vec<String>,
+    /// Things the user should know about that don't fail the run
+    pub warnings: Vec<String>,
+    /// Warn-only minimums that weren't met. They don't fail the run either, but they're
+    /// requirements on the way to being enforced, so they're shown more prominently.
+    pub soft_failures: Vec<String>,
+    /// Items switched off with `#[coverage(off)]`, by filename
+    pub coverage_off: BTreeMap<String, Vec<AttributedItem>>,
+    /// Coverage of the changed lines, when there's a `diff_base`
+    pub patch: Option<PatchCoverage>,
+    /// The files grouped by the path threshold they count towards, in pattern order
+    pub paths: Vec<PathCoverage>,
+    /// Functions that never ran, by filename, when `show_uncovered_functions` is set
+    pub uncovered_functions: Option<BTreeMap<String, Vec<UncoveredFunction>>>,
+    /// The `show_source` file annotated with line counts by llvm-cov, colors included
+    #[serde(skip)]
+    pub source_view: Option<String>,
+    /// Packages whose coverage came from an earlier `changed_only` run instead of their tests
+    pub reused: Vec<String>,
+    /// Tests that failed in a `keep_going` run, as they'd have been reported otherwise
+    pub test_failures: Vec<String>,
+    /// How long each step of the run took
+    pub timings: Vec<Timing>,
+}
+
+impl Report {
+    pub fn from_export(data: ExportData) -> Self {
+        let mut files: Vec<FileCoverage> = data
+            .files
+            .iter()
+            .map(|f| FileCoverage {
+                filename: f.filename.clone(),
+                summary: f.summary.clone(),
+            })
+            .collect();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        Report {
+            line_coverage: data.totals.lines.fraction(),
+            branch_coverage: data.totals.branches.fraction(),
+            function_coverage: data.totals.functions.fraction(),
+            region_coverage: data.totals.regions.fraction(),
+            totals: data.totals.clone(),
+            files,
+            data,
+            table: None,
+            failures: vec![],
+            warnings: vec![],
+            soft_failures: vec![],
+            coverage_off: BTreeMap::new(),
+            patch: None,
+            paths: vec![],
+            uncovered_functions: None,
+            source_view: None,
+            reused: vec![],
+            test_failures: vec![],
+            timings: vec![],
+        }
+    }
+
+    /// Whether the tests passed and every requirement was met
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty() && self.test_failures.is_empty()
+    }
+
+    /// The process exit code for the run, telling failed tests and unmet requirements apart
+    pub fn exit_code(&self) -> i32 {
+        match (self.test_failures.is_empty(), self.failures.is_empty()) {
+            (true, true) => EXIT_OK,
+            (true, false) => EXIT_THRESHOLDS,
+            (false, true) => EXIT_TESTS,
+            (false, false) => EXIT_TESTS_AND_THRESHOLDS,
+        }
+    }
+
+    /// Lines of code behind `#[coverage(off)]`, attributes included
+    pub fn coverage_off_lines(&self) -> u64 {
+        self.coverage_off
+            .values()
+            .flatten()
+            .map(|i| i.lines())
+            .sum()
+    }
+
+    /// Whether the build instrumented any branches. Stable Rust doesn't, in which case the
+    /// branch coverage is meaningless.
+    pub fn has_branch_data(&self) -> bool {
+        self.data.totals.branches.count > 0
+    }
+}
+
+/// Dependencies from crates.io, which are never what you're measuring. Either separator, for
+/// Windows.
+const REGISTRY_REGEX: &str = r"[/\\]\.cargo[/\\]registry[/\\]";
+
+pub(crate) fn rust_cov(config: &Config, subcommand: &str, inputs: &Inputs) -> Command {
+    let mut cmd = Command::new(&config.tools.llvm_cov);
+    cmd.arg(subcommand)
+        .arg(format!("--ignore-filename-regex={}", REGISTRY_REGEX))
+        .arg("-instr-profile")
+        .arg(profdata::profdata_path(config))
+        .current_dir(&config.project_dir);
+
+    for pattern in config.ignore.iter().chain(&inputs.ignore) {
+        cmd.arg(format!("--ignore-filename-regex={}", pattern));
+    }
+    for equivalence in &config.path_equivalence {
+        cmd.arg(format!(
+            "-path-equivalence={},{}",
+            equivalence.from.display(),
+            equivalence.to.display()
+        ));
+    }
+
+    for object in &inputs.objects {
+        cmd.arg("--object").arg(object);
+    }
+    if !inputs.sources.is_empty() {
+        cmd.arg("--sources").args(&inputs.sources);
+    }
+    cmd
+}
+
+/// Runs `llvm-cov report` for its table, which is only shown. The numbers come from the export,
+/// the same as for the other formats, since the table's columns change with llvm's version and
+/// options.
+pub fn execute_report(config: &Config, inputs: &Inputs, data: ExportData) -> Result<Report> {
+    let output = exec(&mut report_command(config, inputs))?;
+    let stdout = decode("rust-cov report", &output.stdout);
+    let stderr = decode("rust-cov report", &output.stderr);
+    Ok(Report {
+        table: Some(format!("{}\n{}\n", stdout, stderr)),
+        ..Report::from_export(data)
+    })
+}
+
+/// What [`execute_report`] runs
+pub(crate) fn report_command(config: &Config, inputs: &Inputs) -> Command {
+    let mut cmd = rust_cov(config, "report", inputs);
+    cmd.arg("--use-color");
+    cmd
+}
+
+/// Runs `llvm-cov export`, once or once per object depending on the `export_strategy`
+pub fn export_data(config: &Config, inputs: &Inputs, summary_only: bool) -> Result<ExportData> {
+    if config.export_strategy == ExportStrategy::Parallel && inputs.objects.len() > 1 {
+        return export_parallel(config, inputs);
+    }
+    export_objects(config, inputs, summary_only)
+}
+
+/// Exports each object on its own, as many at once as there are CPUs, and combines the results.
+/// Combining them needs the line-level data, so that's always exported.
+fn export_parallel(config: &Config, inputs: &Inputs) -> Result<ExportData> {
+    let next = AtomicUsize::new(0);
+    let exports = Mutex::new(vec![]);
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(inputs.objects.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(object) = inputs.objects.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let one = Inputs {
+                        objects: vec![object.clone()],
+                        ..inputs.clone()
+                    };
+                    let export = export_objects(config, &one, false);
+                    exports
+                        .lock()
+                        .expect("an export thread panicked")
+                        .push(export);
+                }
+            });
+        }
+    });
+    let exports = exports
+        .into_inner()
+        .expect("an export thread panicked")
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ExportData::merge(exports))
+}
+
+fn export_objects(config: &Config, inputs: &Inputs, summary_only: bool) -> Result<ExportData> {
+    let mut cmd = rust_cov(config, "export", inputs);
+    if summary_only {
+        cmd.arg("-summary-only");
+    }
+    let output = exec(&mut cmd)?;
+
+    let mut export: Export = serde_json::from_str(&decode("rust-cov export", &output.stdout))
+        .map_err(|e| CovError::parse("rust-cov export", e))?;
+    let mut data = export
+        .data
+        .pop()
+        .ok_or_else(|| CovError::parse("rust-cov export", "it contained no coverage data"))?;
+    // Not every llvm-cov version applies the equivalences to what it exports
+    data.remap_paths(&config.path_equivalence);
+    Ok(data)
+}
+
+/// The covered source files matching at least one `include` pattern, other than those matching
+/// `ignore`. Every file is included when there are no patterns.
+pub fn included_sources(
+    config: &Config,
+    objects: &[PathBuf],
+    ignore: &[String],
+) -> Result<Vec<String>> {
+    if config.include.is_empty() {
+        return Ok(vec![]);
+    }
+    let patterns = config
+        .include
+        .iter()
+        .map(|p| {
+            Regex::new(p).map_err(|e| CovError::Pattern {
+                pattern: p.clone(),
+                message: e.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let all = Inputs {
+        objects: objects.to_vec(),
+        sources: vec![],
+        ignore: ignore.to_vec(),
+    };
+    let sources: Vec<String> = export_data(config, &all, true)?
+        .files
+        .into_iter()
+        .map(|f| f.filename)
+        .filter(|f| patterns.iter().any(|p| p.is_match(f)))
+        .collect();
+    if sources.is_empty() {
+        return Err(CovError::NoSources {
+            patterns: config.include.clone(),
+        });
+    }
+    Ok(sources)
+}
+
+/// What [`export_lcov`] runs
+pub(crate) fn lcov_command(config: &Config, inputs: &Inputs) -> Command {
+    let mut cmd = rust_cov(config, "export", inputs);
+    cmd.arg("-format=lcov");
+    cmd
+}
+
+pub fn export_lcov(config: &Config, inputs: &Inputs, lcov_path: &Path) -> Result<()> {
+    let output = exec(&mut lcov_command(config, inputs))?;
+    let mut lcov = decode("rust-cov export", &output.stdout).into_owned();
+    if !config.path_equivalence.is_empty() {
+        lcov = lcov
+            .lines()
+            .map(|line| match line.strip_prefix("SF:") {
+                Some(path) => match config::remap_path(&config.path_equivalence, Path::new(path)) {
+                    Some(remapped) => format!("SF:{}\n", remapped.display()),
+                    None => format!("{}\n", line),
+                },
+                None => format!("{}\n", line),
+            })
+            .collect();
+    }
+
+    fs::write(lcov_path, lcov).map_err(|e| CovError::io(lcov_path, e))
+}
+
+pub fn show_html(config: &Config, inputs: &Inputs, html_dir: &Path) -> Result<()> {
+    fs::create_dir_all(html_dir).map_err(|e| CovError::io(html_dir, e))?;
+    // rust-cov runs from the project dir, so hand it an absolute path
+    let html_dir: PathBuf = crate::canonicalize(html_dir).map_err(|e| CovError::io(html_dir, e))?;
+
+    exec(&mut html_command(config, inputs, &html_dir))?;
+    Ok(())
+}
+
+/// What [`show_html`] runs
+pub(crate) fn html_command(config: &Config, inputs: &Inputs, html_dir: &Path) -> Command {
+    let mut cmd = rust_cov(config, "show", inputs);
+    cmd.arg("-format=html")
+        .arg("-show-line-counts-or-regions")
+        .arg(format!("-output-dir={}", html_dir.display()));
+    cmd
+}
+
+/// Renders one source file with its line counts for the terminal. Uncovered lines are
+/// highlighted, and generic functions are shown once instead of per instantiation.
+pub fn show_source(config: &Config, inputs: &Inputs, source: &Path) -> Result<String> {
+    // llvm-cov shows every file when the source doesn't exist, so make sure it does first
+    let source = config.project_dir.join(source);
+    let source = crate::canonicalize(&source).map_err(|e| CovError::io(&source, e))?;
+    let output = exec(
+        rust_cov(config, "show", inputs)
+            .arg("--use-color")
+            .arg("-show-line-counts-or-regions")
+            .arg("-show-instantiations=false")
+            .arg("--sources")
+            .arg(&source),
+    )?;
+    if output.stdout.is_empty() {
+        return Err(CovError::NoSources {
+            patterns: vec![source.display().to_string()],
+        });
+    }
+    Ok(decode("rust-cov show", &output.stdout).into_owned())
+}
+
+pub fn write_badge(data: &ExportData, badge_path: &Path) -> Result<()> {
+    let coverage = data.totals.lines.fraction();
+    let badge = if badge_path.extension().is_some_and(|e| e == "svg") {
+        formats::badge::svg(coverage)
+    } else {
+        formats::badge::endpoint(coverage)
+    };
+    fs::write(badge_path, badge).map_err(|e| CovError::io(badge_path, e))
+}
@@ -0,0 +1,610 @@
+//! Building and running the instrumented tests.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use serde_json::Value;
+
+use tracing::warn;
+
+use crate::{
+    Config, CovError, Result, cargo_config, decode, exec, exec_streamed, profdata, workspace,
+};
+
+const COVERAGE_FLAGS: [&str; 2] = ["-C", "instrument-coverage"];
+/// Branch coverage is still unstable, so this only works on nightly
+const BRANCH_FLAG: &str = "-Zcoverage-options=branch";
+/// Separator cargo uses between flags in `CARGO_ENCODED_RUSTFLAGS`
+const FLAG_SEPARATOR: &str = "\x1f";
+/// Where rustdoc keeps the doctest binaries, in the profiles dir so each run starts without them
+pub(crate) const DOCTEST_DIR: &str = "doctests";
+/// What rustdoc names every doctest binary, each in its own directory
+const DOCTEST_BINARY: &str = "rust_out";
+
+/// Where cargo takes one kind of flags from, in order of precedence
+struct FlagSource {
+    encoded_var: &'static str,
+    var: &'static str,
+    /// The key in the config files' `[build]` table
+    config_key: &'static str,
+    /// Whether `[target]` tables can set them too
+    targets: bool,
+}
+
+const RUSTFLAGS: FlagSource = FlagSource {
+    encoded_var: "CARGO_ENCODED_RUSTFLAGS",
+    var: "RUSTFLAGS",
+    config_key: "rustflags",
+    targets: true,
+};
+
+const RUSTDOCFLAGS: FlagSource = FlagSource {
+    encoded_var: "CARGO_ENCODED_RUSTDOCFLAGS",
+    var: "RUSTDOCFLAGS",
+    config_key: "rustdocflags",
+    targets: false,
+};
+
+/// An instrumented test binary, and the package it tests
+#[derive(Debug, Clone)]
+pub struct TestBinary {
+    pub path: PathBuf,
+    /// Directory containing the package's Cargo.toml, which `cargo test` runs the binary in
+    pub package_dir: PathBuf,
+}
+
+/// What the instrumented build produced
+#[derive(Debug, Clone)]
+pub struct Build {
+    /// Paths of the test artifacts and the binaries built for them, for llvm-cov to read
+    /// coverage mappings from
+    pub objects: Vec<PathBuf>,
+    pub tests: Vec<TestBinary>,
+    /// `OUT_DIR` of every build script that ran, where generated sources end up
+    pub out_dirs: Vec<PathBuf>,
+}
+
+/// Builds the tests with instrumentation, without running them
+pub fn build(config: &Config) -> Result<Build> {
+    if config.branch {
+        check_nightly(config)?;
+    }
+
+    let result = exec(&mut build_command(config));
+    let output = match result {
+        // stdout is just the artifacts that did build, which doesn't help explain the failure
+        Err(CovError::ToolFailed {
+            tool,
+            status,
+            stderr,
+            ..
+        }) => {
+            return Err(CovError::ToolFailed {
+                tool,
+                status,
+                stdout: String::new(),
+                stderr,
+            });
+        }
+        result => result?,
+    };
+
+    let selected = selected_manifests(config)?;
+    let stdout = decode("cargo build", &output.stdout);
+    let mut build = Build {
+        objects: vec![],
+        tests: vec![],
+        out_dirs: vec![],
+    };
+    for line in stdout.lines() {
+        let target: Value =
+            serde_json::from_str(line).map_err(|e| CovError::parse("cargo build output", e))?;
+        let is_selected = |manifest: &str| {
+            selected
+                .as_ref()
+                .is_none_or(|s| s.iter().any(|m| m == Path::new(manifest)))
+        };
+        if target["reason"] == "build-script-executed"
+            && let Some(out_dir) = target["out_dir"].as_str()
+        {
+            build.out_dirs.push(out_dir.into());
+        }
+        if let Some(test) = target["profile"]["test"].as_bool()
+            && test
+            && target["manifest_path"].as_str().is_some_and(is_selected)
+        {
+            let object_paths = target["filenames"].as_array().ok_or_else(|| {
+                CovError::parse("cargo build output", "filenames was not an array")
+            })?;
+            for object_path in object_paths {
+                build.objects.push(
+                    object_path
+                        .as_str()
+                        .ok_or_else(|| {
+                            CovError::parse("cargo build output", "filename was not a string")
+                        })?
+                        .into(),
+                );
+            }
+
+            if let Some(executable) = target["executable"].as_str() {
+                let manifest_path = target["manifest_path"].as_str().ok_or_else(|| {
+                    CovError::parse("cargo build output", "manifest_path was not a string")
+                })?;
+                build.tests.push(TestBinary {
+                    path: executable.into(),
+                    package_dir: Path::new(manifest_path)
+                        .parent()
+                        .unwrap_or(Path::new("."))
+                        .to_path_buf(),
+                });
+            }
+        } else if is_bin(&target)
+            && target["manifest_path"].as_str().is_some_and(is_selected)
+            && let Some(executable) = target["executable"].as_str()
+        {
+            // Built for the integration tests to run, which leaves profiles from the same
+            // LLVM_PROFILE_FILE when they do, so they need reading too
+            build.objects.push(executable.into());
+        }
+    }
+    Ok(build)
+}
+
+/// Whether a built artifact is one of the package's binaries, as opposed to a test or library
+fn is_bin(target: &Value) -> bool {
+    target["target"]["kind"]
+        .as_array()
+        .is_some_and(|kinds| kinds.iter().any(|k| k == "bin"))
+}
+
+/// What [`build`] runs
+pub(crate) fn build_command(config: &Config) -> Command {
+    let mut cmd = cargo_test(config);
+    add_targets(config, &mut cmd);
+    cmd.arg("--no-run")
+        // Compiler errors go to stderr as usual, leaving only the artifacts on stdout
+        .arg("--message-format=json-render-diagnostics");
+    cmd
+}
+
+/// `cargo test` with the package selection, features, profile, and flags for an instrumented
+/// build
+fn cargo_test(config: &Config) -> Command {
+    cargo(config, &["test"], None)
+}
+
+/// A cargo subcommand that builds the tests the same way `cargo test` does, so it reuses the
+/// same artifacts. `packages` replaces the configured package selection when given.
+fn cargo(config: &Config, subcommand: &[&str], packages: Option<&[String]>) -> Command {
+    let mut cmd = Command::new(&config.tools.cargo);
+    cmd.args(subcommand).current_dir(&config.project_dir);
+    if let Some(manifest_path) = &config.manifest_path {
+        cmd.arg("--manifest-path").arg(manifest_path);
+    }
+    match packages {
+        Some(packages) => {
+            for package in packages {
+                cmd.arg("--package").arg(package);
+            }
+        }
+        None => {
+            if config.workspace {
+                cmd.arg("--workspace");
+            }
+            for package in &config.packages {
+                cmd.arg("--package").arg(package);
+            }
+            for package in &config.exclude {
+                cmd.arg("--exclude").arg(package);
+            }
+        }
+    }
+    if !config.features.is_empty() {
+        cmd.arg("--features").arg(config.features.join(","));
+    }
+    if config.all_features {
+        cmd.arg("--all-features");
+    }
+    if config.no_default_features {
+        cmd.arg("--no-default-features");
+    }
+    if config.release {
+        cmd.arg("--release");
+    }
+    if let Some(target) = &config.target {
+        cmd.arg("--target").arg(target);
+    }
+    if let Some(target_dir) = &config.target_dir {
+        cmd.env("CARGO_TARGET_DIR", target_dir);
+    }
+    add_runner_to_env(config, &mut cmd);
+    add_flags(config, &mut cmd, &RUSTFLAGS, &coverage_flags(config));
+    cmd
+}
+
+/// Cargo's variable for a target's runner, like `CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_RUNNER`
+fn runner_var(target: &str) -> String {
+    format!(
+        "CARGO_TARGET_{}_RUNNER",
+        target.to_uppercase().replace(['-', '.'], "_")
+    )
+}
+
+/// Hands `target_runner` to cargo too, for the tests it runs itself, like nextest's. Cargo only
+/// reads the variable for the target it's building for, so this needs a `target`.
+fn add_runner_to_env(config: &Config, cmd: &mut Command) {
+    if let (Some(target), Some(runner)) = (&config.target, &config.target_runner) {
+        cmd.env(runner_var(target), runner);
+    }
+}
+
+/// The program and arguments to put in front of a test binary, from `target_runner` or, for a
+/// `target`, cargo's runner variable the way cargo itself would use it
+pub(crate) fn target_runner(config: &Config) -> Vec<String> {
+    let runner = config.target_runner.clone().or_else(|| {
+        let target = config.target.as_ref()?;
+        env::var(runner_var(target)).ok()
+    });
+    runner
+        .map(|r| r.split_whitespace().map(|a| a.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// The target selection options. Left off for the doctests, since cargo won't mix them with
+/// `--doc`.
+fn add_targets(config: &Config, cmd: &mut Command) {
+    if config.lib {
+        cmd.arg("--lib");
+    }
+    if config.bins {
+        cmd.arg("--bins");
+    }
+    if config.tests {
+        cmd.arg("--tests");
+    }
+    for test in &config.test {
+        cmd.arg("--test").arg(test);
+    }
+}
+
+/// Flags for rustc and rustdoc to instrument what they build
+fn coverage_flags(config: &Config) -> Vec<String> {
+    let mut flags: Vec<String> = COVERAGE_FLAGS.map(|f| f.to_string()).into();
+    if config.branch {
+        flags.push(BRANCH_FLAG.to_string());
+    }
+    flags
+}
+
+/// Fails unless the rustc cargo would use is a nightly, which `-Z` flags need
+fn check_nightly(config: &Config) -> Result<()> {
+    // The project dir matters, since a rust-toolchain.toml there can pick the toolchain
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    let output = exec(
+        Command::new(&rustc)
+            .arg("-vV")
+            .current_dir(&config.project_dir),
+    )?;
+    let version = String::from_utf8_lossy(&output.stdout);
+    let release = version
+        .lines()
+        .find_map(|l| l.strip_prefix("release: "))
+        .unwrap_or_default();
+    if release.contains("nightly") || release.contains("dev") {
+        Ok(())
+    } else {
+        Err(CovError::Toolchain(format!(
+            "branch coverage needs a nightly toolchain, but {} is {}. Try `rustup run nightly simple-rust-cov --branch`",
+            rustc, release
+        )))
+    }
+}
+
+/// Manifests of the packages picked with `packages` or `workspace` and `exclude`, or `None` when
+/// cargo's usual choice of packages applies. Cargo only builds tests for the selected packages
+/// anyway, but this makes sure nothing else ends up in the report.
+fn selected_manifests(config: &Config) -> Result<Option<Vec<PathBuf>>> {
+    if config.packages.is_empty() && !config.workspace {
+        return Ok(None);
+    }
+    // `-p` also takes `name@version`
+    let name = |spec: &str| spec.split('@').next().unwrap_or(spec).to_string();
+    let packages: Vec<String> = config.packages.iter().map(|p| name(p)).collect();
+    let exclude: Vec<String> = config.exclude.iter().map(|p| name(p)).collect();
+
+    Ok(Some(
+        workspace::metadata(
+            &config.tools.cargo,
+            &config.project_dir,
+            config.manifest_path.as_deref(),
+        )?
+        .packages
+        .into_iter()
+        .filter(|p| packages.is_empty() || packages.contains(&p.name))
+        .filter(|p| !exclude.contains(&p.name))
+        .map(|p| p.manifest_path)
+        .collect(),
+    ))
+}
+
+/// Adds `flags` to a cargo command, or a command that runs cargo somewhere inside it, on top of
+/// whatever flags the user already builds with unless `replace_rustflags` is set. They go in the
+/// environment either way, so cargo ends up with the same flags and reuses the same build.
+fn add_flags(config: &Config, cmd: &mut Command, source: &FlagSource, flags: &[String]) {
+    let existing = existing_flags(config, source).unwrap_or_else(|| {
+        cargo_config::configured_flags(config, source.config_key, source.targets).unwrap_or_else(
+            |e| {
+                // Cargo will have the same complaint about its config, in more detail
+                warn!(
+                    "couldn't read the {} from cargo's config: {}",
+                    source.config_key, e
+                );
+                vec![]
+            },
+        )
+    });
+    set_flags(cmd, source, existing, flags);
+}
+
+/// The flags the user builds with, when they come from the environment, which means cargo
+/// ignores the config files' flags. `None` when the config files apply.
+fn existing_flags(config: &Config, source: &FlagSource) -> Option<Vec<String>> {
+    if config.replace_rustflags {
+        Some(vec![])
+    } else if let Ok(flags) = env::var(source.encoded_var) {
+        Some(
+            flags
+                .split(FLAG_SEPARATOR)
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+                .collect(),
+        )
+    } else if let Ok(flags) = env::var(source.var) {
+        Some(flags.split_whitespace().map(|f| f.to_string()).collect())
+    } else {
+        None
+    }
+}
+
+/// Either of the flag variables in the environment means cargo ignores the config files, so
+/// the flags to keep are all in `existing`, wherever they came from
+fn set_flags(cmd: &mut Command, source: &FlagSource, mut existing: Vec<String>, flags: &[String]) {
+    existing.extend_from_slice(flags);
+    cmd.env(source.encoded_var, existing.join(FLAG_SEPARATOR))
+        .env_remove(source.var);
+}
+
+/// Runs each test binary the way `cargo test` would, stopping at the first one that fails.
+/// With `keep_going`, every binary runs and the failures are added to `failures` instead.
+/// Binaries for another target run under its runner, which passes the environment on.
+pub fn run_tests(config: &Config, tests: &[TestBinary], failures: &mut Vec<String>) -> Result<()> {
+    let runner = target_runner(config);
+    for test in tests {
+        let result = exec_tests(
+            config,
+            &mut test_command(config, &runner, &test.path, &test.package_dir),
+        );
+        let result = match result {
+            Err(CovError::ToolFailed {
+                status,
+                stdout,
+                stderr,
+                ..
+            }) => Err(CovError::TestsFailed {
+                test: test.path.display().to_string(),
+                status,
+                stdout,
+                stderr,
+            }),
+            result => result.map(|_| ()),
+        };
+        keep_going(config, result, failures)?;
+    }
+    Ok(())
+}
+
+/// Runs a command that runs tests, passing its output on as it comes unless it's `quiet`
+fn exec_tests(config: &Config, cmd: &mut Command) -> Result<Output> {
+    if config.quiet {
+        exec(cmd)
+    } else {
+        exec_streamed(cmd)
+    }
+}
+
+/// What [`run_tests`] runs for a test binary, under the target's `runner` if it has one
+pub(crate) fn test_command(
+    config: &Config,
+    runner: &[String],
+    path: &Path,
+    package_dir: &Path,
+) -> Command {
+    let mut cmd = match runner.split_first() {
+        Some((program, args)) => {
+            let mut cmd = Command::new(program);
+            cmd.args(args).arg(path);
+            cmd
+        }
+        None => Command::new(path),
+    };
+    cmd.args(&config.filter)
+        .args(&config.test_args)
+        .env("LLVM_PROFILE_FILE", &config.profile_file)
+        .env("CARGO_MANIFEST_DIR", package_dir)
+        .current_dir(package_dir);
+    cmd
+}
+
+/// With `keep_going`, a test failure is recorded in `failures` and the run carries on
+fn keep_going(config: &Config, result: Result<()>, failures: &mut Vec<String>) -> Result<()> {
+    match result {
+        Err(e @ CovError::TestsFailed { .. }) if config.keep_going => {
+            failures.push(e.to_string());
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Runs the tests with `cargo nextest run`, on top of the build [`build`] made. Nextest runs
+/// every test in its own process, which the `%p` in the default profile file keeps apart.
+/// `packages` replaces the configured package selection when given. Failures go to `failures`
+/// with `keep_going`, like for [`run_tests`].
+pub fn run_nextest(
+    config: &Config,
+    packages: Option<&[String]>,
+    failures: &mut Vec<String>,
+) -> Result<()> {
+    let mut cmd = nextest_command(config, packages);
+    let result = match exec_tests(config, &mut cmd) {
+        Err(CovError::ToolFailed { stderr, .. }) if stderr.contains("no such command") => {
+            Err(CovError::Toolchain(
+                "cargo-nextest isn't installed. Install it with `cargo install cargo-nextest --locked`, or leave out --runner nextest"
+                    .to_string(),
+            ))
+        }
+        Err(CovError::ToolFailed {
+            status,
+            stdout,
+            stderr,
+            ..
+        }) => Err(CovError::TestsFailed {
+            test: "cargo nextest run".to_string(),
+            status,
+            stdout,
+            stderr,
+        }),
+        result => result.map(|_| ()),
+    };
+    keep_going(config, result, failures)
+}
+
+/// What [`run_nextest`] runs
+pub(crate) fn nextest_command(config: &Config, packages: Option<&[String]>) -> Command {
+    let mut cmd = cargo(config, &["nextest", "run"], packages);
+    add_targets(config, &mut cmd);
+    cmd.env("LLVM_PROFILE_FILE", &config.profile_file)
+        // A filter that matches nothing isn't an error for cargo test either
+        .arg("--no-tests=warn")
+        .args(&config.filter);
+    if config.keep_going {
+        cmd.arg("--no-fail-fast");
+    }
+    if !config.test_args.is_empty() {
+        cmd.arg("--").args(&config.test_args);
+    }
+    cmd
+}
+
+/// Runs the user's own test command in a shell, with the environment set up so that the cargo
+/// it runs builds and runs the tests with instrumentation, in the same target dir [`build`] used.
+/// A failure goes to `failures` with `keep_going`.
+pub fn run_test_command(config: &Config, command: &str, failures: &mut Vec<String>) -> Result<()> {
+    let result = match exec_tests(config, &mut shell_command(config, command)) {
+        Err(CovError::ToolFailed {
+            status,
+            stdout,
+            stderr,
+            ..
+        }) => Err(CovError::TestsFailed {
+            test: command.to_string(),
+            status,
+            stdout,
+            stderr,
+        }),
+        result => result.map(|_| ()),
+    };
+    keep_going(config, result, failures)
+}
+
+/// What [`run_test_command`] runs
+pub(crate) fn shell_command(config: &Config, command: &str) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.current_dir(&config.project_dir)
+        .env("LLVM_PROFILE_FILE", &config.profile_file);
+    if let Some(target_dir) = &config.target_dir {
+        cmd.env("CARGO_TARGET_DIR", target_dir);
+    }
+    add_runner_to_env(config, &mut cmd);
+    add_flags(config, &mut cmd, &RUSTFLAGS, &coverage_flags(config));
+    cmd
+}
+
+/// Runs the doctests with instrumentation, which needs a nightly toolchain for rustdoc's
+/// `--persist-doctests`. Returns the doctest binaries, which llvm-cov needs as objects since
+/// they're built separately from everything else. A failure goes to `failures` with
+/// `keep_going`, and the binaries are still returned.
+pub fn run_doctests(config: &Config, failures: &mut Vec<String>) -> Result<Vec<PathBuf>> {
+    let doctest_dir = profdata::profiles_dir(config).join(DOCTEST_DIR);
+    let mut cmd = doctest_command(config, &doctest_dir);
+
+    let result = match exec_tests(config, &mut cmd) {
+        // Running on stable is a setup problem, not a failing doctest
+        Err(CovError::ToolFailed {
+            status,
+            stdout,
+            stderr,
+            ..
+        }) if !stderr.contains("only accepted on the nightly compiler") => {
+            Err(CovError::TestsFailed {
+                test: "doctests".to_string(),
+                status,
+                stdout,
+                stderr,
+            })
+        }
+        result => result.map(|_| ()),
+    };
+    keep_going(config, result, failures)?;
+
+    let mut binaries = vec![];
+    if doctest_dir.is_dir() {
+        find_doctests(&doctest_dir, &mut binaries)?;
+    }
+    binaries.sort();
+    Ok(binaries)
+}
+
+/// What [`run_doctests`] runs, with rustdoc keeping the binaries in `doctest_dir`
+pub(crate) fn doctest_command(config: &Config, doctest_dir: &Path) -> Command {
+    let mut cmd = cargo_test(config);
+    cmd.arg("--doc")
+        .env("LLVM_PROFILE_FILE", &config.profile_file);
+    let mut rustdocflags = coverage_flags(config);
+    rustdocflags.extend(["-Z", "unstable-options", "--persist-doctests"].map(|f| f.to_string()));
+    rustdocflags.push(doctest_dir.to_string_lossy().into_owned());
+    add_flags(config, &mut cmd, &RUSTDOCFLAGS, &rustdocflags);
+    if config.filter.is_some() || !config.test_args.is_empty() {
+        cmd.arg("--").args(&config.filter).args(&config.test_args);
+    }
+    cmd
+}
+
+fn find_doctests(dir: &Path, binaries: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).map_err(|e| CovError::io(dir, e))? {
+        let entry = entry.map_err(|e| CovError::io(dir, e))?;
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            find_doctests(&path, binaries)?;
+        } else if path.file_stem().is_some_and(|s| s == DOCTEST_BINARY)
+            && path
+                .extension()
+                .is_none_or(|e| e == env::consts::EXE_EXTENSION)
+        {
+            binaries.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! The `serve` command's web server. Just enough HTTP to hand the HTML report to a browser,
+//! for projects in a dev container or on a remote machine where opening the files isn't an
+//! option.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{CovError, Result};
+
+/// Visiting this reruns the tests and regenerates the report
+pub const REGENERATE_PATH: &str = "/regenerate";
+
+/// Added to the top of the report's index page
+const REGENERATE_LINK: &str =
+    "<p><a href=\"/regenerate\">Rerun the tests and regenerate this report</a></p>";
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body.into())
+    }
+}
+
+/// Serves `dir` on `address` until the process is killed, one request at a time. `regenerate`
+/// is called to rebuild the report whenever [`REGENERATE_PATH`] is visited.
+pub fn serve(address: &str, dir: &Path, mut regenerate: impl FnMut() -> Result<()>) -> Result<()> {
+    let listener = TcpListener::bind(address).map_err(|e| CovError::io(address, e))?;
+    for stream in listener.incoming() {
+        // A browser giving up on a connection is no reason to stop serving
+        let handled = stream.and_then(|stream| handle(stream, dir, &mut regenerate));
+        if let Err(e) = handled {
+            eprintln!("warning: couldn't answer a request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle(
+    mut stream: TcpStream,
+    dir: &Path,
+    regenerate: &mut impl FnMut() -> Result<()>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers don't matter, but they have to be read before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+
+    let response = match method {
+        "GET" | "HEAD" if path == REGENERATE_PATH => match regenerate() {
+            Ok(()) => Response {
+                status: "303 See Other",
+                headers: vec![("Location", "/".to_string())],
+                body: vec![],
+            },
+            Err(e) => Response::text(
+                "500 Internal Server Error",
+                format!("Regenerating the report failed:\n\n{}\n", e),
+            ),
+        },
+        "GET" | "HEAD" => file_response(dir, path),
+        _ => Response::text(
+            "405 Method Not Allowed",
+            "Only GET and HEAD are supported\n",
+        ),
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\n", response.status)?;
+    for (name, value) in &response.headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()
+}
+
+fn file_response(dir: &Path, path: &str) -> Response {
+    let Some(mut file) = resolve(dir, path) else {
+        return Response::text("404 Not Found", "Not found\n");
+    };
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    let Ok(mut body) = fs::read(&file) else {
+        return Response::text("404 Not Found", "Not found\n");
+    };
+    if file == dir.join("index.html") {
+        body = with_regenerate_link(body);
+    }
+    Response::new("200 OK", content_type(&file), body)
+}
+
+/// The file a request path points to in `dir`, or `None` if it would point outside it
+fn resolve(dir: &Path, path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path)?;
+    let mut file = dir.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => file.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(file)
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn with_regenerate_link(page: Vec<u8>) -> Vec<u8> {
+    let page = String::from_utf8_lossy(&page);
+    match page.find("<body>") {
+        Some(i) => {
+            let at = i + "<body>".len();
+            format!("{}{}{}", &page[..at], REGENERATE_LINK, &page[at..]).into_bytes()
+        }
+        None => page.into_owned().into_bytes(),
+    }
+}
+
+/// Content types for what llvm-cov puts in an HTML report, and a few things people add to it
+fn content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,591 @@
+//! Checking coverage against the configured minimums.
+
+use std::{cmp::Reverse, collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    Config, Report, Result,
+    codeowners::{self, CodeOwners, OwnerCoverage},
+    config::{OwnerThreshold, PackageThreshold, PathThreshold},
+    export::{ExportFile, Metric},
+    formats, workspace,
+};
+
+/// Every requirement the report doesn't meet, as a message for the user
+pub fn check(config: &Config, report: &Report, root: &Path) -> Result<Vec<String>> {
+    let mut failures = check_thresholds(
+        report,
+        root,
+        config.min_line_coverage,
+        config.min_branch_coverage,
+        config.min_function_coverage,
+        config.min_region_coverage,
+        config.tolerance,
+    );
+    failures.extend(check_file_thresholds(
+        &report.data.files,
+        root,
+        config.min_file_line_coverage,
+        config.min_file_branch_coverage,
+        config.tolerance,
+    ));
+    if config.strict_branch
+        && let Some(missing) = missing_branch_data(config, report)
+    {
+        failures.push(missing);
+    }
+    if let Some(min) = config.min_patch_coverage {
+        match &report.patch {
+            Some(patch) => failures.extend(not_met(
+                Kind::Patch,
+                "",
+                &patch.lines,
+                min,
+                config.tolerance,
+            )),
+            None => failures.push(
+                "Patch coverage requirement set, but there's no diff-base to measure it against"
+                    .to_string(),
+            ),
+        }
+    }
+    if let Some(max) = config.max_coverage_off_lines
+        && report.coverage_off_lines() > max
+    {
+        failures.push(format!(
+            "Too much code behind #[coverage(off)] ({} > {} lines)",
+            report.coverage_off_lines(),
+            max
+        ));
+    }
+    if !config.package_thresholds.is_empty() {
+        let packages = workspace::members(
+            &config.tools.cargo,
+            &config.project_dir,
+            config.manifest_path.as_deref(),
+        )?;
+        failures.extend(check_package_thresholds(
+            &report.data.files,
+            &packages,
+            &config.package_thresholds,
+            config.tolerance,
+        ));
+    }
+    failures.extend(report.paths.iter().flat_map(|p| p.failures.iter().cloned()));
+    if !config.owner_thresholds.is_empty() {
+        let repository_root = codeowners::repository_root(config)?;
+        match CodeOwners::find(&repository_root)? {
+            Some(owners) => failures.extend(check_owner_thresholds(
+                &codeowners::by_owner(&report.data, &owners, &repository_root),
+                &config.owner_thresholds,
+                config.tolerance,
+            )),
+            None => failures.push(
+                "Owner thresholds set, but there's no CODEOWNERS in .github/, docs/ or the root of the repository"
+                    .to_string(),
+            ),
+        }
+    }
+    Ok(failures)
+}
+
+/// Checks each owner's files against that owner's threshold
+pub fn check_owner_thresholds(
+    owners: &BTreeMap<Option<String>, OwnerCoverage>,
+    thresholds: &[OwnerThreshold],
+    tolerance: f32,
+) -> Vec<String> {
+    let mut failures = vec![];
+    for threshold in thresholds {
+        let Some(coverage) = owners.get(&Some(threshold.owner.clone())) else {
+            failures.push(format!(
+                "{} doesn't own any of the covered files in CODEOWNERS",
+                threshold.owner
+            ));
+            continue;
+        };
+        let of = format!(" for {}", threshold.owner);
+        failures.extend(not_met(
+            Kind::Lines,
+            &of,
+            &coverage.lines,
+            threshold.min_line_coverage,
+            tolerance,
+        ));
+        if let Some(min) = threshold.min_branch_coverage {
+            failures.extend(not_met(
+                Kind::Branches,
+                &of,
+                &coverage.branches,
+                min,
+                tolerance,
+            ));
+        }
+    }
+    failures
+}
+
+/// How the files matching one of the path thresholds did against it
+#[derive(Debug, Serialize)]
+pub struct PathCoverage {
+    pub pattern: String,
+    pub min_line_coverage: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_branch_coverage: Option<f32>,
+    /// Over every file that counts towards the pattern
+    pub lines: Metric,
+    pub branches: Metric,
+    /// The files that count towards the pattern, relative to the project dir, in path order
+    pub files: Vec<PathFile>,
+    /// The minimums that weren't met, as messages for the user
+    pub failures: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PathFile {
+    pub path: String,
+    pub lines: Metric,
+}
+
+/// Groups the files by the most specific path threshold they match and checks each group's
+/// totals against it. Files that match none aren't in any group.
+pub fn check_paths(config: &Config, files: &[ExportFile], root: &Path) -> Vec<PathCoverage> {
+    let mut paths: Vec<PathCoverage> = config
+        .path_thresholds
+        .iter()
+        .map(|t| PathCoverage {
+            pattern: t.pattern.clone(),
+            min_line_coverage: t.min_line_coverage,
+            min_branch_coverage: t.min_branch_coverage,
+            lines: Metric::default(),
+            branches: Metric::default(),
+            files: vec![],
+            failures: vec![],
+        })
+        .collect();
+    let mut files: Vec<_> = files
+        .iter()
+        .map(|f| (formats::relative_path(&f.filename, root), f))
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, file) in files {
+        let Some(index) = most_specific(&config.path_thresholds, &path) else {
+            continue;
+        };
+        let group = &mut paths[index];
+        group.lines.add(&file.summary.lines);
+        group.branches.add(&file.summary.branches);
+        group.files.push(PathFile {
+            path,
+            lines: file.summary.lines.clone(),
+        });
+    }
+
+    for group in &mut paths {
+        let of = format!(" for {}", group.pattern);
+        group.failures.extend(not_met(
+            Kind::Lines,
+            &of,
+            &group.lines,
+            group.min_line_coverage,
+            config.tolerance,
+        ));
+        if let Some(min) = group.min_branch_coverage {
+            group.failures.extend(not_met(
+                Kind::Branches,
+                &of,
+                &group.branches,
+                min,
+                config.tolerance,
+            ));
+        }
+    }
+    paths
+}
+
+/// Which of `thresholds` `path` counts towards: the longest pattern it matches
+fn most_specific(thresholds: &[PathThreshold], path: &str) -> Option<usize> {
+    thresholds
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.glob.is_match(path))
+        .max_by_key(|(_, t)| t.pattern.len())
+        .map(|(index, _)| index)
+}
+
+/// Every warn-only minimum the report doesn't meet, as a message for the user
+pub fn check_soft(config: &Config, report: &Report) -> Vec<String> {
+    let totals = &report.data.totals;
+    [
+        (Kind::Lines, &totals.lines, config.warn_line_coverage),
+        (
+            Kind::Branches,
+            &totals.branches,
+            config.warn_branch_coverage,
+        ),
+        (
+            Kind::Functions,
+            &totals.functions,
+            config.warn_function_coverage,
+        ),
+        (Kind::Regions, &totals.regions, config.warn_region_coverage),
+    ]
+    .into_iter()
+    .filter_map(|(kind, metric, min)| {
+        let min = min.filter(|min| under(metric.fraction(), *min, config.tolerance))?;
+        Some(format!(
+            "{} coverage is under its warning level ({}). {}",
+            kind.name(),
+            below(metric.fraction(), min),
+            to_meet(kind, metric, min, config.tolerance)
+        ))
+    })
+    .collect()
+}
+
+/// Why the branch threshold couldn't be checked, if it couldn't. A threshold of 0 doesn't ask for
+/// branch coverage, so it doesn't count.
+pub fn missing_branch_data(config: &Config, report: &Report) -> Option<String> {
+    (config.min_branch_coverage > 0.0 && !report.has_branch_data()).then(|| {
+        format!(
+            "No branch coverage data, so the branch coverage requirement ({:.2}%) wasn't checked. Pass --branch on a nightly toolchain to instrument branches",
+            config.min_branch_coverage * 100.
+        )
+    })
+}
+
+pub fn check_thresholds(
+    report: &Report,
+    root: &Path,
+    min_line_coverage: f32,
+    min_branch_coverage: f32,
+    min_function_coverage: Option<f32>,
+    min_region_coverage: Option<f32>,
+    tolerance: f32,
+) -> Vec<String> {
+    let totals = &report.data.totals;
+    let mut failures = vec![];
+    if let Some(failure) = not_met(Kind::Lines, "", &totals.lines, min_line_coverage, tolerance) {
+        let needed = needed(&totals.lines, min_line_coverage, tolerance);
+        failures.push(failure + &where_to_cover(report, root, needed));
+    }
+    failures.extend(not_met(
+        Kind::Branches,
+        "",
+        &totals.branches,
+        min_branch_coverage,
+        tolerance,
+    ));
+    for (kind, metric, min) in [
+        (Kind::Functions, &totals.functions, min_function_coverage),
+        (Kind::Regions, &totals.regions, min_region_coverage),
+    ] {
+        if let Some(min) = min {
+            failures.extend(not_met(kind, "", metric, min, tolerance));
+        }
+    }
+    failures
+}
+
+/// One failure per file below either per-file minimum
+pub fn check_file_thresholds(
+    files: &[ExportFile],
+    root: &Path,
+    min_file_line_coverage: Option<f32>,
+    min_file_branch_coverage: Option<f32>,
+    tolerance: f32,
+) -> Vec<String> {
+    let mut failures = vec![];
+    for file in files {
+        let of = format!(" for {}", formats::relative_path(&file.filename, root));
+        if let Some(min) = min_file_line_coverage {
+            failures.extend(not_met(
+                Kind::Lines,
+                &of,
+                &file.summary.lines,
+                min,
+                tolerance,
+            ));
+        }
+        if let Some(min) = min_file_branch_coverage {
+            failures.extend(not_met(
+                Kind::Branches,
+                &of,
+                &file.summary.branches,
+                min,
+                tolerance,
+            ));
+        }
+    }
+    failures
+}
+
+/// Totals up each workspace member's files and checks them against that member's threshold
+pub fn check_package_thresholds(
+    files: &[ExportFile],
+    packages: &[workspace::Package],
+    thresholds: &[PackageThreshold],
+    tolerance: f32,
+) -> Vec<String> {
+    let mut failures = vec![];
+    for threshold in thresholds {
+        if !packages.iter().any(|p| p.name == threshold.package) {
+            failures.push(format!(
+                "Package {} is not a member of this workspace",
+                threshold.package
+            ));
+            continue;
+        }
+
+        let package_files = files.iter().filter(|f| {
+            workspace::package_for(packages, &f.filename)
+                .is_some_and(|p| p.name == threshold.package)
+        });
+        let mut lines = Metric::default();
+        let mut branches = Metric::default();
+        for file in package_files {
+            lines.add(&file.summary.lines);
+            branches.add(&file.summary.branches);
+        }
+
+        let of = format!(" for package {}", threshold.package);
+        failures.extend(not_met(
+            Kind::Lines,
+            &of,
+            &lines,
+            threshold.min_line_coverage,
+            tolerance,
+        ));
+        if let Some(min) = threshold.min_branch_coverage {
+            failures.extend(not_met(Kind::Branches, &of, &branches, min, tolerance));
+        }
+    }
+    failures
+}
+
+/// What a minimum is of
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Lines,
+    Branches,
+    Functions,
+    Regions,
+    /// The lines changed since the diff base
+    Patch,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Lines => "Line",
+            Kind::Branches => "Branch",
+            Kind::Functions => "Function",
+            Kind::Regions => "Region",
+            Kind::Patch => "Patch",
+        }
+    }
+
+    /// What's covered, for `count` of them, like `line` or `changed lines`
+    fn noun(self, count: u64) -> &'static str {
+        let (one, many) = match self {
+            Kind::Lines => ("line", "lines"),
+            Kind::Branches => ("branch", "branches"),
+            Kind::Functions => ("function", "functions"),
+            Kind::Regions => ("region", "regions"),
+            Kind::Patch => ("changed line", "changed lines"),
+        };
+        if count == 1 { one } else { many }
+    }
+}
+
+/// The failure for `metric` under `min`, like `Line coverage requirement not met for src/lib.rs
+/// (88.89% < 95.00%). Cover 1 more line to meet it`, if it is. `of` says whose coverage it is.
+fn not_met(kind: Kind, of: &str, metric: &Metric, min: f32, tolerance: f32) -> Option<String> {
+    under(metric.fraction(), min, tolerance).then(|| {
+        format!(
+            "{} coverage requirement not met{} ({}). {}",
+            kind.name(),
+            of,
+            below(metric.fraction(), min),
+            to_meet(kind, metric, min, tolerance)
+        )
+    })
+}
+
+/// Like `Cover 3 more lines to meet it`
+fn to_meet(kind: Kind, metric: &Metric, min: f32, tolerance: f32) -> String {
+    let needed = needed(metric, min, tolerance);
+    format!("Cover {} more {} to meet it", needed, kind.noun(needed))
+}
+
+/// How many more of what `metric` counts would need to be covered to meet `min`
+fn needed(metric: &Metric, min: f32, tolerance: f32) -> u64 {
+    let meets = |covered: u64| !under(covered as f32 / metric.count as f32, min, tolerance);
+    // A first guess from the fraction, then corrected for how the f32s round
+    let target = ((min - tolerance).max(0.0) as f64 * metric.count as f64).ceil() as u64;
+    let mut needed = target.saturating_sub(metric.covered);
+    while needed > 0 && meets(metric.covered + needed - 1) {
+        needed -= 1;
+    }
+    while !meets(metric.covered + needed) && metric.covered + needed < metric.count {
+        needed += 1;
+    }
+    needed
+}
+
+/// The fewest files with `needed` uncovered lines between them, most uncovered first, as the
+/// end of a sentence saying to cover that many lines
+fn where_to_cover(report: &Report, root: &Path, needed: u64) -> String {
+    /// Past this many, the rest of the files are just counted
+    const LISTED: usize = 5;
+
+    let mut files = formats::least_covered(&report.data);
+    files.sort_by_key(|f| Reverse(f.summary.lines.count - f.summary.lines.covered));
+    let mut candidates = vec![];
+    let mut uncovered = 0;
+    for file in files {
+        if uncovered >= needed {
+            break;
+        }
+        let lines = &file.summary.lines;
+        uncovered += lines.count - lines.covered;
+        candidates.push(format!(
+            "{} ({} uncovered)",
+            formats::relative_path(&file.filename, root),
+            lines.count - lines.covered
+        ));
+    }
+    if candidates.len() > LISTED {
+        let more = candidates.len() - LISTED;
+        candidates.truncate(LISTED);
+        candidates.push(format!("{} more files", more));
+    }
+    match candidates.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!(", like in {}", last),
+        Some((last, rest)) => format!(", like in {} and {}", rest.join(", "), last),
+    }
+}
+
+/// Whether `coverage` doesn't meet `min`, even allowing for `tolerance`
+fn under(coverage: f32, min: f32, tolerance: f32) -> bool {
+    coverage < min - tolerance
+}
+
+/// Coverage under its minimum, as `88.89% < 90.00%`, with more decimal places if that's what it
+/// takes for them not to look the same
+pub(crate) fn below(coverage: f32, min: f32) -> String {
+    let mut places = 2;
+    loop {
+        let coverage = format!("{:.*}%", places, coverage * 100.);
+        let min = format!("{:.*}%", places, min * 100.);
+        if coverage != min || places == 6 {
+            return format!("{} < {}", coverage, min);
+        }
+        places += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::export::{ExportData, Summary};
+
+    fn report(files: &[(&str, u64, u64)]) -> Report {
+        let files = files
+            .iter()
+            .map(|&(filename, count, covered)| ExportFile {
+                filename: format!("/repo/{}", filename),
+                segments: vec![],
+                branches: vec![],
+                summary: Summary {
+                    lines: Metric::new(count, covered),
+                    ..Summary::default()
+                },
+                excluded_lines: BTreeSet::new(),
+            })
+            .collect();
+        Report::from_export(ExportData {
+            files,
+            functions: vec![],
+            totals: Summary::default(),
+        })
+    }
+
+    #[test]
+    fn needed_at_the_boundary() {
+        // (count, covered, min, tolerance, needed)
+        let cases = [
+            // 8 of 10 is exactly 80%
+            (10, 7, 0.8, 0.0, 1),
+            // 0.3 is a little over 0.3 as an f32, which 3 of 10 still meets
+            (10, 0, 0.3, 0.0, 3),
+            // 79.99% isn't 80%
+            (10_000, 7_999, 0.8, 0.0, 1),
+            // 2 of 3 is just under 66.67%, so only all 3 meet it
+            (3, 2, 0.6667, 0.0, 1),
+            (10_000, 7_999, 0.8, 0.005, 0),
+            // Already met
+            (10, 9, 0.8, 0.0, 0),
+            (10, 10, 1.0, 0.0, 0),
+            (10, 0, 0.0, 0.0, 0),
+            // Nothing to cover
+            (0, 0, 0.8, 0.0, 0),
+        ];
+        for (count, covered, min, tolerance, expected) in cases {
+            let metric = Metric::new(count, covered);
+            assert_eq!(
+                needed(&metric, min, tolerance),
+                expected,
+                "{} of {} against {} with {}",
+                covered,
+                count,
+                min,
+                tolerance
+            );
+        }
+    }
+
+    #[test]
+    fn where_to_cover_lists_the_fewest_files_with_enough_uncovered_lines() {
+        let report = report(&[
+            ("src/a.rs", 4, 3),
+            ("src/b.rs", 10, 2),
+            ("src/covered.rs", 5, 5),
+            ("src/empty.rs", 0, 0),
+        ]);
+        let root = Path::new("/repo");
+        assert_eq!(
+            where_to_cover(&report, root, 3),
+            ", like in src/b.rs (8 uncovered)"
+        );
+        assert_eq!(
+            where_to_cover(&report, root, 8),
+            ", like in src/b.rs (8 uncovered)"
+        );
+        assert_eq!(
+            where_to_cover(&report, root, 9),
+            ", like in src/b.rs (8 uncovered) and src/a.rs (1 uncovered)"
+        );
+        assert_eq!(where_to_cover(&report, root, 0), "");
+        // Files with nothing to cover are never suggested, even when it isn't enough
+        assert_eq!(
+            where_to_cover(&report, root, 100),
+            ", like in src/b.rs (8 uncovered) and src/a.rs (1 uncovered)"
+        );
+    }
+
+    #[test]
+    fn where_to_cover_counts_the_files_past_the_first_few() {
+        let names: Vec<String> = (1..=7).map(|n| format!("src/{}.rs", n)).collect();
+        let files: Vec<_> = names.iter().map(|n| (n.as_str(), 2, 1)).collect();
+        assert_eq!(
+            where_to_cover(&report(&files), Path::new("/repo"), 7),
+            ", like in src/1.rs (1 uncovered), src/2.rs (1 uncovered), src/3.rs (1 uncovered), \
+             src/4.rs (1 uncovered), src/5.rs (1 uncovered) and 2 more files"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Finding the llvm tools. `rust-profdata` and `rust-cov` from cargo-binutils are only
+//! wrappers around the binaries the `llvm-tools` component puts in the sysroot, which work just
+//! as well without them, and failing those, an LLVM install's own will do if its version
+//! matches the toolchain's.
+
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use crate::{
+    CovError, Result,
+    config::{ToolSettings, Tools},
+    exec,
+};
+
+pub const DEFAULT_LLVM_PROFDATA: &str = "rust-profdata";
+pub const DEFAULT_LLVM_COV: &str = "rust-cov";
+/// Installs the binaries behind the default tools
+pub const INSTALL_COMMAND: [&str; 4] = ["rustup", "component", "add", "llvm-tools"];
+
+/// The tools to use. The llvm tools that weren't configured and aren't on the PATH under their
+/// default names are replaced by the component's own binaries if those are installed, or else
+/// the plain `llvm-profdata` and `llvm-cov` if those are on the PATH. Ones that can't be found
+/// at all keep their default names, for the errors to mention.
+pub fn locate(settings: ToolSettings, project_dir: &Path) -> Tools {
+    let configured = settings.clone();
+    let mut tools = Tools::from(settings);
+    let mut bin_dir = None;
+    for (setting, tool, name) in [
+        (
+            &configured.llvm_profdata,
+            &mut tools.llvm_profdata,
+            "llvm-profdata",
+        ),
+        (&configured.llvm_cov, &mut tools.llvm_cov, "llvm-cov"),
+    ] {
+        if setting.is_some() || version(tool).is_ok() {
+            continue;
+        }
+        let dir = bin_dir.get_or_insert_with(|| sysroot_bin_dir(project_dir));
+        let path = dir
+            .as_ref()
+            .map(|d| d.join(format!("{}{}", name, env::consts::EXE_SUFFIX)));
+        if let Some(path) = path.filter(|p| p.is_file()) {
+            *tool = path.to_string_lossy().into_owned();
+        } else if version(name).is_ok() {
+            *tool = name.to_string();
+        }
+    }
+    tools
+}
+
+/// Fails the same way a run would without the llvm tools, but before any tests have run
+pub fn check(tools: &Tools) -> Result<()> {
+    for tool in [&tools.llvm_profdata, &tools.llvm_cov] {
+        version(tool).map_err(|source| CovError::Spawn {
+            tool: tool.clone(),
+            source,
+        })?;
+    }
+    Ok(())
+}
+
+/// Whether [`install`] would provide a tool that couldn't be run. Only the default names are,
+/// since a configured path that's missing won't appear by installing anything.
+pub fn installable(tool: &str) -> bool {
+    tool == DEFAULT_LLVM_PROFDATA || tool == DEFAULT_LLVM_COV
+}
+
+/// Adds the `llvm-tools` component to the toolchain the project dir uses
+pub fn install(project_dir: &Path) -> Result<()> {
+    let [program, args @ ..] = INSTALL_COMMAND;
+    exec(Command::new(program).args(args).current_dir(project_dir)).map(|_| ())
+}
+
+/// Runs the tool just to see that it can be started
+pub(crate) fn version(tool: &str) -> io::Result<ExitStatus> {
+    Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+}
+
+/// The output of rustc in the project dir, where a rust-toolchain.toml can pick the toolchain
+pub(crate) fn rustc_output(project_dir: &Path, args: &[&str]) -> Option<String> {
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    exec(Command::new(&rustc).args(args).current_dir(project_dir))
+        .ok()
+        .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+}
+
+/// A field of `rustc -vV`, like `release: ` or `LLVM version: `
+pub(crate) fn rustc_field<'a>(info: &'a str, prefix: &str) -> Option<&'a str> {
+    info.lines().find_map(|l| l.strip_prefix(prefix))
+}
+
+/// The LLVM version of an llvm tool, like `22.1.2-rust-1.95.0-stable`. Older llvm-profdata
+/// only answers `--version` after a subcommand.
+pub(crate) fn llvm_version(tool: &str) -> Option<String> {
+    [&["--version"][..], &["merge", "--version"]]
+        .into_iter()
+        .find_map(|args| {
+            let output = Command::new(tool).args(args).output().ok()?;
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(|l| {
+                    l.split_once("LLVM version ")
+                        .map(|(_, v)| v.trim().to_string())
+                })
+        })
+}
+
+/// Where the `llvm-tools` component goes in the toolchain the project dir uses
+fn sysroot_bin_dir(project_dir: &Path) -> Option<PathBuf> {
+    let sysroot = rustc_output(project_dir, &["--print", "sysroot"])?;
+    let info = rustc_output(project_dir, &["-vV"])?;
+    let host = rustc_field(&info, "host: ")?;
+    Some(
+        Path::new(sysroot.trim())
+            .join("lib")
+            .join("rustlib")
+            .join(host)
+            .join("bin"),
+    )
+}
@@ -0,0 +1,410 @@
+//! The `tui` command: an interactive browser of the per-file coverage, for digging through a
+//! project that's too big to read as a table.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+};
+
+use crate::{CovError, Report, Result, export::Metric, formats};
+
+/// Lines kept above an uncovered range when jumping to it
+const CONTEXT: usize = 2;
+
+struct FileEntry {
+    path: String,
+    lines: Metric,
+    /// Hit count of every instrumented line
+    counts: BTreeMap<u64, u64>,
+    uncovered: Vec<(u64, u64)>,
+    /// The source lines, or why they couldn't be read
+    source: std::result::Result<Vec<String>, String>,
+}
+
+impl FileEntry {
+    fn uncovered_count(&self) -> u64 {
+        self.lines.count - self.lines.covered
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sort {
+    Coverage,
+    Uncovered,
+    Name,
+}
+
+impl Sort {
+    fn next(self) -> Sort {
+        match self {
+            Sort::Coverage => Sort::Uncovered,
+            Sort::Uncovered => Sort::Name,
+            Sort::Name => Sort::Coverage,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Sort::Coverage => "coverage",
+            Sort::Uncovered => "uncovered lines",
+            Sort::Name => "name",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Focus {
+    Files,
+    Source,
+}
+
+struct App {
+    files: Vec<FileEntry>,
+    /// Indices into `files` of the ones matching the filter, in display order
+    visible: Vec<usize>,
+    list: ListState,
+    sort: Sort,
+    reversed: bool,
+    filter: String,
+    editing_filter: bool,
+    focus: Focus,
+    /// Source lines scrolled past
+    scroll: usize,
+    /// Rows the source pane had when it was last drawn, for paging
+    source_rows: usize,
+}
+
+/// Opens the browser on the report's files and blocks until it's closed. Needs line-level data,
+/// see [`crate::Config::line_data`].
+pub fn browse(report: &Report, root: &Path) -> Result<()> {
+    let files = report
+        .data
+        .files
+        .iter()
+        .map(|file| {
+            let counts: BTreeMap<u64, u64> = file
+                .line_counts()
+                .into_iter()
+                .map(|l| (l.line, l.count))
+                .collect();
+            FileEntry {
+                path: formats::relative_path(&file.filename, root),
+                lines: file.summary.lines.clone(),
+                uncovered: formats::line_ranges(
+                    counts.iter().filter(|(_, c)| **c == 0).map(|(l, _)| *l),
+                ),
+                counts,
+                source: fs::read_to_string(&file.filename)
+                    .map(|s| s.lines().map(|l| l.replace('\t', "    ")).collect())
+                    .map_err(|e| e.to_string()),
+            }
+        })
+        .collect();
+
+    let mut app = App {
+        files,
+        visible: vec![],
+        list: ListState::default(),
+        sort: Sort::Coverage,
+        reversed: false,
+        filter: String::new(),
+        editing_filter: false,
+        focus: Focus::Files,
+        scroll: 0,
+        source_rows: 0,
+    };
+    app.refresh();
+
+    let mut terminal = ratatui::try_init().map_err(CovError::Terminal)?;
+    let result = app.run(&mut terminal);
+    let restored = ratatui::try_restore();
+    result.and(restored).map_err(CovError::Terminal)
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && !self.handle_key(key)
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<&FileEntry> {
+        self.list
+            .selected()
+            .and_then(|i| self.visible.get(i))
+            .map(|&i| &self.files[i])
+    }
+
+    /// Re-applies the filter and sort, keeping the same file selected if it's still listed
+    fn refresh(&mut self) {
+        let selected = self
+            .list
+            .selected()
+            .and_then(|i| self.visible.get(i))
+            .copied();
+        let filter = self.filter.to_lowercase();
+        let mut visible: Vec<usize> = (0..self.files.len())
+            .filter(|&i| self.files[i].path.to_lowercase().contains(&filter))
+            .collect();
+
+        let files = &self.files;
+        visible.sort_by(|&a, &b| {
+            let (a, b) = (&files[a], &files[b]);
+            let order = match self.sort {
+                Sort::Coverage => a.lines.fraction().total_cmp(&b.lines.fraction()),
+                Sort::Uncovered => b.uncovered_count().cmp(&a.uncovered_count()),
+                Sort::Name => a.path.cmp(&b.path),
+            };
+            order.then_with(|| a.path.cmp(&b.path))
+        });
+        if self.reversed {
+            visible.reverse();
+        }
+
+        let position = selected.and_then(|s| visible.iter().position(|&i| i == s));
+        if position.is_none() {
+            self.scroll = 0;
+        }
+        self.visible = visible;
+        self.list
+            .select((!self.visible.is_empty()).then(|| position.unwrap_or(0)));
+    }
+
+    /// Handles a key press, returning false when it's time to quit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if self.editing_filter {
+            match key.code {
+                KeyCode::Char(c) => self.filter.push(c),
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing_filter = false;
+                }
+                _ => return true,
+            }
+            self.refresh();
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Files => Focus::Source,
+                    Focus::Source => Focus::Files,
+                }
+            }
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => self.focus = Focus::Source,
+            KeyCode::Left | KeyCode::Char('h') => self.focus = Focus::Files,
+            KeyCode::Char('/') => self.editing_filter = true,
+            KeyCode::Char('s') => {
+                self.sort = self.sort.next();
+                self.refresh();
+            }
+            KeyCode::Char('r') => {
+                self.reversed = !self.reversed;
+                self.refresh();
+            }
+            KeyCode::Char('n') => self.jump_uncovered(true),
+            KeyCode::Char('N') => self.jump_uncovered(false),
+            _ if self.focus == Focus::Files => self.handle_files_key(key.code),
+            _ => self.handle_source_key(key.code),
+        }
+        true
+    }
+
+    fn handle_files_key(&mut self, code: KeyCode) {
+        let before = self.list.selected();
+        match code {
+            KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+            KeyCode::Home | KeyCode::Char('g') => self.list.select_first(),
+            KeyCode::End | KeyCode::Char('G') => self.list.select_last(),
+            _ => {}
+        }
+        // The list clamps the selection when it's drawn, which is too late for picking the source
+        if let Some(i) = self.list.selected() {
+            self.list
+                .select(Some(i.min(self.visible.len().saturating_sub(1))));
+        }
+        if self.list.selected() != before {
+            self.scroll = 0;
+        }
+    }
+
+    fn handle_source_key(&mut self, code: KeyCode) {
+        let page = self.source_rows.max(1);
+        self.scroll = match code {
+            KeyCode::Down | KeyCode::Char('j') => self.scroll + 1,
+            KeyCode::Up | KeyCode::Char('k') => self.scroll.saturating_sub(1),
+            KeyCode::PageDown | KeyCode::Char(' ') => self.scroll + page,
+            KeyCode::PageUp => self.scroll.saturating_sub(page),
+            KeyCode::Home | KeyCode::Char('g') => 0,
+            KeyCode::End | KeyCode::Char('G') => usize::MAX,
+            _ => self.scroll,
+        };
+        self.clamp_scroll();
+    }
+
+    /// Scrolls the source to the next or previous run of uncovered lines
+    fn jump_uncovered(&mut self, forward: bool) {
+        let Some(file) = self.selected() else {
+            return;
+        };
+        // The line a jump puts an uncovered range on
+        let anchor = (self.scroll + CONTEXT + 1) as u64;
+        let mut starts = file.uncovered.iter().map(|(start, _)| *start);
+        let target = if forward {
+            starts.find(|&s| s > anchor)
+        } else {
+            starts.rfind(|&s| s < anchor)
+        };
+        if let Some(line) = target {
+            self.scroll = (line as usize).saturating_sub(CONTEXT + 1);
+            self.focus = Focus::Source;
+            self.clamp_scroll();
+        }
+    }
+
+    fn clamp_scroll(&mut self) {
+        let lines = self
+            .selected()
+            .and_then(|f| f.source.as_ref().ok())
+            .map_or(0, |s| s.len());
+        self.scroll = self.scroll.min(lines.saturating_sub(self.source_rows));
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [files_area, source_area] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                .areas(main);
+
+        let current = self.focus;
+        let focused = move |focus: Focus| {
+            if current == focus {
+                Style::default().fg(Color::Cyan)
+            } else {
+                Style::default()
+            }
+        };
+
+        let mut title = format!(" Files by {}", self.sort.label());
+        if self.reversed {
+            title.push_str(", reversed");
+        }
+        if !self.filter.is_empty() {
+            title.push_str(&format!(", matching \"{}\"", self.filter));
+        }
+        title.push(' ');
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|&i| {
+                let file = &self.files[i];
+                ListItem::new(Line::from(vec![
+                    Span::styled(
+                        format!("{:>7.2}%", file.lines.percent),
+                        Style::default().fg(coverage_color(&file.lines)),
+                    ),
+                    Span::raw(format!(" {:>5}  {}", file.uncovered_count(), file.path)),
+                ]))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::bordered()
+                    .title(title)
+                    .border_style(focused(Focus::Files)),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, files_area, &mut self.list);
+
+        // Minus the borders
+        self.source_rows = source_area.height.saturating_sub(2) as usize;
+        self.clamp_scroll();
+        let source_block = Block::bordered().border_style(focused(Focus::Source));
+        let source = match self.selected() {
+            None => Paragraph::new("No files match the filter").block(source_block),
+            Some(file) => {
+                let block = source_block.title(format!(
+                    " {}  {}/{} lines ",
+                    file.path, file.lines.covered, file.lines.count
+                ));
+                match &file.source {
+                    Err(e) => Paragraph::new(format!("Couldn't read the source: {}", e)),
+                    Ok(lines) => Paragraph::new(
+                        lines
+                            .iter()
+                            .enumerate()
+                            .skip(self.scroll)
+                            .take(self.source_rows)
+                            .map(|(i, text)| source_line(i as u64 + 1, text, &file.counts))
+                            .collect::<Vec<_>>(),
+                    ),
+                }
+                .block(block)
+            }
+        };
+        frame.render_widget(source, source_area);
+
+        let help = if self.editing_filter {
+            format!("Filter: {}_   Enter keep   Esc clear", self.filter)
+        } else {
+            "q quit   Tab switch pane   j/k move   n/N next/previous uncovered   / filter   s sort   r reverse"
+                .to_string()
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::default().add_modifier(Modifier::DIM)),
+            status,
+        );
+    }
+}
+
+/// One line of source behind its line number and hit count, highlighted when it never ran
+fn source_line<'a>(number: u64, text: &'a str, counts: &BTreeMap<u64, u64>) -> Line<'a> {
+    let gutter = Style::default().add_modifier(Modifier::DIM);
+    let (count, style) = match counts.get(&number) {
+        None => (String::new(), Style::default()),
+        Some(0) => (
+            "0".to_string(),
+            Style::default().bg(Color::Red).fg(Color::White),
+        ),
+        Some(count) => (count.to_string(), Style::default()),
+    };
+    let count_style = match counts.get(&number) {
+        Some(0) => Style::default().fg(Color::Red),
+        Some(_) => Style::default().fg(Color::Green),
+        None => gutter,
+    };
+    Line::from(vec![
+        Span::styled(format!("{:>5} ", number), gutter),
+        Span::styled(format!("{:>7} ", count), count_style),
+        Span::styled("│ ", gutter),
+        Span::styled(text, style),
+    ])
+}
+
+fn coverage_color(lines: &Metric) -> Color {
+    match lines.fraction() {
+        f if f >= 1.0 => Color::Green,
+        f if f >= 0.75 => Color::Yellow,
+        _ => Color::Red,
+    }
+}
@@ -0,0 +1,77 @@
+//! Uploads through Codecov's v4 upload endpoint, the same one the old bash uploader used.
+
+use std::env;
+
+use crate::{CovError, Result};
+
+use super::{CiInfo, curl, url_encode};
+
+const DEFAULT_URL: &str = "https://codecov.io";
+
+/// Uploads a Cobertura report. `CODECOV_TOKEN` is required for private repositories, and
+/// `CODECOV_URL` points at a self-hosted instance.
+pub fn upload(cobertura: &str, ci: &CiInfo) -> Result<()> {
+    let base_url = env::var("CODECOV_URL").unwrap_or(DEFAULT_URL.to_string());
+
+    let mut params = vec![
+        (
+            "package",
+            format!("simple-rust-cov-{}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("commit", ci.commit.clone()),
+    ];
+    if let Ok(token) = env::var("CODECOV_TOKEN") {
+        params.push(("token", token));
+    }
+    for (name, value) in [
+        ("service", ci.service.map(|s| s.to_string())),
+        ("branch", ci.branch.clone()),
+        ("pr", ci.pr.clone()),
+        ("build", ci.build.clone()),
+        ("build_url", ci.build_url.clone()),
+        ("slug", ci.slug.clone()),
+    ] {
+        if let Some(value) = value {
+            params.push((name, value));
+        }
+    }
+    let query: Vec<String> = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, url_encode(value)))
+        .collect();
+
+    // The first request hands back the report URL and a pre-signed URL to PUT the report to.
+    // The URL goes to curl as a config file on stdin, since the token in it would show up in
+    // the process list on the command line.
+    let url = format!("{}/upload/v4?{}", base_url, query.join("&"));
+    let config = format!(
+        "url = \"{}\"\n",
+        url.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let response = curl(
+        &["-X", "POST", "-H", "Accept: text/plain", "--config", "-"],
+        Some(config.as_bytes()),
+    )?;
+    let mut lines = response.lines();
+    let report_url = lines.next().unwrap_or_default().to_string();
+    let upload_url = lines.next().ok_or_else(|| {
+        CovError::Upload(format!("unexpected response from Codecov: {}", response))
+    })?;
+
+    let body = format!("# path=coverage.xml\n{}\n<<<<<< EOF\n", cobertura);
+    curl(
+        &[
+            "-X",
+            "PUT",
+            "-H",
+            "Content-Type: text/plain",
+            "--data-binary",
+            "@-",
+            upload_url,
+        ],
+        Some(body.as_bytes()),
+    )?;
+
+    eprintln!("Uploaded coverage to Codecov: {}", report_url);
+    Ok(())
+}
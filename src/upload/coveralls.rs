@@ -0,0 +1,123 @@
+//! Uploads through the Coveralls jobs API (<https://docs.coveralls.io/api-reference>).
+
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use md5::{Digest, Md5};
+use serde::Serialize;
+
+use crate::{CovError, Result, export::ExportData, formats::relative_path};
+
+use super::{CiInfo, curl};
+
+const DEFAULT_URL: &str = "https://coveralls.io";
+
+#[derive(Debug, Serialize)]
+struct Job {
+    repo_token: String,
+    service_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_pull_request: Option<String>,
+    git: Git,
+    source_files: Vec<SourceFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct Git {
+    head: Head,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branch: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Head {
+    id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SourceFile {
+    name: String,
+    source_digest: String,
+    /// Hits per line, starting at line 1, with `None` for lines that aren't instrumented
+    coverage: Vec<Option<u64>>,
+    /// Flattened `[line, block, branch, hits]` quadruples
+    branches: Vec<u64>,
+}
+
+/// Uploads the per-line data, with paths relative to `root` (the repository root). Needs
+/// `COVERALLS_REPO_TOKEN`, or `GITHUB_TOKEN` on GitHub Actions. `COVERALLS_ENDPOINT` points at
+/// Coveralls Enterprise.
+pub fn upload(data: &ExportData, root: &Path, ci: &CiInfo) -> Result<()> {
+    let base_url = env::var("COVERALLS_ENDPOINT").unwrap_or(DEFAULT_URL.to_string());
+    let repo_token = env::var("COVERALLS_REPO_TOKEN")
+        .or_else(|_| env::var("GITHUB_TOKEN"))
+        .map_err(|_| {
+            CovError::Upload("COVERALLS_REPO_TOKEN must be set to upload to Coveralls".to_string())
+        })?;
+
+    let mut source_files = vec![];
+    for file in &data.files {
+        let source = fs::read(&file.filename).map_err(|e| CovError::io(&file.filename, e))?;
+        let line_count = String::from_utf8_lossy(&source).lines().count();
+
+        let mut coverage = vec![None; line_count];
+        for line in file.line_counts() {
+            if let Some(hits) = coverage.get_mut(line.line as usize - 1) {
+                *hits = Some(line.count);
+            }
+        }
+
+        let mut blocks: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut branches = vec![];
+        for branch in &file.branches {
+            let block = blocks.entry(branch.line_start).or_default();
+            branches.extend([branch.line_start, *block, 0, branch.true_count]);
+            branches.extend([branch.line_start, *block, 1, branch.false_count]);
+            *block += 1;
+        }
+
+        source_files.push(SourceFile {
+            name: relative_path(&file.filename, root),
+            source_digest: format!("{:x}", Md5::digest(&source)),
+            coverage,
+            branches,
+        });
+    }
+
+    let job = Job {
+        repo_token,
+        service_name: match ci.service {
+            Some("github-actions") => "github",
+            Some("gitlab") => "gitlab-ci",
+            _ => "simple-rust-cov",
+        }
+        .to_string(),
+        service_job_id: ci.build.clone(),
+        service_pull_request: ci.pr.clone(),
+        git: Git {
+            head: Head {
+                id: ci.commit.clone(),
+            },
+            branch: ci.branch.clone(),
+        },
+        source_files,
+    };
+
+    let payload = serde_json::to_vec(&job).expect("unable to serialize Coveralls job");
+    let response = curl(
+        &[
+            "-F",
+            "json_file=@-;filename=coveralls.json;type=application/json",
+            &format!("{}/api/v1/jobs", base_url),
+        ],
+        Some(&payload),
+    )?;
+
+    let url = serde_json::from_str::<serde_json::Value>(&response)
+        .ok()
+        .and_then(|r| r["url"].as_str().map(|u| u.to_string()))
+        .unwrap_or(response);
+    eprintln!("Uploaded coverage to Coveralls: {}", url);
+    Ok(())
+}
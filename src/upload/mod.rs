@@ -0,0 +1,127 @@
+//! Publishing reports to hosted coverage services.
+
+pub mod codecov;
+pub mod coveralls;
+
+use std::{
+    env,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{CovError, Result, git};
+
+/// What we can tell about the CI run this is part of. Falls back to asking git when not running
+/// on a known CI provider.
+#[derive(Debug, Default)]
+pub struct CiInfo {
+    /// Provider name as the coverage services spell it
+    pub service: Option<&'static str>,
+    pub commit: String,
+    pub branch: Option<String>,
+    pub pr: Option<String>,
+    pub build: Option<String>,
+    pub build_url: Option<String>,
+    /// `owner/repo`
+    pub slug: Option<String>,
+}
+
+impl CiInfo {
+    pub fn detect(project_dir: &Path) -> Result<CiInfo> {
+        if env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true") {
+            let server = var("GITHUB_SERVER_URL").unwrap_or("https://github.com".to_string());
+            let slug = var("GITHUB_REPOSITORY");
+            let build = var("GITHUB_RUN_ID");
+            // refs/pull/<number>/merge on pull_request runs
+            let pr = var("GITHUB_REF").and_then(|r| {
+                r.strip_prefix("refs/pull/")
+                    .and_then(|r| r.split('/').next())
+                    .map(|n| n.to_string())
+            });
+            return Ok(CiInfo {
+                service: Some("github-actions"),
+                commit: var("GITHUB_SHA").map_or_else(|| git::head(project_dir), Ok)?,
+                branch: var("GITHUB_HEAD_REF").or_else(|| var("GITHUB_REF_NAME")),
+                pr,
+                build_url: match (&slug, &build) {
+                    (Some(slug), Some(build)) => {
+                        Some(format!("{}/{}/actions/runs/{}", server, slug, build))
+                    }
+                    _ => None,
+                },
+                build,
+                slug,
+            });
+        }
+        if env::var("GITLAB_CI").is_ok() {
+            return Ok(CiInfo {
+                service: Some("gitlab"),
+                commit: var("CI_COMMIT_SHA").map_or_else(|| git::head(project_dir), Ok)?,
+                branch: var("CI_COMMIT_REF_NAME"),
+                pr: var("CI_MERGE_REQUEST_IID"),
+                build: var("CI_PIPELINE_ID"),
+                build_url: var("CI_PIPELINE_URL"),
+                slug: var("CI_PROJECT_PATH"),
+            });
+        }
+        Ok(CiInfo {
+            commit: git::head(project_dir)?,
+            branch: git::branch(project_dir)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Non-empty environment variable
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Runs curl, which is everywhere CI is, and returns the response body. `body` is sent on stdin
+/// for flags like `--data-binary @-`.
+pub fn curl(args: &[&str], body: Option<&[u8]>) -> Result<String> {
+    let mut child = Command::new("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| CovError::Spawn {
+            tool: "curl".to_string(),
+            source,
+        })?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(body) = body {
+        stdin
+            .write_all(body)
+            .map_err(|e| CovError::Upload(format!("unable to send request body: {}", e)))?;
+    }
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| CovError::Upload(format!("curl didn't finish: {}", e)))?;
+    if !output.status.success() {
+        return Err(CovError::tool_failed("curl", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Percent-encodes a query parameter value
+pub fn url_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,86 @@
+//! Waiting for source changes between `--watch` runs
+
+use std::{
+    collections::BTreeSet,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+
+use crate::{CovError, Result, config::CONFIG_FILE, formats};
+
+/// How long things have to stay quiet after a change before the next run, so that saving
+/// several files at once only runs the tests once
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+pub struct Watcher {
+    // Watching stops when this is dropped
+    _watcher: RecommendedWatcher,
+    root: PathBuf,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl Watcher {
+    /// Starts watching everything under `root`, which should be absolute. Changes are queued up
+    /// from here on, so ones made during a run aren't missed.
+    pub fn new(root: &Path) -> Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(CovError::Watch)?;
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(CovError::Watch)?;
+        Ok(Watcher {
+            _watcher: watcher,
+            root: root.to_path_buf(),
+            events,
+        })
+    }
+
+    /// Blocks until a source file, manifest or the config file changes, and then until things
+    /// settle down. Returns what changed, relative to the root.
+    pub fn wait(&self) -> Result<BTreeSet<String>> {
+        let mut changed = BTreeSet::new();
+        loop {
+            let event = if changed.is_empty() {
+                self.events
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                self.events.recv_timeout(DEBOUNCE)
+            };
+            let event = match event {
+                Ok(event) => event.map_err(CovError::Watch)?,
+                Err(RecvTimeoutError::Timeout) => return Ok(changed),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(CovError::Watch(notify::Error::generic(
+                        "the watcher stopped",
+                    )));
+                }
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            changed.extend(event.paths.iter().filter_map(|p| self.relevant(p)));
+        }
+    }
+
+    /// The path relative to the root when a change to it means the coverage could have changed.
+    /// Build output is left out, since the runs themselves write plenty of it, generated source
+    /// included.
+    fn relevant(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let in_ignored_dir = relative.parent()?.components().any(|c| match c {
+            Component::Normal(dir) => dir == "target" || dir.to_string_lossy().starts_with('.'),
+            _ => false,
+        });
+        let name = relative.file_name()?;
+        let watched = path.extension().is_some_and(|e| e == "rs")
+            || name == "Cargo.toml"
+            || name == "Cargo.lock"
+            || name == CONFIG_FILE;
+        (watched && !in_ignored_dir)
+            .then(|| formats::relative_path(&path.to_string_lossy(), &self.root))
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{CovError, Result, decode, exec};
+
+/// A workspace member, as described by `cargo metadata`
+#[derive(Debug, Clone)]
+pub struct Package {
+    pub name: String,
+    /// Directory containing the package's Cargo.toml
+    pub root: PathBuf,
+}
+
+/// The parts of `cargo metadata --no-deps` this tool uses
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<MetadataPackage>,
+    pub workspace_root: PathBuf,
+    pub target_directory: PathBuf,
+    /// `[workspace.metadata]`
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataPackage {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    #[serde(default)]
+    pub dependencies: Vec<MetadataDependency>,
+    /// `[package.metadata]`
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataDependency {
+    pub name: String,
+    /// Set for path dependencies, which is what workspace members depend on each other with
+    pub path: Option<PathBuf>,
+}
+
+pub fn metadata(cargo: &str, project_dir: &Path, manifest_path: Option<&Path>) -> Result<Metadata> {
+    let mut cmd = Command::new(cargo);
+    cmd.arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version=1")
+        .current_dir(project_dir);
+    if let Some(manifest_path) = manifest_path {
+        cmd.arg("--manifest-path").arg(manifest_path);
+    }
+    let output = exec(&mut cmd)?;
+
+    serde_json::from_str(&decode("cargo metadata", &output.stdout))
+        .map_err(|e| CovError::parse("cargo metadata", e))
+}
+
+/// The Cargo.toml cargo's own commands would use in `dir`: the nearest one in it or above it
+pub fn locate_project(cargo: &str, dir: &Path) -> Result<PathBuf> {
+    let mut cmd = Command::new(cargo);
+    cmd.arg("locate-project")
+        .arg("--message-format=plain")
+        .current_dir(dir);
+    let output = exec(&mut cmd)?;
+    Ok(PathBuf::from(
+        decode("cargo locate-project", &output.stdout).trim(),
+    ))
+}
+
+pub fn members(
+    cargo: &str,
+    project_dir: &Path,
+    manifest_path: Option<&Path>,
+) -> Result<Vec<Package>> {
+    Ok(packages(
+        &metadata(cargo, project_dir, manifest_path)?.packages,
+    ))
+}
+
+pub fn packages(metadata: &[MetadataPackage]) -> Vec<Package> {
+    metadata
+        .iter()
+        .map(|p| Package {
+            name: p.name.clone(),
+            root: p
+                .manifest_path
+                .parent()
+                .expect("manifest path has no parent")
+                .to_path_buf(),
+        })
+        .collect()
+}
+
+/// `changed` and every member that depends on one of them, directly or through other members.
+/// Dependencies are matched by path rather than name, so a crates.io dependency that happens to
+/// share a member's name doesn't count, and dev- and build-dependencies count like any other
+/// since the tests are built with them.
+pub fn dependents(metadata: &[MetadataPackage], changed: &BTreeSet<String>) -> BTreeSet<String> {
+    let by_root: BTreeMap<&Path, &str> = metadata
+        .iter()
+        .filter_map(|p| Some((p.manifest_path.parent()?, p.name.as_str())))
+        .collect();
+    let mut reverse: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for package in metadata {
+        for dependency in &package.dependencies {
+            if let Some(member) = dependency
+                .path
+                .as_deref()
+                .and_then(|path| by_root.get(path))
+            {
+                reverse.entry(member).or_default().push(&package.name);
+            }
+        }
+    }
+
+    let mut affected = changed.clone();
+    let mut queue: Vec<&str> = changed.iter().map(|c| c.as_str()).collect();
+    while let Some(name) = queue.pop() {
+        for dependent in reverse.get(name).into_iter().flatten() {
+            if affected.insert(dependent.to_string()) {
+                queue.push(dependent);
+            }
+        }
+    }
+    affected
+}
+
+/// The member a source file belongs to. Members can be nested inside each other (the root
+/// package of a workspace usually is), so the deepest matching root wins.
+pub fn package_for<'a>(packages: &'a [Package], filename: &str) -> Option<&'a Package> {
+    packages
+        .iter()
+        .filter(|p| Path::new(filename).starts_with(&p.root))
+        .max_by_key(|p| p.root.components().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, dependencies: &[(&str, Option<&str>)]) -> MetadataPackage {
+        MetadataPackage {
+            name: name.to_string(),
+            manifest_path: PathBuf::from(format!("/repo/{}/Cargo.toml", name)),
+            dependencies: dependencies
+                .iter()
+                .map(|(name, path)| MetadataDependency {
+                    name: name.to_string(),
+                    path: path.map(PathBuf::from),
+                })
+                .collect(),
+            metadata: None,
+        }
+    }
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn dependents_follow_path_dependencies_transitively() {
+        let metadata = [
+            package("core", &[]),
+            package("app", &[("core", Some("/repo/core"))]),
+            package("cli", &[("app", Some("/repo/app"))]),
+            // From crates.io, so not the member of the same name
+            package("other", &[("core", None)]),
+        ];
+        assert_eq!(
+            dependents(&metadata, &names(&["core"])),
+            names(&["app", "cli", "core"])
+        );
+        assert_eq!(dependents(&metadata, &names(&["cli"])), names(&["cli"]));
+        assert_eq!(dependents(&metadata, &names(&[])), names(&[]));
+    }
+
+    #[test]
+    fn dependents_handle_cycles() {
+        // Dev-dependencies can point back at a package that depends on them
+        let metadata = [
+            package("a", &[("b", Some("/repo/b"))]),
+            package("b", &[("a", Some("/repo/a"))]),
+        ];
+        assert_eq!(dependents(&metadata, &names(&["a"])), names(&["a", "b"]));
+    }
+}